@@ -0,0 +1,563 @@
+use bytes::{Buf, BufMut, Bytes};
+
+use thiserror::Error;
+
+use super::VarInt;
+
+// The maximum length of the reason in a CLOSE_WEBTRANSPORT_SESSION capsule.
+const MAX_REASON_SIZE: usize = 1024;
+
+// Identifies the type of a capsule sent on the CONNECT stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CapsuleType(pub VarInt);
+
+impl CapsuleType {
+    pub fn decode<B: Buf>(buf: &mut B) -> Result<Self, CapsuleError> {
+        let typ = VarInt::decode(buf).map_err(|_| CapsuleError::UnexpectedEnd)?;
+        Ok(CapsuleType(typ))
+    }
+
+    pub fn encode<B: BufMut>(&self, buf: &mut B) {
+        self.0.encode(buf)
+    }
+
+    // Reserved capsule types are of the form 0x29 * N + 0x17.
+    pub fn is_grease(&self) -> bool {
+        let val = self.0.into_inner();
+        if val < 0x17 {
+            return false;
+        }
+
+        (val - 0x17).is_multiple_of(0x29)
+    }
+
+    // The largest payload we'll buffer for this capsule type, or None if the payload should be skipped.
+    // This stops the peer from using a huge capsule to pin memory.
+    pub fn max_size(&self) -> Option<usize> {
+        match *self {
+            Self::CLOSE_WEBTRANSPORT_SESSION => Some(4 + MAX_REASON_SIZE),
+            Self::DRAIN_WEBTRANSPORT_SESSION => Some(0),
+            Self::WT_MAX_DATA
+            | Self::WT_MAX_STREAMS_BIDI
+            | Self::WT_MAX_STREAMS_UNI
+            | Self::WT_DATA_BLOCKED
+            | Self::WT_STREAMS_BLOCKED_BIDI
+            | Self::WT_STREAMS_BLOCKED_UNI => Some(8),
+            Self::WT_MAX_STREAM_DATA | Self::WT_STREAM_DATA_BLOCKED => Some(16),
+            // We don't support datagrams over the CONNECT stream.
+            _ => None,
+        }
+    }
+}
+
+macro_rules! capsules {
+    {$($name:ident = $val:expr,)*} => {
+        impl CapsuleType {
+            $(pub const $name: CapsuleType = CapsuleType(VarInt::from_u32($val));)*
+        }
+    }
+}
+
+capsules! {
+    DATAGRAM = 0x00,
+    CLOSE_WEBTRANSPORT_SESSION = 0x2843,
+    DRAIN_WEBTRANSPORT_SESSION = 0x78ae,
+
+    // Session-level flow control, added in draft 07
+    WT_MAX_DATA = 0x190b4d3d,
+    WT_MAX_STREAM_DATA = 0x190b4d3e,
+    WT_MAX_STREAMS_BIDI = 0x190b4d3f,
+    WT_MAX_STREAMS_UNI = 0x190b4d40,
+    WT_DATA_BLOCKED = 0x190b4d41,
+    WT_STREAM_DATA_BLOCKED = 0x190b4d42,
+    WT_STREAMS_BLOCKED_BIDI = 0x190b4d43,
+    WT_STREAMS_BLOCKED_UNI = 0x190b4d44,
+}
+
+#[derive(Error, Debug, Clone)]
+pub enum CapsuleError {
+    #[error("unexpected end of input")]
+    UnexpectedEnd,
+
+    #[error("invalid size")]
+    InvalidSize,
+
+    #[error("reason is too long: {0} bytes")]
+    ReasonTooLong(usize),
+
+    #[error("reason is not valid UTF-8")]
+    InvalidReason,
+}
+
+// A capsule sent on the CONNECT stream, as defined in RFC 9297.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Capsule {
+    // The session was closed with an application error code and reason.
+    CloseWebTransportSession { code: u32, reason: String },
+
+    // The session should be gracefully closed.
+    DrainWebTransportSession,
+
+    // A datagram sent over the stream instead of as a QUIC datagram.
+    Datagram(Bytes),
+
+    // The total amount of data the peer may send on all streams in the session.
+    MaxData(u64),
+
+    // The amount of data the peer may send on a single stream.
+    MaxStreamData { stream_id: VarInt, max: u64 },
+
+    // The total number of streams the peer may open in the session.
+    MaxStreamsBidi(u64),
+    MaxStreamsUni(u64),
+
+    // Sent when blocked by one of the above limits, including the limit.
+    DataBlocked(u64),
+    StreamDataBlocked { stream_id: VarInt, max: u64 },
+    StreamsBlockedBidi(u64),
+    StreamsBlockedUni(u64),
+
+    // Any other capsule, including GREASE, which should be ignored.
+    Unknown { typ: CapsuleType, payload: Bytes },
+}
+
+impl Capsule {
+    // Decode a single capsule, returning UnexpectedEnd if the buffer doesn't contain the full payload.
+    pub fn decode<B: Buf>(buf: &mut B) -> Result<Self, CapsuleError> {
+        let typ = CapsuleType::decode(buf)?;
+        let size = VarInt::decode(buf).map_err(|_| CapsuleError::UnexpectedEnd)?;
+
+        let size: usize = size
+            .into_inner()
+            .try_into()
+            .map_err(|_| CapsuleError::InvalidSize)?;
+        if buf.remaining() < size {
+            return Err(CapsuleError::UnexpectedEnd);
+        }
+
+        // We no longer return UnexpectedEnd because we know the buffer should be large enough.
+        let mut payload = buf.copy_to_bytes(size);
+
+        match typ {
+            CapsuleType::CLOSE_WEBTRANSPORT_SESSION => {
+                if payload.remaining() < 4 {
+                    return Err(CapsuleError::InvalidSize);
+                }
+
+                let code = payload.get_u32();
+
+                if payload.len() > MAX_REASON_SIZE {
+                    return Err(CapsuleError::ReasonTooLong(payload.len()));
+                }
+
+                let reason =
+                    String::from_utf8(payload.to_vec()).map_err(|_| CapsuleError::InvalidReason)?;
+
+                Ok(Self::CloseWebTransportSession { code, reason })
+            }
+            CapsuleType::DRAIN_WEBTRANSPORT_SESSION => {
+                if !payload.is_empty() {
+                    return Err(CapsuleError::InvalidSize);
+                }
+
+                Ok(Self::DrainWebTransportSession)
+            }
+            CapsuleType::DATAGRAM => Ok(Self::Datagram(payload)),
+            CapsuleType::WT_MAX_DATA => Ok(Self::MaxData(decode_value(payload)?)),
+            CapsuleType::WT_MAX_STREAMS_BIDI => Ok(Self::MaxStreamsBidi(decode_value(payload)?)),
+            CapsuleType::WT_MAX_STREAMS_UNI => Ok(Self::MaxStreamsUni(decode_value(payload)?)),
+            CapsuleType::WT_DATA_BLOCKED => Ok(Self::DataBlocked(decode_value(payload)?)),
+            CapsuleType::WT_STREAMS_BLOCKED_BIDI => {
+                Ok(Self::StreamsBlockedBidi(decode_value(payload)?))
+            }
+            CapsuleType::WT_STREAMS_BLOCKED_UNI => {
+                Ok(Self::StreamsBlockedUni(decode_value(payload)?))
+            }
+            CapsuleType::WT_MAX_STREAM_DATA => {
+                let (stream_id, max) = decode_stream_value(payload)?;
+                Ok(Self::MaxStreamData { stream_id, max })
+            }
+            CapsuleType::WT_STREAM_DATA_BLOCKED => {
+                let (stream_id, max) = decode_stream_value(payload)?;
+                Ok(Self::StreamDataBlocked { stream_id, max })
+            }
+            typ => Ok(Self::Unknown { typ, payload }),
+        }
+    }
+
+    // Returns the capsule type, used to check if it's supported by the negotiated draft.
+    pub fn typ(&self) -> CapsuleType {
+        match self {
+            Self::CloseWebTransportSession { .. } => CapsuleType::CLOSE_WEBTRANSPORT_SESSION,
+            Self::DrainWebTransportSession => CapsuleType::DRAIN_WEBTRANSPORT_SESSION,
+            Self::Datagram(_) => CapsuleType::DATAGRAM,
+            Self::MaxData(_) => CapsuleType::WT_MAX_DATA,
+            Self::MaxStreamData { .. } => CapsuleType::WT_MAX_STREAM_DATA,
+            Self::MaxStreamsBidi(_) => CapsuleType::WT_MAX_STREAMS_BIDI,
+            Self::MaxStreamsUni(_) => CapsuleType::WT_MAX_STREAMS_UNI,
+            Self::DataBlocked(_) => CapsuleType::WT_DATA_BLOCKED,
+            Self::StreamDataBlocked { .. } => CapsuleType::WT_STREAM_DATA_BLOCKED,
+            Self::StreamsBlockedBidi(_) => CapsuleType::WT_STREAMS_BLOCKED_BIDI,
+            Self::StreamsBlockedUni(_) => CapsuleType::WT_STREAMS_BLOCKED_UNI,
+            Self::Unknown { typ, .. } => *typ,
+        }
+    }
+
+    pub fn encode<B: BufMut>(&self, buf: &mut B) {
+        match self {
+            Self::CloseWebTransportSession { code, reason } => {
+                // The reason is truncated on a character boundary if it's too long.
+                let mut end = reason.len().min(MAX_REASON_SIZE);
+                while !reason.is_char_boundary(end) {
+                    end -= 1;
+                }
+                let reason = &reason[..end];
+
+                CapsuleType::CLOSE_WEBTRANSPORT_SESSION.encode(buf);
+                VarInt::try_from(4 + reason.len()).unwrap().encode(buf);
+                buf.put_u32(*code);
+                buf.put_slice(reason.as_bytes());
+            }
+            Self::DrainWebTransportSession => {
+                CapsuleType::DRAIN_WEBTRANSPORT_SESSION.encode(buf);
+                VarInt::from_u32(0).encode(buf);
+            }
+            Self::Datagram(payload) => {
+                CapsuleType::DATAGRAM.encode(buf);
+                VarInt::try_from(payload.len()).unwrap().encode(buf);
+                buf.put_slice(payload);
+            }
+            Self::MaxData(value)
+            | Self::MaxStreamsBidi(value)
+            | Self::MaxStreamsUni(value)
+            | Self::DataBlocked(value)
+            | Self::StreamsBlockedBidi(value)
+            | Self::StreamsBlockedUni(value) => {
+                let value = VarInt::try_from(*value).unwrap();
+
+                self.typ().encode(buf);
+                VarInt::try_from(value.size()).unwrap().encode(buf);
+                value.encode(buf);
+            }
+            Self::MaxStreamData { stream_id, max } | Self::StreamDataBlocked { stream_id, max } => {
+                let max = VarInt::try_from(*max).unwrap();
+
+                self.typ().encode(buf);
+                VarInt::try_from(stream_id.size() + max.size())
+                    .unwrap()
+                    .encode(buf);
+                stream_id.encode(buf);
+                max.encode(buf);
+            }
+            Self::Unknown { typ, payload } => {
+                typ.encode(buf);
+                VarInt::try_from(payload.len()).unwrap().encode(buf);
+                buf.put_slice(payload);
+            }
+        }
+    }
+}
+
+// Decode a payload containing a single VarInt, such as a limit.
+fn decode_value(mut payload: Bytes) -> Result<u64, CapsuleError> {
+    let value = VarInt::decode(&mut payload).map_err(|_| CapsuleError::InvalidSize)?;
+    if payload.has_remaining() {
+        return Err(CapsuleError::InvalidSize);
+    }
+
+    Ok(value.into_inner())
+}
+
+// Decode a payload containing a stream ID followed by a limit.
+fn decode_stream_value(mut payload: Bytes) -> Result<(VarInt, u64), CapsuleError> {
+    let stream_id = VarInt::decode(&mut payload).map_err(|_| CapsuleError::InvalidSize)?;
+    let value = decode_value(payload)?;
+
+    Ok((stream_id, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(capsule: &Capsule) -> Vec<u8> {
+        let mut buf = Vec::new();
+        capsule.encode(&mut buf);
+        buf
+    }
+
+    fn round_trip(capsule: Capsule) {
+        let encoded = encode(&capsule);
+
+        let mut buf = encoded.as_slice();
+        assert_eq!(Capsule::decode(&mut buf).unwrap(), capsule);
+        assert!(buf.is_empty(), "trailing bytes for {:?}", capsule);
+    }
+
+    #[test]
+    fn close_round_trip() {
+        round_trip(Capsule::CloseWebTransportSession {
+            code: 42,
+            reason: "bye".to_string(),
+        });
+
+        round_trip(Capsule::CloseWebTransportSession {
+            code: u32::MAX,
+            reason: String::new(),
+        });
+    }
+
+    #[test]
+    fn drain_round_trip() {
+        round_trip(Capsule::DrainWebTransportSession);
+        assert_eq!(
+            encode(&Capsule::DrainWebTransportSession),
+            [0x80, 0x00, 0x78, 0xae, 0x00]
+        );
+    }
+
+    #[test]
+    fn flow_control_round_trip() {
+        for value in [
+            0,
+            63,
+            64,
+            16383,
+            16384,
+            (1 << 30) - 1,
+            1 << 30,
+            (1 << 62) - 1,
+        ] {
+            round_trip(Capsule::MaxData(value));
+            round_trip(Capsule::MaxStreamsBidi(value));
+            round_trip(Capsule::MaxStreamsUni(value));
+            round_trip(Capsule::DataBlocked(value));
+            round_trip(Capsule::StreamsBlockedBidi(value));
+            round_trip(Capsule::StreamsBlockedUni(value));
+
+            let stream_id = VarInt::from_u32(4);
+            round_trip(Capsule::MaxStreamData {
+                stream_id,
+                max: value,
+            });
+            round_trip(Capsule::StreamDataBlocked {
+                stream_id,
+                max: value,
+            });
+        }
+    }
+
+    #[test]
+    fn flow_control_invalid_size() {
+        // A limit with a trailing byte.
+        let mut buf = Vec::new();
+        CapsuleType::WT_MAX_DATA.encode(&mut buf);
+        VarInt::from_u32(2).encode(&mut buf);
+        buf.extend_from_slice(&[0x01, 0x02]);
+        assert!(matches!(
+            Capsule::decode(&mut buf.as_slice()),
+            Err(CapsuleError::InvalidSize)
+        ));
+
+        // A stream ID without a limit.
+        let mut buf = Vec::new();
+        CapsuleType::WT_MAX_STREAM_DATA.encode(&mut buf);
+        VarInt::from_u32(1).encode(&mut buf);
+        buf.push(0x04);
+        assert!(matches!(
+            Capsule::decode(&mut buf.as_slice()),
+            Err(CapsuleError::InvalidSize)
+        ));
+
+        // An empty payload.
+        let mut buf = Vec::new();
+        CapsuleType::WT_DATA_BLOCKED.encode(&mut buf);
+        VarInt::from_u32(0).encode(&mut buf);
+        assert!(matches!(
+            Capsule::decode(&mut buf.as_slice()),
+            Err(CapsuleError::InvalidSize)
+        ));
+    }
+
+    #[test]
+    fn truncated() {
+        let capsules = [
+            Capsule::CloseWebTransportSession {
+                code: 7,
+                reason: "going away".to_string(),
+            },
+            Capsule::DrainWebTransportSession,
+            Capsule::MaxData(1 << 20),
+            Capsule::MaxStreamData {
+                stream_id: VarInt::from_u32(8),
+                max: 1 << 40,
+            },
+        ];
+
+        for capsule in capsules {
+            let encoded = encode(&capsule);
+
+            // Every partial buffer asks for more data, so the caller can retry once it has the rest.
+            for end in 0..encoded.len() {
+                assert!(
+                    matches!(
+                        Capsule::decode(&mut &encoded[..end]),
+                        Err(CapsuleError::UnexpectedEnd)
+                    ),
+                    "{:?} truncated to {} bytes",
+                    capsule,
+                    end
+                );
+            }
+
+            assert_eq!(Capsule::decode(&mut encoded.as_slice()).unwrap(), capsule);
+        }
+    }
+
+    #[test]
+    fn multiple() {
+        let first = Capsule::MaxData(1024);
+        let second = Capsule::CloseWebTransportSession {
+            code: 1,
+            reason: "done".to_string(),
+        };
+
+        let mut encoded = encode(&first);
+        encoded.extend(encode(&second));
+
+        let mut buf = encoded.as_slice();
+        assert_eq!(Capsule::decode(&mut buf).unwrap(), first);
+        assert_eq!(Capsule::decode(&mut buf).unwrap(), second);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn close_reason_limit() {
+        // The longest reason is allowed.
+        round_trip(Capsule::CloseWebTransportSession {
+            code: 0,
+            reason: "a".repeat(MAX_REASON_SIZE),
+        });
+
+        // Anything longer is truncated when encoding, without splitting a character.
+        let reason = "é".repeat(MAX_REASON_SIZE);
+        let encoded = encode(&Capsule::CloseWebTransportSession { code: 0, reason });
+        match Capsule::decode(&mut encoded.as_slice()).unwrap() {
+            Capsule::CloseWebTransportSession { reason, .. } => {
+                assert_eq!(reason, "é".repeat(MAX_REASON_SIZE / 2));
+            }
+            capsule => panic!("unexpected capsule: {:?}", capsule),
+        }
+
+        // And rejected when decoding.
+        let mut buf = Vec::new();
+        CapsuleType::CLOSE_WEBTRANSPORT_SESSION.encode(&mut buf);
+        VarInt::try_from(4 + MAX_REASON_SIZE + 1)
+            .unwrap()
+            .encode(&mut buf);
+        buf.put_u32(0);
+        buf.extend_from_slice(&[b'a'; MAX_REASON_SIZE + 1]);
+        assert!(matches!(
+            Capsule::decode(&mut buf.as_slice()),
+            Err(CapsuleError::ReasonTooLong(size)) if size == MAX_REASON_SIZE + 1
+        ));
+    }
+
+    #[test]
+    fn close_invalid() {
+        // Too short for the error code.
+        let mut buf = Vec::new();
+        CapsuleType::CLOSE_WEBTRANSPORT_SESSION.encode(&mut buf);
+        VarInt::from_u32(3).encode(&mut buf);
+        buf.extend_from_slice(&[0, 0, 0]);
+        assert!(matches!(
+            Capsule::decode(&mut buf.as_slice()),
+            Err(CapsuleError::InvalidSize)
+        ));
+
+        // The reason isn't UTF-8.
+        let mut buf = Vec::new();
+        CapsuleType::CLOSE_WEBTRANSPORT_SESSION.encode(&mut buf);
+        VarInt::from_u32(5).encode(&mut buf);
+        buf.extend_from_slice(&[0, 0, 0, 0, 0xff]);
+        assert!(matches!(
+            Capsule::decode(&mut buf.as_slice()),
+            Err(CapsuleError::InvalidReason)
+        ));
+
+        // DRAIN doesn't have a payload.
+        let mut buf = Vec::new();
+        CapsuleType::DRAIN_WEBTRANSPORT_SESSION.encode(&mut buf);
+        VarInt::from_u32(1).encode(&mut buf);
+        buf.push(0);
+        assert!(matches!(
+            Capsule::decode(&mut buf.as_slice()),
+            Err(CapsuleError::InvalidSize)
+        ));
+    }
+
+    #[test]
+    fn max_size() {
+        for capsule in [
+            Capsule::CloseWebTransportSession {
+                code: u32::MAX,
+                reason: "a".repeat(MAX_REASON_SIZE),
+            },
+            Capsule::DrainWebTransportSession,
+            Capsule::MaxData((1 << 62) - 1),
+            Capsule::StreamsBlockedUni((1 << 62) - 1),
+            Capsule::MaxStreamData {
+                stream_id: VarInt::MAX,
+                max: (1 << 62) - 1,
+            },
+        ] {
+            // The largest valid payload must fit within the limit.
+            let encoded = encode(&capsule);
+            let mut buf = encoded.as_slice();
+            CapsuleType::decode(&mut buf).unwrap();
+            let size = VarInt::decode(&mut buf).unwrap().into_inner() as usize;
+            assert_eq!(Some(size), capsule.typ().max_size(), "{:?}", capsule);
+        }
+
+        // Anything we don't buffer is skipped instead.
+        assert_eq!(CapsuleType::DATAGRAM.max_size(), None);
+        assert_eq!(CapsuleType(VarInt::from_u32(0x17)).max_size(), None);
+        assert_eq!(CapsuleType(VarInt::from_u32(0x1234)).max_size(), None);
+    }
+
+    #[test]
+    fn grease() {
+        for n in [0, 1, 1000] {
+            let typ = CapsuleType(VarInt::from_u32(0x29 * n + 0x17));
+            assert!(typ.is_grease());
+        }
+
+        for val in [0x00, 0x16, 0x18, 0x29 + 0x16, 0x2843] {
+            assert!(!CapsuleType(VarInt::from_u32(val)).is_grease());
+        }
+    }
+
+    #[test]
+    fn unknown_skipped() {
+        let grease = Capsule::Unknown {
+            typ: CapsuleType(VarInt::from_u32(0x29 * 3 + 0x17)),
+            payload: Bytes::from_static(b"ignore me"),
+        };
+        let unknown = Capsule::Unknown {
+            typ: CapsuleType(VarInt::from_u32(0x1234)),
+            payload: Bytes::new(),
+        };
+        let drain = Capsule::DrainWebTransportSession;
+
+        let mut encoded = encode(&grease);
+        encoded.extend(encode(&unknown));
+        encoded.extend(encode(&drain));
+
+        // Unknown payloads are consumed, so the next capsule can be decoded.
+        let mut buf = encoded.as_slice();
+        assert_eq!(Capsule::decode(&mut buf).unwrap(), grease);
+        assert_eq!(Capsule::decode(&mut buf).unwrap(), unknown);
+        assert_eq!(Capsule::decode(&mut buf).unwrap(), drain);
+        assert!(buf.is_empty());
+    }
+}
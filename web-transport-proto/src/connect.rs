@@ -1,9 +1,9 @@
 use std::str::FromStr;
 
 use bytes::{Buf, BufMut};
-use url::Url;
+use url::{Position, Url};
 
-use super::{qpack, Frame, VarInt};
+use super::{qpack, DraftVersion, Frame, VarInt, DRAFT02_REQUEST_HEADER, DRAFT02_RESPONSE_HEADER};
 
 use thiserror::Error;
 
@@ -48,15 +48,51 @@ pub enum ConnectError {
 
     #[error("non-200 status: {0:?}")]
     ErrorStatus(http::StatusCode),
+
+    #[error("invalid header: {0}")]
+    InvalidHeader(String),
 }
 
+// The subprotocols offered by the client, in order of preference.
+const PROTOCOLS_HEADER: &str = "wt-available-protocols";
+
+// The subprotocol selected by the server.
+const PROTOCOL_HEADER: &str = "wt-protocol";
+
 #[derive(Debug)]
 pub struct ConnectRequest {
     pub url: Url,
+
+    // The application protocols offered via wt-available-protocols, in order of preference.
+    pub protocols: Vec<String>,
+
+    // The draft used by the session, which determines the extra headers sent.
+    pub draft: DraftVersion,
+
+    // Any additional headers, excluding pseudo-headers.
+    pub headers: http::HeaderMap,
 }
 
 impl ConnectRequest {
+    pub fn new(url: Url) -> Self {
+        Self {
+            url,
+            protocols: Vec::new(),
+            draft: DraftVersion::default(),
+            headers: Default::default(),
+        }
+    }
+
     pub fn decode<B: Buf>(buf: &mut B) -> Result<Self, ConnectError> {
+        Self::decode_with(buf, &mut qpack::Decoder::default(), 0)
+    }
+
+    // Decode the request using the QPACK dynamic table, which may return a Blocked error.
+    pub fn decode_with<B: Buf>(
+        buf: &mut B,
+        decoder: &mut qpack::Decoder,
+        stream_id: u64,
+    ) -> Result<Self, ConnectError> {
         let (typ, mut data) = Frame::read(buf).map_err(|_| ConnectError::UnexpectedEnd)?;
         if typ != Frame::HEADERS {
             return Err(ConnectError::UnexpectedFrame(typ));
@@ -64,8 +100,12 @@ impl ConnectRequest {
 
         // We no longer return UnexpectedEnd because we know the buffer should be large enough.
 
-        let headers = qpack::Headers::decode(&mut data)?;
+        let headers = decoder.decode(stream_id, &mut data)?;
+        Self::from_headers(&headers)
+    }
 
+    // Validate the decoded headers, which must be an extended CONNECT for WebTransport.
+    pub(crate) fn from_headers(headers: &qpack::Headers) -> Result<Self, ConnectError> {
         let scheme = match headers.get(":scheme") {
             Some("https") => "https",
             Some(scheme) => Err(ConnectError::WrongScheme(Some(scheme.to_string())))?,
@@ -92,9 +132,36 @@ impl ConnectRequest {
             return Err(ConnectError::WrongProtocol(protocol.map(|s| s.to_string())));
         }
 
+        // The path includes the query string, if any, which is parsed into the URL.
         let url = Url::parse(&format!("{}://{}{}", scheme, authority, path))?;
 
-        Ok(Self { url })
+        // Fragments are never sent over the wire.
+        if url.fragment().is_some() {
+            return Err(ConnectError::WrongPath);
+        }
+
+        // An invalid structured field is ignored, as if no protocols were offered.
+        let protocols = headers
+            .get(PROTOCOLS_HEADER)
+            .and_then(decode_sf_string_list)
+            .unwrap_or_default();
+
+        // Draft 02 clients identify themselves with an extra header.
+        let draft = match headers.get(DRAFT02_REQUEST_HEADER) {
+            Some(_) => DraftVersion::Draft02,
+            None => DraftVersion::Draft07,
+        };
+
+        let mut headers = decode_header_map(headers)?;
+        headers.remove(PROTOCOLS_HEADER);
+        headers.remove(DRAFT02_REQUEST_HEADER);
+
+        Ok(Self {
+            url,
+            protocols,
+            draft,
+            headers,
+        })
     }
 
     pub fn encode<B: BufMut>(&self, buf: &mut B) {
@@ -102,9 +169,24 @@ impl ConnectRequest {
         headers.set(":method", "CONNECT");
         headers.set(":scheme", self.url.scheme());
         headers.set(":authority", self.url.authority());
-        headers.set(":path", self.url.path());
+        // The request target is the path and query, without the fragment.
+        headers.set(
+            ":path",
+            &self.url[Position::BeforePath..Position::AfterQuery],
+        );
         headers.set(":protocol", "webtransport");
 
+        if self.draft == DraftVersion::Draft02 {
+            headers.set(DRAFT02_REQUEST_HEADER, "1");
+        }
+
+        if !self.protocols.is_empty() {
+            headers.set(PROTOCOLS_HEADER, &encode_sf_string_list(&self.protocols));
+        }
+
+        // Pseudo-headers must come first.
+        encode_header_map(&mut headers, &self.headers);
+
         // Use a temporary buffer so we can compute the size.
         let mut tmp = Vec::new();
         headers.encode(&mut tmp);
@@ -119,17 +201,45 @@ impl ConnectRequest {
 #[derive(Debug)]
 pub struct ConnectResponse {
     pub status: http::status::StatusCode,
+
+    // The application protocol selected via wt-protocol, if any.
+    pub protocol: Option<String>,
+
+    // The draft used by the session, which determines the extra headers sent.
+    pub draft: DraftVersion,
+
+    // Any additional headers, excluding pseudo-headers.
+    pub headers: http::HeaderMap,
 }
 
 impl ConnectResponse {
+    pub fn new(status: http::StatusCode) -> Self {
+        Self {
+            status,
+            protocol: None,
+            draft: DraftVersion::default(),
+            headers: Default::default(),
+        }
+    }
+
     pub fn decode<B: Buf>(buf: &mut B) -> Result<Self, ConnectError> {
+        Self::decode_with(buf, &mut qpack::Decoder::default(), 0)
+    }
+
+    // Decode the response using the QPACK dynamic table, which may return a Blocked error.
+    pub fn decode_with<B: Buf>(
+        buf: &mut B,
+        decoder: &mut qpack::Decoder,
+        stream_id: u64,
+    ) -> Result<Self, ConnectError> {
         let (typ, mut data) = Frame::read(buf).map_err(|_| ConnectError::UnexpectedEnd)?;
         if typ != Frame::HEADERS {
             return Err(ConnectError::UnexpectedFrame(typ));
         }
 
-        let headers = qpack::Headers::decode(&mut data)?;
+        let headers = decoder.decode(stream_id, &mut data)?;
 
+        // NOTE: Error statuses are returned too, so the caller can read the headers and body.
         let status = match headers
             .get(":status")
             .map(|status| {
@@ -137,17 +247,44 @@ impl ConnectResponse {
             })
             .transpose()?
         {
-            Some(status) if status.is_success() => status,
-            o => return Err(ConnectError::WrongStatus(o)),
+            Some(status) => status,
+            None => return Err(ConnectError::WrongStatus(None)),
         };
 
-        Ok(Self { status })
+        // An invalid structured field is ignored, as if no protocol was selected.
+        let protocol = headers.get(PROTOCOL_HEADER).and_then(decode_sf_string);
+
+        // Draft 02 servers identify themselves with an extra header.
+        let draft = match headers.get(DRAFT02_RESPONSE_HEADER) {
+            Some("draft02") => DraftVersion::Draft02,
+            _ => DraftVersion::Draft07,
+        };
+
+        let mut headers = decode_header_map(&headers)?;
+        headers.remove(PROTOCOL_HEADER);
+        headers.remove(DRAFT02_RESPONSE_HEADER);
+
+        Ok(Self {
+            status,
+            protocol,
+            draft,
+            headers,
+        })
     }
 
     pub fn encode<B: BufMut>(&self, buf: &mut B) {
         let mut headers = qpack::Headers::default();
         headers.set(":status", self.status.as_str());
-        headers.set("sec-webtransport-http3-draft", "draft02");
+
+        if self.draft == DraftVersion::Draft02 {
+            headers.set(DRAFT02_RESPONSE_HEADER, "draft02");
+        }
+
+        if let Some(protocol) = &self.protocol {
+            headers.set(PROTOCOL_HEADER, &encode_sf_string(protocol));
+        }
+
+        encode_header_map(&mut headers, &self.headers);
 
         // Use a temporary buffer so we can compute the size.
         let mut tmp = Vec::new();
@@ -159,3 +296,326 @@ impl ConnectResponse {
         buf.put_slice(&tmp);
     }
 }
+
+// Collect every header that isn't a pseudo-header.
+pub(crate) fn decode_header_map(headers: &qpack::Headers) -> Result<http::HeaderMap, ConnectError> {
+    let mut map = http::HeaderMap::new();
+
+    for (name, value) in headers.iter() {
+        if name.starts_with(':') {
+            continue;
+        }
+
+        let name = http::HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| ConnectError::InvalidHeader(name.to_string()))?;
+        let value = http::HeaderValue::from_str(value)
+            .map_err(|_| ConnectError::InvalidHeader(name.to_string()))?;
+
+        map.append(name, value);
+    }
+
+    Ok(map)
+}
+
+pub(crate) fn encode_header_map(headers: &mut qpack::Headers, map: &http::HeaderMap) {
+    for (name, value) in map {
+        // QPACK headers are strings, so replace any opaque bytes.
+        let value = String::from_utf8_lossy(value.as_bytes());
+        headers.append(name.as_str(), &value);
+    }
+}
+
+// Decode a structured field list of strings (RFC 8941), ignoring any parameters.
+fn decode_sf_string_list(value: &str) -> Option<Vec<String>> {
+    let mut list = Vec::new();
+    let mut rest = value.trim_matches(' ');
+
+    while !rest.is_empty() {
+        let (item, remain) = decode_sf_item(rest)?;
+        list.push(item);
+
+        // Each member is separated by a comma and optional whitespace.
+        rest = remain.trim_start_matches([' ', '\t']);
+        if rest.is_empty() {
+            break;
+        }
+
+        rest = rest.strip_prefix(',')?.trim_start_matches([' ', '\t']);
+        if rest.is_empty() {
+            // A trailing comma is invalid.
+            return None;
+        }
+    }
+
+    Some(list)
+}
+
+// Decode a structured field string item (RFC 8941), ignoring any parameters.
+fn decode_sf_string(value: &str) -> Option<String> {
+    let (item, rest) = decode_sf_item(value.trim_matches(' '))?;
+    match rest.is_empty() {
+        true => Some(item),
+        false => None,
+    }
+}
+
+// Decode a string followed by any parameters, returning the remaining input.
+fn decode_sf_item(value: &str) -> Option<(String, &str)> {
+    let mut chars = value.strip_prefix('"')?.char_indices();
+    let mut item = String::new();
+
+    let rest = loop {
+        match chars.next()? {
+            (_, '\\') => match chars.next()? {
+                (_, c @ ('"' | '\\')) => item.push(c),
+                _ => return None,
+            },
+            (i, '"') => break &value[i + 2..],
+            (_, c @ ' '..='~') => item.push(c),
+            _ => return None,
+        }
+    };
+
+    Some((item, skip_sf_parameters(rest)?))
+}
+
+// Skip over any parameters, such as ;key=value, since we don't use them.
+fn skip_sf_parameters(mut value: &str) -> Option<&str> {
+    while let Some(param) = value.strip_prefix(';') {
+        let param = param.trim_start_matches(' ');
+        let end = param
+            .find(|c: char| !(c.is_ascii_alphanumeric() || "_-.*".contains(c)))
+            .unwrap_or(param.len());
+        if end == 0 {
+            return None;
+        }
+
+        value = &param[end..];
+
+        if let Some(param) = value.strip_prefix('=') {
+            value = match param.strip_prefix('"') {
+                Some(_) => decode_sf_item(param)?.1,
+                None => {
+                    let end = param.find([';', ',', ' ']).unwrap_or(param.len());
+                    &param[end..]
+                }
+            };
+        }
+    }
+
+    Some(value)
+}
+
+fn encode_sf_string_list(list: &[String]) -> String {
+    list.iter()
+        .map(|item| encode_sf_string(item))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Encode a structured field string, escaping quotes and backslashes.
+// Any characters that can't be represented are dropped.
+fn encode_sf_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                out.push('\\');
+                out.push(c);
+            }
+            ' '..='~' => out.push(c),
+            _ => {}
+        }
+    }
+
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The pseudo-headers for a valid WebTransport request.
+    fn request(path: &str) -> qpack::Headers {
+        let mut headers = qpack::Headers::default();
+        headers.set(":method", "CONNECT");
+        headers.set(":scheme", "https");
+        headers.set(":authority", "localhost:4443");
+        headers.set(":path", path);
+        headers.set(":protocol", "webtransport");
+        headers
+    }
+
+    fn encode_request(request: &ConnectRequest) -> Vec<u8> {
+        let mut buf = Vec::new();
+        request.encode(&mut buf);
+        buf
+    }
+
+    #[test]
+    fn request_round_trip() {
+        let mut request =
+            ConnectRequest::new(Url::parse("https://localhost:4443/chat?room=1#ignored").unwrap());
+        request.protocols = vec!["chat".to_string(), "say \"hi\\\"".to_string()];
+        request
+            .headers
+            .append("x-custom", http::HeaderValue::from_static("a"));
+        request
+            .headers
+            .append("x-custom", http::HeaderValue::from_static("b"));
+
+        let buf = encode_request(&request);
+        let decoded = ConnectRequest::decode(&mut buf.as_slice()).unwrap();
+
+        // The fragment is stripped but the query is kept.
+        assert_eq!(decoded.url.as_str(), "https://localhost:4443/chat?room=1");
+        assert_eq!(decoded.url.query(), Some("room=1"));
+        assert_eq!(decoded.protocols, request.protocols);
+        assert_eq!(decoded.draft, DraftVersion::Draft07);
+
+        // Multiple values are preserved, and our own headers are removed.
+        let values: Vec<_> = decoded.headers.get_all("x-custom").iter().collect();
+        assert_eq!(values, ["a", "b"]);
+        assert_eq!(decoded.headers.len(), 2);
+    }
+
+    #[test]
+    fn request_path() {
+        let mut headers = request("/path?a=1&b=%20");
+        headers.set("x-custom", "value");
+
+        let decoded = ConnectRequest::from_headers(&headers).unwrap();
+        assert_eq!(decoded.url.path(), "/path");
+        assert_eq!(decoded.url.query(), Some("a=1&b=%20"));
+        assert_eq!(decoded.headers.get("x-custom").unwrap(), "value");
+
+        // Pseudo-headers never show up in the map.
+        assert!(decoded.headers.keys().all(|k| !k.as_str().starts_with(':')));
+
+        // A fragment can't be sent over the wire.
+        let headers = request("/path#fragment");
+        assert!(matches!(
+            ConnectRequest::from_headers(&headers),
+            Err(ConnectError::WrongPath)
+        ));
+    }
+
+    #[test]
+    fn request_invalid_header() {
+        // A single invalid header fails the entire request.
+        let mut headers = request("/");
+        headers.set("x-valid", "value");
+        headers.set("x-invalid", "line\nbreak");
+        assert!(matches!(
+            ConnectRequest::from_headers(&headers),
+            Err(ConnectError::InvalidHeader(name)) if name == "x-invalid"
+        ));
+
+        let mut headers = request("/");
+        headers.set("bad name", "value");
+        assert!(matches!(
+            ConnectRequest::from_headers(&headers),
+            Err(ConnectError::InvalidHeader(name)) if name == "bad name"
+        ));
+    }
+
+    #[test]
+    fn request_invalid_protocols() {
+        // An invalid list is ignored rather than failing the request.
+        let mut headers = request("/");
+        headers.set(PROTOCOLS_HEADER, "chat");
+
+        let decoded = ConnectRequest::from_headers(&headers).unwrap();
+        assert!(decoded.protocols.is_empty());
+        assert!(decoded.headers.get(PROTOCOLS_HEADER).is_none());
+    }
+
+    #[test]
+    fn response_round_trip() {
+        let mut response = ConnectResponse::new(http::StatusCode::OK);
+        response.protocol = Some("say \"hi\\\"".to_string());
+        response
+            .headers
+            .insert("x-custom", http::HeaderValue::from_static("value"));
+
+        let mut buf = Vec::new();
+        response.encode(&mut buf);
+
+        let decoded = ConnectResponse::decode(&mut buf.as_slice()).unwrap();
+        assert_eq!(decoded.status, http::StatusCode::OK);
+        assert_eq!(decoded.protocol, response.protocol);
+        assert_eq!(decoded.headers, response.headers);
+    }
+
+    #[test]
+    fn sf_string_list() {
+        let list = |v: &[&str]| Some(v.iter().map(|s| s.to_string()).collect::<Vec<_>>());
+
+        assert_eq!(decode_sf_string_list(""), list(&[]));
+        assert_eq!(decode_sf_string_list("  "), list(&[]));
+        assert_eq!(decode_sf_string_list(r#""a""#), list(&["a"]));
+        assert_eq!(
+            decode_sf_string_list(r#" "a" ,	"b","c" "#),
+            list(&["a", "b", "c"])
+        );
+        assert_eq!(decode_sf_string_list(r#""""#), list(&[""]));
+
+        // Parameters are ignored.
+        assert_eq!(
+            decode_sf_string_list(r#""a";q=1;v="x,y", "b";flag"#),
+            list(&["a", "b"])
+        );
+
+        // Trailing or missing commas.
+        assert_eq!(decode_sf_string_list(r#""a","#), None);
+        assert_eq!(decode_sf_string_list(r#""a" "b""#), None);
+        assert_eq!(decode_sf_string_list(r#","a""#), None);
+
+        // Tokens and other bare items aren't strings.
+        assert_eq!(decode_sf_string_list("a, b"), None);
+        assert_eq!(decode_sf_string_list(r#""a", 1"#), None);
+
+        // Invalid parameter keys.
+        assert_eq!(decode_sf_string_list(r#""a";=1"#), None);
+    }
+
+    #[test]
+    fn sf_string_escapes() {
+        assert_eq!(decode_sf_string(r#""a\"b""#), Some("a\"b".to_string()));
+        assert_eq!(decode_sf_string(r#""a\\b""#), Some("a\\b".to_string()));
+
+        // Only quotes and backslashes can be escaped.
+        assert_eq!(decode_sf_string(r#""a\nb""#), None);
+        assert_eq!(decode_sf_string(r#""a\"#), None);
+
+        // Unterminated strings.
+        assert_eq!(decode_sf_string(r#""abc"#), None);
+        assert_eq!(decode_sf_string(r#"""#), None);
+
+        // Trailing input after the item.
+        assert_eq!(decode_sf_string(r#""a" x"#), None);
+        assert_eq!(decode_sf_string(r#""a", "b""#), None);
+
+        // Encoding escapes the same characters.
+        let value = "q\"b\\";
+        assert_eq!(encode_sf_string(value), r#""q\"b\\""#);
+        assert_eq!(
+            decode_sf_string(&encode_sf_string(value)),
+            Some(value.to_string())
+        );
+    }
+
+    #[test]
+    fn sf_string_invalid_chars() {
+        // Only printable ASCII is allowed.
+        assert_eq!(decode_sf_string("\"a\tb\""), None);
+        assert_eq!(decode_sf_string("\"a\u{7f}b\""), None);
+        assert_eq!(decode_sf_string("\"caf\u{e9}\""), None);
+
+        // Encoding drops anything that can't be represented.
+        assert_eq!(encode_sf_string("caf\u{e9}\n"), r#""caf""#);
+    }
+}
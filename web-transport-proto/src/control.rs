@@ -0,0 +1,148 @@
+use bytes::{Buf, BufMut};
+
+use thiserror::Error;
+
+use super::{Frame, VarInt};
+
+// The largest control frame we'll buffer, since every frame we decode contains a single VarInt.
+const MAX_FRAME_SIZE: u64 = 8;
+
+#[derive(Error, Debug, Clone)]
+pub enum ControlError {
+    #[error("unexpected end of input")]
+    UnexpectedEnd,
+
+    #[error("unexpected frame {0:?}")]
+    UnexpectedFrame(Frame),
+
+    #[error("invalid size")]
+    InvalidSize,
+}
+
+// A frame sent on the control stream, after the initial SETTINGS frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlFrame {
+    // Only one SETTINGS frame is allowed, so the contents are ignored.
+    Settings,
+
+    // The peer is shutting down; a stream ID from the server or a push ID from the client.
+    GoAway(VarInt),
+
+    // The client allows the server to push up to this ID.
+    MaxPushId(VarInt),
+
+    // The push with this ID was cancelled.
+    CancelPush(VarInt),
+
+    // Any other frame, which should be ignored.
+    Unknown(Frame),
+}
+
+impl ControlFrame {
+    pub fn decode<B: Buf>(buf: &mut B) -> Result<Self, ControlError> {
+        let (typ, mut data) = Frame::read(buf).map_err(|_| ControlError::UnexpectedEnd)?;
+
+        // We no longer return UnexpectedEnd because we know the buffer should be large enough.
+        let frame = match typ {
+            Frame::SETTINGS => Self::Settings,
+            Frame::GOAWAY => Self::GoAway(Self::decode_id(&mut data)?),
+            Frame::MAX_PUSH_ID => Self::MaxPushId(Self::decode_id(&mut data)?),
+            Frame::CANCEL_PUSH => Self::CancelPush(Self::decode_id(&mut data)?),
+            Frame::DATA | Frame::HEADERS | Frame::PUSH_PROMISE => {
+                return Err(ControlError::UnexpectedFrame(typ))
+            }
+            // These are reserved because they were used in HTTP/2.
+            typ if [0x02, 0x06, 0x08, 0x09].contains(&typ.0.into_inner()) => {
+                return Err(ControlError::UnexpectedFrame(typ))
+            }
+            typ => Self::Unknown(typ),
+        };
+
+        // Skip over any payload we ignored.
+        data.advance(data.remaining());
+
+        Ok(frame)
+    }
+
+    pub fn encode<B: BufMut>(&self, buf: &mut B) {
+        match self {
+            Self::Settings => Self::encode_empty(Frame::SETTINGS, buf),
+            Self::GoAway(id) => Self::encode_id(Frame::GOAWAY, *id, buf),
+            Self::MaxPushId(id) => Self::encode_id(Frame::MAX_PUSH_ID, *id, buf),
+            Self::CancelPush(id) => Self::encode_id(Frame::CANCEL_PUSH, *id, buf),
+            Self::Unknown(typ) => Self::encode_empty(*typ, buf),
+        }
+    }
+
+    // Returns the largest payload we'll buffer for the frame type, or None if the payload should be skipped.
+    // This lets the caller discard unknown frames as they arrive, so the peer can't use them to pin memory.
+    pub fn max_size(typ: Frame) -> Result<Option<u64>, ControlError> {
+        match typ {
+            Frame::GOAWAY | Frame::MAX_PUSH_ID | Frame::CANCEL_PUSH => Ok(Some(MAX_FRAME_SIZE)),
+            // Only one SETTINGS frame is allowed, so there's no need to buffer it either.
+            Frame::SETTINGS | Frame::DATA | Frame::HEADERS | Frame::PUSH_PROMISE => {
+                Err(ControlError::UnexpectedFrame(typ))
+            }
+            typ if [0x02, 0x06, 0x08, 0x09].contains(&typ.0.into_inner()) => {
+                Err(ControlError::UnexpectedFrame(typ))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // Decode a frame that contains a single VarInt and nothing else.
+    fn decode_id<B: Buf>(data: &mut B) -> Result<VarInt, ControlError> {
+        let id = VarInt::decode(data).map_err(|_| ControlError::InvalidSize)?;
+        if data.has_remaining() {
+            return Err(ControlError::InvalidSize);
+        }
+
+        Ok(id)
+    }
+
+    fn encode_id<B: BufMut>(typ: Frame, id: VarInt, buf: &mut B) {
+        typ.encode(buf);
+        VarInt::try_from(id.size()).unwrap().encode(buf);
+        id.encode(buf);
+    }
+
+    fn encode_empty<B: BufMut>(typ: Frame, buf: &mut B) {
+        typ.encode(buf);
+        VarInt::from_u32(0).encode(buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for frame in [
+            ControlFrame::Settings,
+            ControlFrame::GoAway(VarInt::from_u32(4)),
+            ControlFrame::MaxPushId(VarInt::from_u32(1 << 20)),
+            ControlFrame::CancelPush(VarInt::from_u32(0)),
+            ControlFrame::Unknown(Frame(VarInt::from_u32(0x22))),
+        ] {
+            let mut buf = Vec::new();
+            frame.encode(&mut buf);
+
+            let mut data = buf.as_slice();
+            assert_eq!(ControlFrame::decode(&mut data).unwrap(), frame);
+            assert!(data.is_empty());
+        }
+    }
+
+    #[test]
+    fn goaway_invalid_size() {
+        // A GOAWAY frame must contain exactly one VarInt.
+        let mut buf = Vec::new();
+        Frame::GOAWAY.encode(&mut buf);
+        VarInt::from_u32(2).encode(&mut buf);
+        buf.extend_from_slice(&[4, 0]);
+
+        let res = ControlFrame::decode(&mut buf.as_slice());
+        assert!(matches!(res, Err(ControlError::InvalidSize)));
+    }
+}
@@ -0,0 +1,92 @@
+use super::{error_from_http3, error_to_http3, CapsuleType, Setting, Settings, VarInt};
+
+// The request header sent by draft-02 clients, and the response header expected by them.
+pub const DRAFT02_REQUEST_HEADER: &str = "sec-webtransport-http3-draft02";
+pub const DRAFT02_RESPONSE_HEADER: &str = "sec-webtransport-http3-draft";
+
+// The version of draft-ietf-webtrans-http3 used by a connection.
+// Both sides advertise the drafts they support in SETTINGS, and the newest common draft is used.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DraftVersion {
+    // Enabled via SETTINGS_ENABLE_WEBTRANSPORT, with an extra header and 8-bit error codes.
+    // This is still used by some browsers.
+    Draft02,
+
+    // Enabled via SETTINGS_WEBTRANSPORT_MAX_SESSIONS, with 32-bit error codes and more capsules.
+    #[default]
+    Draft07,
+}
+
+impl DraftVersion {
+    // Every supported draft, newest first.
+    pub const ALL: [DraftVersion; 2] = [DraftVersion::Draft07, DraftVersion::Draft02];
+
+    // Returns the newest draft that is in the list and supported by the peer's SETTINGS.
+    pub fn negotiate(supported: &[DraftVersion], remote: &Settings) -> Option<Self> {
+        supported
+            .iter()
+            .copied()
+            .filter(|draft| remote.supports_draft(*draft))
+            .max()
+    }
+
+    // Add the settings needed to enable WebTransport with this draft.
+    pub fn enable(self, settings: &mut Settings, max_sessions: u32) {
+        let max = VarInt::from_u32(max_sessions);
+
+        settings.insert(Setting::ENABLE_CONNECT_PROTOCOL, VarInt::from_u32(1));
+
+        match self {
+            Self::Draft02 => {
+                settings.insert(Setting::ENABLE_DATAGRAM_DEPRECATED, VarInt::from_u32(1));
+                settings.insert(Setting::WEBTRANSPORT_ENABLE_DEPRECATED, VarInt::from_u32(1));
+                settings.insert(Setting::WEBTRANSPORT_MAX_SESSIONS_DEPRECATED, max);
+            }
+            Self::Draft07 => {
+                settings.insert(Setting::ENABLE_DATAGRAM, VarInt::from_u32(1));
+                settings.insert(Setting::WEBTRANSPORT_MAX_SESSIONS, max);
+            }
+        }
+    }
+
+    // Returns true if the capsule type was defined by this draft.
+    // Unsupported capsules should be ignored like any unknown capsule.
+    pub fn supports_capsule(self, typ: CapsuleType) -> bool {
+        match typ {
+            CapsuleType::DATAGRAM | CapsuleType::CLOSE_WEBTRANSPORT_SESSION => true,
+            CapsuleType::DRAIN_WEBTRANSPORT_SESSION
+            | CapsuleType::WT_MAX_DATA
+            | CapsuleType::WT_MAX_STREAM_DATA
+            | CapsuleType::WT_MAX_STREAMS_BIDI
+            | CapsuleType::WT_MAX_STREAMS_UNI
+            | CapsuleType::WT_DATA_BLOCKED
+            | CapsuleType::WT_STREAM_DATA_BLOCKED
+            | CapsuleType::WT_STREAMS_BLOCKED_BIDI
+            | CapsuleType::WT_STREAMS_BLOCKED_UNI => self >= Self::Draft07,
+            _ => false,
+        }
+    }
+
+    // The largest WebTransport error code that can be sent.
+    pub fn max_error_code(self) -> u32 {
+        match self {
+            Self::Draft02 => 0xff,
+            Self::Draft07 => u32::MAX,
+        }
+    }
+
+    // Map a WebTransport error code to HTTP/3, clamping it to the range allowed by this draft.
+    pub fn error_to_http3(self, code: u32) -> u64 {
+        error_to_http3(code.min(self.max_error_code()))
+    }
+
+    // Map an HTTP/3 error code to WebTransport, returning None if it's outside the range allowed by this draft.
+    pub fn error_from_http3(self, code: u64) -> Option<u32> {
+        let code = error_from_http3(code)?;
+        if code > self.max_error_code() {
+            return None;
+        }
+
+        Some(code)
+    }
+}
@@ -21,7 +21,13 @@ impl Frame {
             return false;
         }
 
-        (val - 0x21) % 0x1f == 0
+        (val - 0x21).is_multiple_of(0x1f)
+    }
+
+    // Returns the Nth reserved value, which the peer must ignore.
+    pub fn grease(n: u32) -> Self {
+        let val = 0x1f * n as u64 + 0x21;
+        Self(VarInt::from_u64(val).unwrap())
     }
 
     pub fn read<B: Buf>(
@@ -57,6 +63,10 @@ macro_rules! frames {
 frames! {
     DATA = 0x00,
     HEADERS = 0x01,
+    CANCEL_PUSH = 0x03,
     SETTINGS = 0x04,
+    PUSH_PROMISE = 0x05,
+    GOAWAY = 0x07,
+    MAX_PUSH_ID = 0x0d,
     WEBTRANSPORT = 0x41,
 }
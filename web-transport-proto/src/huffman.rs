@@ -34,6 +34,7 @@ impl BitWindow {
     }
 }
 
+use bytes::BufMut;
 use thiserror::Error;
 
 #[derive(Error, Debug, PartialEq, Clone)]
@@ -208,7 +209,11 @@ macro_rules! bits_decode {
         }
     };
     [ $( $name:ident => ( $($value:tt)* ), )* ] => {
-        $( const $name: HuffmanDecoder = bits_decode!( $( $value )* ); )*
+        // The tables mix character and integer literals, which are all cast to u8.
+        $(
+            #[allow(clippy::char_lit_as_u8)]
+            const $name: HuffmanDecoder = bits_decode!( $( $value )* );
+        )*
     };
 }
 
@@ -351,14 +356,241 @@ impl<'a> Iterator for DecodeIter<'a> {
 }
 
 pub trait HpackStringDecode {
-    fn hpack_decode(&self) -> DecodeIter;
+    fn hpack_decode(&self) -> DecodeIter<'_>;
 }
 
 impl HpackStringDecode for Vec<u8> {
-    fn hpack_decode(&self) -> DecodeIter {
+    fn hpack_decode(&self) -> DecodeIter<'_> {
         DecodeIter {
             bit_pos: BitWindow::new(),
             content: self,
         }
     }
 }
+
+// The encoding side is much simpler: each byte maps to a code of up to 30 bits.
+// The table was converted from https://github.com/hyperium/h3/blob/master/h3/src/qpack/prefix_string/encode.rs
+// License: MIT
+
+pub trait HpackStringEncode {
+    // The number of bytes needed to Huffman encode the string, including padding.
+    fn hpack_encoded_len(&self) -> usize;
+
+    fn hpack_encode<B: BufMut>(&self, buf: &mut B);
+}
+
+impl HpackStringEncode for [u8] {
+    fn hpack_encoded_len(&self) -> usize {
+        let bits: usize = self
+            .iter()
+            .map(|&byte| HPACK_ENCODE[byte as usize].1 as usize)
+            .sum();
+
+        bits.div_ceil(8)
+    }
+
+    fn hpack_encode<B: BufMut>(&self, buf: &mut B) {
+        // Accumulate bits until we have full bytes to write.
+        let mut pending: u64 = 0;
+        let mut count = 0;
+
+        for &byte in self {
+            let (code, len) = HPACK_ENCODE[byte as usize];
+
+            pending = (pending << len) | code as u64;
+            count += len;
+
+            while count >= 8 {
+                count -= 8;
+                buf.put_u8((pending >> count) as u8);
+            }
+
+            // Drop the bits we've already written so we never overflow.
+            pending &= (1 << count) - 1;
+        }
+
+        // Pad the last byte with the most significant bits of EOS, which are all 1s.
+        if count > 0 {
+            let padding = 8 - count;
+            buf.put_u8(((pending << padding) as u8) | ((1 << padding) - 1));
+        }
+    }
+}
+
+// The code and bit length for each byte value, as defined in RFC 7541 Appendix B.
+#[rustfmt::skip]
+const HPACK_ENCODE: [(u32, u8); 256] = [
+    (0x1ff8, 13), (0x7fffd8, 23), (0xfffffe2, 28), (0xfffffe3, 28),
+    (0xfffffe4, 28), (0xfffffe5, 28), (0xfffffe6, 28), (0xfffffe7, 28),
+    (0xfffffe8, 28), (0xffffea, 24), (0x3ffffffc, 30), (0xfffffe9, 28),
+    (0xfffffea, 28), (0x3ffffffd, 30), (0xfffffeb, 28), (0xfffffec, 28),
+    (0xfffffed, 28), (0xfffffee, 28), (0xfffffef, 28), (0xffffff0, 28),
+    (0xffffff1, 28), (0xffffff2, 28), (0x3ffffffe, 30), (0xffffff3, 28),
+    (0xffffff4, 28), (0xffffff5, 28), (0xffffff6, 28), (0xffffff7, 28),
+    (0xffffff8, 28), (0xffffff9, 28), (0xffffffa, 28), (0xffffffb, 28),
+    (0x14, 6), (0x3f8, 10), (0x3f9, 10), (0xffa, 12),
+    (0x1ff9, 13), (0x15, 6), (0xf8, 8), (0x7fa, 11),
+    (0x3fa, 10), (0x3fb, 10), (0xf9, 8), (0x7fb, 11),
+    (0xfa, 8), (0x16, 6), (0x17, 6), (0x18, 6),
+    (0x0, 5), (0x1, 5), (0x2, 5), (0x19, 6),
+    (0x1a, 6), (0x1b, 6), (0x1c, 6), (0x1d, 6),
+    (0x1e, 6), (0x1f, 6), (0x5c, 7), (0xfb, 8),
+    (0x7ffc, 15), (0x20, 6), (0xffb, 12), (0x3fc, 10),
+    (0x1ffa, 13), (0x21, 6), (0x5d, 7), (0x5e, 7),
+    (0x5f, 7), (0x60, 7), (0x61, 7), (0x62, 7),
+    (0x63, 7), (0x64, 7), (0x65, 7), (0x66, 7),
+    (0x67, 7), (0x68, 7), (0x69, 7), (0x6a, 7),
+    (0x6b, 7), (0x6c, 7), (0x6d, 7), (0x6e, 7),
+    (0x6f, 7), (0x70, 7), (0x71, 7), (0x72, 7),
+    (0xfc, 8), (0x73, 7), (0xfd, 8), (0x1ffb, 13),
+    (0x7fff0, 19), (0x1ffc, 13), (0x3ffc, 14), (0x22, 6),
+    (0x7ffd, 15), (0x3, 5), (0x23, 6), (0x4, 5),
+    (0x24, 6), (0x5, 5), (0x25, 6), (0x26, 6),
+    (0x27, 6), (0x6, 5), (0x74, 7), (0x75, 7),
+    (0x28, 6), (0x29, 6), (0x2a, 6), (0x7, 5),
+    (0x2b, 6), (0x76, 7), (0x2c, 6), (0x8, 5),
+    (0x9, 5), (0x2d, 6), (0x77, 7), (0x78, 7),
+    (0x79, 7), (0x7a, 7), (0x7b, 7), (0x7ffe, 15),
+    (0x7fc, 11), (0x3ffd, 14), (0x1ffd, 13), (0xffffffc, 28),
+    (0xfffe6, 20), (0x3fffd2, 22), (0xfffe7, 20), (0xfffe8, 20),
+    (0x3fffd3, 22), (0x3fffd4, 22), (0x3fffd5, 22), (0x7fffd9, 23),
+    (0x3fffd6, 22), (0x7fffda, 23), (0x7fffdb, 23), (0x7fffdc, 23),
+    (0x7fffdd, 23), (0x7fffde, 23), (0xffffeb, 24), (0x7fffdf, 23),
+    (0xffffec, 24), (0xffffed, 24), (0x3fffd7, 22), (0x7fffe0, 23),
+    (0xffffee, 24), (0x7fffe1, 23), (0x7fffe2, 23), (0x7fffe3, 23),
+    (0x7fffe4, 23), (0x1fffdc, 21), (0x3fffd8, 22), (0x7fffe5, 23),
+    (0x3fffd9, 22), (0x7fffe6, 23), (0x7fffe7, 23), (0xffffef, 24),
+    (0x3fffda, 22), (0x1fffdd, 21), (0xfffe9, 20), (0x3fffdb, 22),
+    (0x3fffdc, 22), (0x7fffe8, 23), (0x7fffe9, 23), (0x1fffde, 21),
+    (0x7fffea, 23), (0x3fffdd, 22), (0x3fffde, 22), (0xfffff0, 24),
+    (0x1fffdf, 21), (0x3fffdf, 22), (0x7fffeb, 23), (0x7fffec, 23),
+    (0x1fffe0, 21), (0x1fffe1, 21), (0x3fffe0, 22), (0x1fffe2, 21),
+    (0x7fffed, 23), (0x3fffe1, 22), (0x7fffee, 23), (0x7fffef, 23),
+    (0xfffea, 20), (0x3fffe2, 22), (0x3fffe3, 22), (0x3fffe4, 22),
+    (0x7ffff0, 23), (0x3fffe5, 22), (0x3fffe6, 22), (0x7ffff1, 23),
+    (0x3ffffe0, 26), (0x3ffffe1, 26), (0xfffeb, 20), (0x7fff1, 19),
+    (0x3fffe7, 22), (0x7ffff2, 23), (0x3fffe8, 22), (0x1ffffec, 25),
+    (0x3ffffe2, 26), (0x3ffffe3, 26), (0x3ffffe4, 26), (0x7ffffde, 27),
+    (0x7ffffdf, 27), (0x3ffffe5, 26), (0xfffff1, 24), (0x1ffffed, 25),
+    (0x7fff2, 19), (0x1fffe3, 21), (0x3ffffe6, 26), (0x7ffffe0, 27),
+    (0x7ffffe1, 27), (0x3ffffe7, 26), (0x7ffffe2, 27), (0xfffff2, 24),
+    (0x1fffe4, 21), (0x1fffe5, 21), (0x3ffffe8, 26), (0x3ffffe9, 26),
+    (0xffffffd, 28), (0x7ffffe3, 27), (0x7ffffe4, 27), (0x7ffffe5, 27),
+    (0xfffec, 20), (0xfffff3, 24), (0xfffed, 20), (0x1fffe6, 21),
+    (0x3fffe9, 22), (0x1fffe7, 21), (0x1fffe8, 21), (0x7ffff3, 23),
+    (0x3fffea, 22), (0x3fffeb, 22), (0x1ffffee, 25), (0x1ffffef, 25),
+    (0xfffff4, 24), (0xfffff5, 24), (0x3ffffea, 26), (0x7ffff4, 23),
+    (0x3ffffeb, 26), (0x7ffffe6, 27), (0x3ffffec, 26), (0x3ffffed, 26),
+    (0x7ffffe7, 27), (0x7ffffe8, 27), (0x7ffffe9, 27), (0x7ffffea, 27),
+    (0x7ffffeb, 27), (0xffffffe, 28), (0x7ffffec, 27), (0x7ffffed, 27),
+    (0x7ffffee, 27), (0x7ffffef, 27), (0x7fffff0, 27), (0x3ffffee, 26),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(value: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        value.hpack_encode(&mut buf);
+        assert_eq!(buf.len(), value.hpack_encoded_len());
+        buf
+    }
+
+    fn decode(encoded: &[u8]) -> Vec<u8> {
+        encoded
+            .to_vec()
+            .hpack_decode()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn round_trip_every_byte() {
+        for byte in 0..=255u8 {
+            let value = [byte];
+            assert_eq!(decode(&encode(&value)), value, "byte {}", byte);
+        }
+
+        let all: Vec<u8> = (0..=255).collect();
+        assert_eq!(decode(&encode(&all)), all);
+
+        let reversed: Vec<u8> = all.iter().rev().copied().collect();
+        assert_eq!(decode(&encode(&reversed)), reversed);
+    }
+
+    #[test]
+    fn round_trip_padding() {
+        // Every length mod 8 so the EOS padding is exercised for each bit offset.
+        for len in 0..16 {
+            let value: Vec<u8> = b"abcdefghijklmnop"[..len].to_vec();
+            assert_eq!(decode(&encode(&value)), value);
+        }
+    }
+
+    // See: https://www.rfc-editor.org/rfc/rfc7541#appendix-C.4
+    #[test]
+    fn rfc7541_request_examples() {
+        let vectors: &[(&str, &[u8])] = &[
+            (
+                "www.example.com",
+                &[
+                    0xf1, 0xe3, 0xc2, 0xe5, 0xf2, 0x3a, 0x6b, 0xa0, 0xab, 0x90, 0xf4, 0xff,
+                ],
+            ),
+            ("no-cache", &[0xa8, 0xeb, 0x10, 0x64, 0x9c, 0xbf]),
+            (
+                "custom-key",
+                &[0x25, 0xa8, 0x49, 0xe9, 0x5b, 0xa9, 0x7d, 0x7f],
+            ),
+            (
+                "custom-value",
+                &[0x25, 0xa8, 0x49, 0xe9, 0x5b, 0xb8, 0xe8, 0xb4, 0xbf],
+            ),
+        ];
+
+        for (value, encoded) in vectors {
+            assert_eq!(encode(value.as_bytes()), *encoded, "{}", value);
+            assert_eq!(decode(encoded), value.as_bytes(), "{}", value);
+        }
+    }
+
+    // See: https://www.rfc-editor.org/rfc/rfc7541#appendix-C.6
+    #[test]
+    fn rfc7541_response_examples() {
+        let vectors: &[(&str, &[u8])] = &[
+            ("302", &[0x64, 0x02]),
+            ("307", &[0x64, 0x0e, 0xff]),
+            ("private", &[0xae, 0xc3, 0x77, 0x1a, 0x4b]),
+            ("gzip", &[0x9b, 0xd9, 0xab]),
+            (
+                "Mon, 21 Oct 2013 20:13:21 GMT",
+                &[
+                    0xd0, 0x7a, 0xbe, 0x94, 0x10, 0x54, 0xd4, 0x44, 0xa8, 0x20, 0x05, 0x95, 0x04,
+                    0x0b, 0x81, 0x66, 0xe0, 0x82, 0xa6, 0x2d, 0x1b, 0xff,
+                ],
+            ),
+            (
+                "https://www.example.com",
+                &[
+                    0x9d, 0x29, 0xad, 0x17, 0x18, 0x63, 0xc7, 0x8f, 0x0b, 0x97, 0xc8, 0xe9, 0xae,
+                    0x82, 0xae, 0x43, 0xd3,
+                ],
+            ),
+            (
+                "foo=ASDJKHQKBZXOQWEOPIUAXQWEOIU; max-age=3600; version=1",
+                &[
+                    0x94, 0xe7, 0x82, 0x1d, 0xd7, 0xf2, 0xe6, 0xc7, 0xb3, 0x35, 0xdf, 0xdf, 0xcd,
+                    0x5b, 0x39, 0x60, 0xd5, 0xaf, 0x27, 0x08, 0x7f, 0x36, 0x72, 0xc1, 0xab, 0x27,
+                    0x0f, 0xb5, 0x29, 0x1f, 0x95, 0x87, 0x31, 0x60, 0x65, 0xc0, 0x03, 0xed, 0x4e,
+                    0xe5, 0xb1, 0x06, 0x3d, 0x50, 0x07,
+                ],
+            ),
+        ];
+
+        for (value, encoded) in vectors {
+            assert_eq!(encode(value.as_bytes()), *encoded, "{}", value);
+            assert_eq!(decode(encoded), value.as_bytes(), "{}", value);
+        }
+    }
+}
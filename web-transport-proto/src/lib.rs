@@ -1,16 +1,24 @@
+mod capsule;
 mod connect;
+mod control;
+mod draft;
 mod error;
 mod frame;
+mod request;
 mod settings;
 mod stream;
 mod varint;
 
+pub use capsule::*;
 pub use connect::*;
+pub use control::*;
+pub use draft::*;
 pub use error::*;
 pub use frame::*;
+pub use request::*;
 pub use settings::*;
 pub use stream::*;
 pub use varint::*;
 
 mod huffman;
-mod qpack;
+pub mod qpack;
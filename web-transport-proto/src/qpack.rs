@@ -1,12 +1,13 @@
-// This is a minimal QPACK implementation that only supports the static table and literals.
-// By refusing to acknowledge the QPACK encoder, we can avoid implementing the dynamic table altogether.
-// This is not recommended for a full HTTP/3 implementation but it's literally more efficient for handling a single WebTransport CONNECT request.
+// This is a QPACK implementation that supports the static table, literals, Huffman encoding, and the dynamic table.
+// The dynamic table is only used when decoding; the peer is free to reference it if we advertise a non-zero capacity.
+// We always encode with the static table and literals, so the peer's decoder never has anything to acknowledge.
+// See: https://www.rfc-editor.org/rfc/rfc9204.html
 
-use std::collections::HashMap;
+use std::collections::{HashSet, VecDeque};
 
 use bytes::{Buf, BufMut};
 
-use super::huffman::{self, HpackStringDecode};
+use super::huffman::{self, HpackStringDecode, HpackStringEncode};
 use thiserror::Error;
 
 #[derive(Error, Debug, Clone)]
@@ -28,6 +29,27 @@ pub enum DecodeError {
 
     #[error("invalid utf8 header")] // technically not required by the HTTP spec
     Utf8Error(#[from] std::str::Utf8Error),
+
+    #[error("blocked until insert count: {0}")]
+    Blocked(u64),
+
+    #[error("too many blocked streams")]
+    BlockedStreams,
+
+    #[error("invalid required insert count")]
+    InvalidInsertCount,
+
+    #[error("invalid base")]
+    InvalidBase,
+
+    #[error("dynamic table capacity exceeded")]
+    CapacityExceeded,
+
+    #[error("unknown stream: {0}")]
+    UnknownStream(u64),
+
+    #[error("invalid insert count increment")]
+    InvalidIncrement,
 }
 
 #[cfg(target_pointer_width = "64")]
@@ -36,27 +58,74 @@ const MAX_POWER: usize = 10 * 7;
 #[cfg(target_pointer_width = "32")]
 const MAX_POWER: usize = 5 * 7;
 
-// Simple QPACK implementation that ONLY supports the static table and literals.
+// The size of each entry is the length of the name and value plus 32 bytes of overhead.
+const ENTRY_OVERHEAD: usize = 32;
+
+// A list of fields in the order they were added, as the same name may be used multiple times.
 #[derive(Debug, Default)]
 pub struct Headers {
-    fields: HashMap<String, String>,
+    fields: Vec<(String, String)>,
 }
 
 impl Headers {
+    // Returns the first value for the given name.
     pub fn get(&self, name: &str) -> Option<&str> {
-        self.fields.get(name).map(|v| v.as_str())
+        self.fields
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
     }
 
+    // Replaces any existing values for the given name.
     pub fn set(&mut self, name: &str, value: &str) {
-        self.fields.insert(name.to_string(), value.to_string());
+        self.fields.retain(|(n, _)| n != name);
+        self.append(name, value);
     }
 
-    pub fn decode<B: Buf>(mut buf: &mut B) -> Result<Self, DecodeError> {
-        // We don't support dynamic entries so we can skip these.
-        let (_, _insert_count) = decode_prefix(buf, 8)?;
-        let (_sign, _delta_base) = decode_prefix(buf, 7)?;
+    // Adds a value without replacing any existing values for the given name.
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.fields.push((name.to_string(), value.to_string()));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    // Decode a field section that only references the static table.
+    pub fn decode<B: Buf>(buf: &mut B) -> Result<Self, DecodeError> {
+        let (headers, _) = Self::decode_section(buf, &DynamicTable::default())?;
+        Ok(headers)
+    }
+
+    // Decode a field section that may reference the given dynamic table, also returning the required insert count.
+    // Returns Blocked if the section references entries that have not been inserted yet.
+    fn decode_section<B: Buf>(
+        mut buf: &mut B,
+        table: &DynamicTable,
+    ) -> Result<(Self, u64), DecodeError> {
+        let (_, encoded_insert_count) = decode_prefix(buf, 8)?;
+        let (sign, delta_base) = decode_prefix(buf, 7)?;
+
+        let required = table.decode_insert_count(encoded_insert_count as u64)?;
+        if required > table.inserted {
+            return Err(DecodeError::Blocked(required));
+        }
+
+        // See: https://www.rfc-editor.org/rfc/rfc9204.html#section-4.5.1.2
+        let base = if sign == 0 {
+            required.checked_add(delta_base as u64)
+        } else {
+            required.checked_sub(delta_base as u64 + 1)
+        }
+        .ok_or(DecodeError::InvalidBase)?;
 
-        let mut fields = HashMap::new();
+        let dynamic = DynamicRef {
+            table,
+            base,
+            required,
+        };
+
+        let mut fields = Vec::new();
         while buf.has_remaining() {
             // Read the first byte;
             let peek = buf.get_u8();
@@ -72,24 +141,24 @@ impl Headers {
                 0b1100_0000 => Self::decode_index(&mut chain)?,
 
                 // Indexed line field from dynamic table
-                0b1000_0000 => return Err(DecodeError::DynamicEntry),
+                0b1000_0000 => Self::decode_dynamic_index(&mut chain, &dynamic)?,
 
                 _ => match peek & 0b1101_0000 {
                     // Indexed with literal name ref from static table
                     0b0101_0000 => Self::decode_literal_value(&mut chain)?,
 
                     // Indexed with literal name ref from dynamic table
-                    0b0100_0000 => return Err(DecodeError::DynamicEntry),
+                    0b0100_0000 => Self::decode_dynamic_literal_value(&mut chain, &dynamic)?,
 
                     // Literal
                     _ if peek & 0b1110_0000 == 0b0010_0000 => Self::decode_literal(&mut chain)?,
 
                     _ => match peek & 0b1111_0000 {
                         // Indexed with post base
-                        0b0001_0000 => return Err(DecodeError::DynamicEntry),
+                        0b0001_0000 => Self::decode_post_base_index(&mut chain, &dynamic)?,
 
                         // Indexed with post base name ref
-                        0b0000_0000 => return Err(DecodeError::DynamicEntry),
+                        0b0000_0000 => Self::decode_post_base_literal_value(&mut chain, &dynamic)?,
 
                         // ugh
                         _ => return Err(DecodeError::UnknownEntry),
@@ -97,13 +166,13 @@ impl Headers {
                 },
             };
 
-            fields.insert(name, value);
+            fields.push((name, value));
 
             // Get the buffer back.
             (_, buf) = chain.into_inner();
         }
 
-        Ok(Self { fields })
+        Ok((Self { fields }, required))
     }
 
     fn decode_index<B: Buf>(buf: &mut B) -> Result<(String, String), DecodeError> {
@@ -119,6 +188,38 @@ impl Headers {
         Ok((name.to_string(), value.to_string()))
     }
 
+    fn decode_dynamic_index<B: Buf>(
+        buf: &mut B,
+        dynamic: &DynamicRef,
+    ) -> Result<(String, String), DecodeError> {
+        /*
+            0   1   2   3   4   5   6   7
+        +---+---+---+---+---+---+---+---+
+        | 1 | 0 |      Index (6+)       |
+        +---+---+-----------------------+
+        */
+
+        let (_, index) = decode_prefix(buf, 6)?;
+        let (name, value) = dynamic.get_relative(index)?;
+        Ok((name.to_string(), value.to_string()))
+    }
+
+    fn decode_post_base_index<B: Buf>(
+        buf: &mut B,
+        dynamic: &DynamicRef,
+    ) -> Result<(String, String), DecodeError> {
+        /*
+          0   1   2   3   4   5   6   7
+        +---+---+---+---+---+---+---+---+
+        | 0 | 0 | 0 | 1 |  Index (4+)   |
+        +---+---+---+---+---------------+
+        */
+
+        let (_, index) = decode_prefix(buf, 4)?;
+        let (name, value) = dynamic.get_post_base(index)?;
+        Ok((name.to_string(), value.to_string()))
+    }
+
     fn decode_literal_value<B: Buf>(buf: &mut B) -> Result<(String, String), DecodeError> {
         /*
           0   1   2   3   4   5   6   7
@@ -140,6 +241,54 @@ impl Headers {
         Ok((name.to_string(), value.to_string()))
     }
 
+    fn decode_dynamic_literal_value<B: Buf>(
+        buf: &mut B,
+        dynamic: &DynamicRef,
+    ) -> Result<(String, String), DecodeError> {
+        /*
+          0   1   2   3   4   5   6   7
+        +---+---+---+---+---+---+---+---+
+        | 0 | 1 | N | 0 |Name Index (4+)|
+        +---+---+---+---+---------------+
+        | H |     Value Length (7+)     |
+        +---+---------------------------+
+        |  Value String (Length bytes)  |
+        +-------------------------------+
+        */
+
+        let (_, name) = decode_prefix(buf, 4)?;
+        let (name, _) = dynamic.get_relative(name)?;
+
+        let value = decode_string(buf, 8)?;
+        let value = std::str::from_utf8(&value)?;
+
+        Ok((name.to_string(), value.to_string()))
+    }
+
+    fn decode_post_base_literal_value<B: Buf>(
+        buf: &mut B,
+        dynamic: &DynamicRef,
+    ) -> Result<(String, String), DecodeError> {
+        /*
+          0   1   2   3   4   5   6   7
+        +---+---+---+---+---+---+---+---+
+        | 0 | 0 | 0 | 0 | N |NameIdx(3+)|
+        +---+---+---+---+---+-----------+
+        | H |     Value Length (7+)     |
+        +---+---------------------------+
+        |  Value String (Length bytes)  |
+        +-------------------------------+
+        */
+
+        let (_, name) = decode_prefix(buf, 3)?;
+        let (name, _) = dynamic.get_post_base(name)?;
+
+        let value = decode_string(buf, 8)?;
+        let value = std::str::from_utf8(&value)?;
+
+        Ok((name.to_string(), value.to_string()))
+    }
+
     fn decode_literal<B: Buf>(buf: &mut B) -> Result<(String, String), DecodeError> {
         /*
           0   1   2   3   4   5   6   7
@@ -163,19 +312,24 @@ impl Headers {
         Ok((name.to_string(), value.to_string()))
     }
 
+    // Encode a field section that only references the static table.
     pub fn encode<B: BufMut>(&self, buf: &mut B) {
-        // We don't support dynamic entries so we can skip these.
+        // No dynamic entries are referenced, so the required insert count and base are zero.
         encode_prefix(buf, 8, 0, 0);
         encode_prefix(buf, 7, 0, 0);
 
         for (name, value) in &self.fields {
-            if let Some(index) = StaticTable::find(name, value) {
-                Self::encode_index(buf, index)
-            } else if let Some(index) = StaticTable::find_name(name) {
-                Self::encode_literal_value(buf, index, value)
-            } else {
-                Self::encode_literal(buf, name, value)
-            }
+            Self::encode_field(buf, name, value)
+        }
+    }
+
+    fn encode_field<B: BufMut>(buf: &mut B, name: &str, value: &str) {
+        if let Some(index) = StaticTable::find(name, value) {
+            Self::encode_index(buf, index)
+        } else if let Some(index) = StaticTable::find_name(name) {
+            Self::encode_literal_value(buf, index, value)
+        } else {
+            Self::encode_literal(buf, name, value)
         }
     }
 
@@ -203,9 +357,7 @@ impl Headers {
         */
 
         encode_prefix(buf, 4, 0b0111, name);
-        encode_prefix(buf, 7, 0b0, value.len());
-
-        buf.put_slice(value.as_bytes());
+        encode_string(buf, 8, 0b0, value.as_bytes());
     }
 
     fn encode_literal<B: BufMut>(buf: &mut B, name: &str, value: &str) {
@@ -222,11 +374,465 @@ impl Headers {
         +-------------------------------+
         */
 
-        encode_prefix(buf, 3, 0b00110, name.len());
-        buf.put_slice(name.as_bytes());
+        encode_string(buf, 4, 0b0011, name.as_bytes());
+        encode_string(buf, 8, 0b0, value.as_bytes());
+    }
+}
+
+// The dynamic table, populated by the peer's encoder instructions.
+// Entries are addressed by their absolute index, which is the number of entries inserted before them.
+#[derive(Debug, Default)]
+pub struct DynamicTable {
+    // The oldest entry is at the front.
+    entries: VecDeque<(String, String)>,
+
+    // The size of all entries, including the overhead.
+    size: usize,
+
+    // The current capacity, as set by the encoder.
+    capacity: usize,
+
+    // The maximum capacity, as advertised by the decoder via SETTINGS.
+    max_capacity: usize,
+
+    // The total number of entries ever inserted.
+    inserted: u64,
+}
+
+impl DynamicTable {
+    pub fn new(max_capacity: usize) -> Self {
+        Self {
+            max_capacity,
+            ..Default::default()
+        }
+    }
+
+    // The number of entries that have been inserted, also known as the insert count.
+    pub fn inserted(&self) -> u64 {
+        self.inserted
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    // The absolute index of the oldest entry still in the table.
+    fn dropped(&self) -> u64 {
+        self.inserted - self.entries.len() as u64
+    }
+
+    pub fn get(&self, absolute: u64) -> Option<(&str, &str)> {
+        let index = absolute.checked_sub(self.dropped())?;
+        self.entries
+            .get(index as usize)
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
 
-        encode_prefix(buf, 7, 0b0, value.len());
-        buf.put_slice(value.as_bytes());
+    // Look up an entry relative to the most recent insert, as used by encoder instructions.
+    fn get_inserted_relative(&self, relative: usize) -> Option<(&str, &str)> {
+        let absolute = self.inserted.checked_sub(relative as u64 + 1)?;
+        self.get(absolute)
+    }
+
+    fn set_capacity(&mut self, capacity: usize) -> Result<(), DecodeError> {
+        if capacity > self.max_capacity {
+            return Err(DecodeError::CapacityExceeded);
+        }
+
+        self.capacity = capacity;
+        self.evict(0);
+
+        Ok(())
+    }
+
+    fn insert(&mut self, name: String, value: String) -> Result<(), DecodeError> {
+        let size = name.len() + value.len() + ENTRY_OVERHEAD;
+        if size > self.capacity {
+            return Err(DecodeError::CapacityExceeded);
+        }
+
+        self.evict(size);
+
+        self.size += size;
+        self.entries.push_back((name, value));
+        self.inserted += 1;
+
+        Ok(())
+    }
+
+    // Evict the oldest entries until there's room for the given size.
+    fn evict(&mut self, required: usize) {
+        while self.size + required > self.capacity {
+            let (name, value) = match self.entries.pop_front() {
+                Some(entry) => entry,
+                None => return,
+            };
+
+            self.size -= name.len() + value.len() + ENTRY_OVERHEAD;
+        }
+    }
+
+    fn max_entries(&self) -> u64 {
+        (self.max_capacity / ENTRY_OVERHEAD) as u64
+    }
+
+    // See: https://www.rfc-editor.org/rfc/rfc9204.html#section-4.5.1.1
+    fn decode_insert_count(&self, encoded: u64) -> Result<u64, DecodeError> {
+        if encoded == 0 {
+            return Ok(0);
+        }
+
+        let max_entries = self.max_entries();
+        if max_entries == 0 {
+            // We never advertised a dynamic table, so there's no way this is valid.
+            return Err(DecodeError::DynamicEntry);
+        }
+
+        let full_range = 2 * max_entries;
+        if encoded > full_range {
+            return Err(DecodeError::InvalidInsertCount);
+        }
+
+        let max_value = self.inserted + max_entries;
+        let max_wrapped = (max_value / full_range) * full_range;
+        let mut required = max_wrapped + encoded - 1;
+
+        if required > max_value {
+            if required <= full_range {
+                return Err(DecodeError::InvalidInsertCount);
+            }
+
+            required -= full_range;
+        }
+
+        if required == 0 {
+            return Err(DecodeError::InvalidInsertCount);
+        }
+
+        Ok(required)
+    }
+}
+
+// The information needed to resolve dynamic references within a single field section.
+struct DynamicRef<'a> {
+    table: &'a DynamicTable,
+    base: u64,
+    required: u64,
+}
+
+impl DynamicRef<'_> {
+    fn get_relative(&self, index: usize) -> Result<(&str, &str), DecodeError> {
+        let absolute = self
+            .base
+            .checked_sub(index as u64 + 1)
+            .ok_or(DecodeError::UnknownEntry)?;
+
+        self.get(absolute)
+    }
+
+    fn get_post_base(&self, index: usize) -> Result<(&str, &str), DecodeError> {
+        let absolute = self
+            .base
+            .checked_add(index as u64)
+            .ok_or(DecodeError::UnknownEntry)?;
+
+        self.get(absolute)
+    }
+
+    fn get(&self, absolute: u64) -> Result<(&str, &str), DecodeError> {
+        // A section may only reference entries below its required insert count.
+        if absolute >= self.required {
+            return Err(DecodeError::DynamicEntry);
+        }
+
+        self.table.get(absolute).ok_or(DecodeError::UnknownEntry)
+    }
+}
+
+// Instructions sent by the peer's encoder on the QPACK encoder stream.
+// See: https://www.rfc-editor.org/rfc/rfc9204.html#section-4.3
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EncoderInstruction {
+    SetCapacity(usize),
+    InsertStaticRef { index: usize, value: String },
+    InsertDynamicRef { index: usize, value: String },
+    InsertLiteral { name: String, value: String },
+    Duplicate(usize),
+}
+
+impl EncoderInstruction {
+    pub fn decode<B: Buf>(buf: &mut B) -> Result<Self, DecodeError> {
+        if !buf.has_remaining() {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+
+        let peek = buf.chunk()[0];
+
+        if peek & 0b1000_0000 != 0 {
+            /*
+                0   1   2   3   4   5   6   7
+            +---+---+---+---+---+---+---+---+
+            | 1 | T |    Name Index (6+)    |
+            +---+---+-----------------------+
+            | H |     Value Length (7+)     |
+            +---+---------------------------+
+            |  Value String (Length bytes)  |
+            +-------------------------------+
+            */
+
+            let (flags, index) = decode_prefix(buf, 6)?;
+            let value = decode_string(buf, 8)?;
+            let value = std::str::from_utf8(&value)?.to_string();
+
+            Ok(match flags & 0b01 {
+                0b01 => Self::InsertStaticRef { index, value },
+                _ => Self::InsertDynamicRef { index, value },
+            })
+        } else if peek & 0b0100_0000 != 0 {
+            /*
+                0   1   2   3   4   5   6   7
+            +---+---+---+---+---+---+---+---+
+            | 0 | 1 | H | Name Length (5+)  |
+            +---+---+---+-------------------+
+            |  Name String (Length bytes)   |
+            +---+---------------------------+
+            | H |     Value Length (7+)     |
+            +---+---------------------------+
+            |  Value String (Length bytes)  |
+            +-------------------------------+
+            */
+
+            let name = decode_string(buf, 6)?;
+            let name = std::str::from_utf8(&name)?.to_string();
+
+            let value = decode_string(buf, 8)?;
+            let value = std::str::from_utf8(&value)?.to_string();
+
+            Ok(Self::InsertLiteral { name, value })
+        } else if peek & 0b0010_0000 != 0 {
+            /*
+                0   1   2   3   4   5   6   7
+            +---+---+---+---+---+---+---+---+
+            | 0 | 0 | 1 |   Capacity (5+)   |
+            +---+---+---+-------------------+
+            */
+
+            let (_, capacity) = decode_prefix(buf, 5)?;
+            Ok(Self::SetCapacity(capacity))
+        } else {
+            /*
+                0   1   2   3   4   5   6   7
+            +---+---+---+---+---+---+---+---+
+            | 0 | 0 | 0 |    Index (5+)     |
+            +---+---+---+-------------------+
+            */
+
+            let (_, index) = decode_prefix(buf, 5)?;
+            Ok(Self::Duplicate(index))
+        }
+    }
+
+    pub fn encode<B: BufMut>(&self, buf: &mut B) {
+        match self {
+            Self::InsertStaticRef { index, value } => {
+                encode_prefix(buf, 6, 0b11, *index);
+                encode_string(buf, 8, 0b0, value.as_bytes());
+            }
+            Self::InsertDynamicRef { index, value } => {
+                encode_prefix(buf, 6, 0b10, *index);
+                encode_string(buf, 8, 0b0, value.as_bytes());
+            }
+            Self::InsertLiteral { name, value } => {
+                encode_string(buf, 6, 0b01, name.as_bytes());
+                encode_string(buf, 8, 0b0, value.as_bytes());
+            }
+            Self::SetCapacity(capacity) => encode_prefix(buf, 5, 0b001, *capacity),
+            Self::Duplicate(index) => encode_prefix(buf, 5, 0b000, *index),
+        }
+    }
+}
+
+// Instructions sent by the peer's decoder on the QPACK decoder stream.
+// See: https://www.rfc-editor.org/rfc/rfc9204.html#section-4.4
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecoderInstruction {
+    SectionAck(u64),
+    StreamCancel(u64),
+    InsertCountIncrement(u64),
+}
+
+impl DecoderInstruction {
+    pub fn decode<B: Buf>(buf: &mut B) -> Result<Self, DecodeError> {
+        if !buf.has_remaining() {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+
+        let peek = buf.chunk()[0];
+
+        if peek & 0b1000_0000 != 0 {
+            /*
+                0   1   2   3   4   5   6   7
+            +---+---+---+---+---+---+---+---+
+            | 1 |      Stream ID (7+)       |
+            +---+---------------------------+
+            */
+
+            let (_, id) = decode_prefix(buf, 7)?;
+            Ok(Self::SectionAck(id as u64))
+        } else if peek & 0b0100_0000 != 0 {
+            /*
+                0   1   2   3   4   5   6   7
+            +---+---+---+---+---+---+---+---+
+            | 0 | 1 |     Stream ID (6+)    |
+            +---+---+-----------------------+
+            */
+
+            let (_, id) = decode_prefix(buf, 6)?;
+            Ok(Self::StreamCancel(id as u64))
+        } else {
+            /*
+                0   1   2   3   4   5   6   7
+            +---+---+---+---+---+---+---+---+
+            | 0 | 0 |     Increment (6+)    |
+            +---+---+-----------------------+
+            */
+
+            let (_, increment) = decode_prefix(buf, 6)?;
+            Ok(Self::InsertCountIncrement(increment as u64))
+        }
+    }
+
+    pub fn encode<B: BufMut>(&self, buf: &mut B) {
+        match *self {
+            Self::SectionAck(id) => encode_prefix(buf, 7, 0b1, id as usize),
+            Self::StreamCancel(id) => encode_prefix(buf, 6, 0b01, id as usize),
+            Self::InsertCountIncrement(increment) => {
+                encode_prefix(buf, 6, 0b00, increment as usize)
+            }
+        }
+    }
+}
+
+// Decodes field sections using a dynamic table populated by the peer's encoder stream.
+#[derive(Debug, Default)]
+pub struct Decoder {
+    table: DynamicTable,
+
+    // The maximum number of streams that can be blocked at once, as advertised via SETTINGS.
+    max_blocked: usize,
+
+    // The streams currently waiting on inserts.
+    blocked: HashSet<u64>,
+
+    // The insert count that we've told the encoder about.
+    acknowledged: u64,
+
+    // Instructions that need to be sent on our decoder stream.
+    pending: Vec<DecoderInstruction>,
+}
+
+impl Decoder {
+    pub fn new(max_capacity: usize, max_blocked: usize) -> Self {
+        Self {
+            table: DynamicTable::new(max_capacity),
+            max_blocked,
+            ..Default::default()
+        }
+    }
+
+    pub fn table(&self) -> &DynamicTable {
+        &self.table
+    }
+
+    // Decode the field section for the given stream.
+    // Returns Blocked if more encoder instructions are needed, in which case the caller should retry later.
+    pub fn decode<B: Buf>(&mut self, stream_id: u64, buf: &mut B) -> Result<Headers, DecodeError> {
+        match Headers::decode_section(buf, &self.table) {
+            Ok((headers, required)) => {
+                self.blocked.remove(&stream_id);
+
+                if required > 0 {
+                    // This implicitly tells the encoder about every insert up to the required insert count.
+                    self.pending.push(DecoderInstruction::SectionAck(stream_id));
+                    self.acknowledged = self.acknowledged.max(required);
+                }
+
+                Ok(headers)
+            }
+            Err(DecodeError::Blocked(required)) => {
+                self.blocked.insert(stream_id);
+                if self.blocked.len() > self.max_blocked {
+                    // The stream is going to be reset, so don't let it hold a slot.
+                    self.blocked.remove(&stream_id);
+                    return Err(DecodeError::BlockedStreams);
+                }
+
+                Err(DecodeError::Blocked(required))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    // Apply an instruction received on the peer's encoder stream.
+    pub fn apply(&mut self, instruction: EncoderInstruction) -> Result<(), DecodeError> {
+        match instruction {
+            EncoderInstruction::SetCapacity(capacity) => self.table.set_capacity(capacity)?,
+            EncoderInstruction::InsertStaticRef { index, value } => {
+                let (name, _) = StaticTable::get(index)?;
+                self.table.insert(name.to_string(), value)?;
+            }
+            EncoderInstruction::InsertDynamicRef { index, value } => {
+                let (name, _) = self
+                    .table
+                    .get_inserted_relative(index)
+                    .ok_or(DecodeError::UnknownEntry)?;
+                self.table.insert(name.to_string(), value)?;
+            }
+            EncoderInstruction::InsertLiteral { name, value } => self.table.insert(name, value)?,
+            EncoderInstruction::Duplicate(index) => {
+                let (name, value) = self
+                    .table
+                    .get_inserted_relative(index)
+                    .ok_or(DecodeError::UnknownEntry)?;
+                self.table.insert(name.to_string(), value.to_string())?;
+            }
+        };
+
+        Ok(())
+    }
+
+    // Abandon decoding the given stream, for example if it was reset.
+    pub fn cancel(&mut self, stream_id: u64) {
+        self.blocked.remove(&stream_id);
+
+        // We only need to cancel if the encoder could have referenced the dynamic table.
+        if self.table.max_capacity > 0 {
+            self.pending
+                .push(DecoderInstruction::StreamCancel(stream_id));
+        }
+    }
+
+    // Returns true if the stream is waiting on inserts from the encoder stream.
+    pub fn is_blocked(&self, stream_id: u64) -> bool {
+        self.blocked.contains(&stream_id)
+    }
+
+    // Encode any instructions that need to be sent on our decoder stream.
+    pub fn encode_instructions<B: BufMut>(&mut self, buf: &mut B) {
+        for instruction in self.pending.drain(..) {
+            instruction.encode(buf);
+        }
+
+        // Tell the encoder about any inserts that weren't implicitly acknowledged by a section.
+        if self.table.inserted > self.acknowledged {
+            let increment = self.table.inserted - self.acknowledged;
+            DecoderInstruction::InsertCountIncrement(increment).encode(buf);
+            self.acknowledged = self.table.inserted;
+        }
     }
 }
 
@@ -328,6 +934,20 @@ pub fn decode_string<B: Buf>(buf: &mut B, size: u8) -> Result<Vec<u8>, DecodeErr
     Ok(value)
 }
 
+// Encode a string literal, using Huffman encoding whenever it's shorter.
+// The size includes the H bit, matching decode_string.
+pub fn encode_string<B: BufMut>(buf: &mut B, size: u8, flags: u8, value: &[u8]) {
+    let huffman = value.hpack_encoded_len();
+
+    if huffman < value.len() {
+        encode_prefix(buf, size - 1, (flags << 1) | 1, huffman);
+        value.hpack_encode(buf);
+    } else {
+        encode_prefix(buf, size - 1, flags << 1, value.len());
+        buf.put_slice(value);
+    }
+}
+
 // Based on https://github.com/hyperium/h3/blob/master/h3/src/qpack/static_.rs
 // I switched over to str because it's nicer in Rust... even though HTTP doesn't use utf8.
 struct StaticTable {}
@@ -620,3 +1240,261 @@ const PREDEFINED_HEADERS: [(&str, &str); 99] = [
     ("x-frame-options", "deny"),
     ("x-frame-options", "sameorigin"),
 ];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(value: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        encode_string(&mut buf, 8, 0, value);
+        buf
+    }
+
+    #[test]
+    fn string_huffman_when_shorter() {
+        // Lowercase ASCII takes 5-6 bits per byte with Huffman.
+        let encoded = string(b"www.example.com");
+        assert_eq!(encoded[0] & 0x80, 0x80, "expected the H bit");
+        assert_eq!(encoded.len(), 1 + 12);
+
+        assert_eq!(
+            decode_string(&mut encoded.as_slice(), 8).unwrap(),
+            b"www.example.com"
+        );
+    }
+
+    #[test]
+    fn string_literal_when_not_shorter() {
+        // Control characters take 13+ bits per byte with Huffman.
+        let value = [0x00, 0x01, 0x7f, 0xff];
+        let encoded = string(&value);
+        assert_eq!(encoded[0], value.len() as u8, "expected no H bit");
+        assert_eq!(&encoded[1..], value);
+
+        // Equal lengths also use the literal, as there's no point paying for Huffman decoding.
+        // "0" is 5 bits, so the Huffman encoding is also 1 byte.
+        let encoded = string(b"0");
+        assert_eq!(encoded, [0x01, b'0']);
+
+        assert_eq!(string(b""), [0x00]);
+    }
+
+    #[test]
+    fn string_round_trip_with_flags() {
+        // Literal names use a 4-bit prefix with flags, ex. 0b0011 for literal field lines.
+        for value in [&b"x-custom-header"[..], b"\x00\x01", b""] {
+            let mut buf = Vec::new();
+            encode_string(&mut buf, 4, 0b0011, value);
+
+            let mut slice = buf.as_slice();
+            assert_eq!(slice[0] >> 4, 0b0011);
+            assert_eq!(decode_string(&mut slice, 4).unwrap(), value);
+            assert!(slice.is_empty());
+        }
+    }
+
+    #[test]
+    fn headers_round_trip() {
+        let mut headers = Headers::default();
+        headers.set(":method", "CONNECT");
+        headers.set(":protocol", "webtransport");
+        headers.set(":path", "/chat?room=1");
+        headers.set("x-binary", "\u{1}\u{2}");
+        headers.append("origin", "https://example.com");
+
+        let mut buf = Vec::new();
+        headers.encode(&mut buf);
+
+        let decoded = Headers::decode(&mut buf.as_slice()).unwrap();
+        assert_eq!(
+            decoded.iter().collect::<Vec<_>>(),
+            headers.iter().collect::<Vec<_>>()
+        );
+    }
+
+    // A field section with a single indexed reference to the first dynamic entry.
+    fn dynamic_section() -> Vec<u8> {
+        let mut buf = Vec::new();
+        encode_prefix(&mut buf, 8, 0, 2); // required insert count 1
+        encode_prefix(&mut buf, 7, 0, 0); // base 1
+        encode_prefix(&mut buf, 6, 0b10, 0); // relative index 0
+        buf
+    }
+
+    fn instructions(buf: &[u8]) -> Vec<DecoderInstruction> {
+        let mut buf = buf;
+        let mut instructions = Vec::new();
+        while !buf.is_empty() {
+            instructions.push(DecoderInstruction::decode(&mut buf).unwrap());
+        }
+        instructions
+    }
+
+    fn insert(decoder: &mut Decoder, name: &str, value: &str) {
+        decoder
+            .apply(EncoderInstruction::InsertLiteral {
+                name: name.to_string(),
+                value: value.to_string(),
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn insert_count_wraparound() {
+        // 4096 / 32 = 128 entries, so the encoded value wraps every 256 inserts.
+        let mut table = DynamicTable::new(4096);
+        let max_entries = table.max_entries();
+        let full_range = 2 * max_entries;
+
+        assert_eq!(table.decode_insert_count(0).unwrap(), 0);
+
+        for inserted in [0, 1, 127, 128, 255, 256, 257, 1000, 65_535] {
+            table.inserted = inserted;
+
+            // Any required insert count the encoder could send, including ones we haven't received yet.
+            let max_value = inserted + max_entries;
+            let min_value = (max_value + 1).saturating_sub(full_range).max(1);
+
+            for required in min_value..=max_value {
+                let encoded = required % full_range + 1;
+                assert_eq!(
+                    table.decode_insert_count(encoded).unwrap(),
+                    required,
+                    "inserted={} required={}",
+                    inserted,
+                    required
+                );
+            }
+        }
+
+        assert!(matches!(
+            table.decode_insert_count(full_range + 1),
+            Err(DecodeError::InvalidInsertCount)
+        ));
+
+        // A required insert count of zero must be encoded as zero.
+        table.inserted = 0;
+        assert!(matches!(
+            table.decode_insert_count(1),
+            Err(DecodeError::InvalidInsertCount)
+        ));
+
+        // Without a dynamic table, any reference is an error.
+        let table = DynamicTable::new(0);
+        assert!(matches!(
+            table.decode_insert_count(1),
+            Err(DecodeError::DynamicEntry)
+        ));
+    }
+
+    #[test]
+    fn blocked_streams() {
+        let mut decoder = Decoder::new(4096, 1);
+        let section = dynamic_section();
+
+        assert!(matches!(
+            decoder.decode(0, &mut section.as_slice()),
+            Err(DecodeError::Blocked(1))
+        ));
+        assert!(decoder.is_blocked(0));
+
+        // Retrying the same stream doesn't use another slot.
+        assert!(matches!(
+            decoder.decode(0, &mut section.as_slice()),
+            Err(DecodeError::Blocked(1))
+        ));
+
+        // A second stream is over the limit, and doesn't hold a slot either.
+        assert!(matches!(
+            decoder.decode(4, &mut section.as_slice()),
+            Err(DecodeError::BlockedStreams)
+        ));
+        assert!(!decoder.is_blocked(4));
+
+        decoder
+            .apply(EncoderInstruction::SetCapacity(4096))
+            .unwrap();
+        insert(&mut decoder, "x-test", "1");
+
+        let headers = decoder.decode(0, &mut section.as_slice()).unwrap();
+        assert_eq!(headers.get("x-test"), Some("1"));
+        assert!(!decoder.is_blocked(0));
+
+        // The section acknowledgement implies the insert, so there's no increment.
+        let mut buf = Vec::new();
+        decoder.encode_instructions(&mut buf);
+        assert_eq!(instructions(&buf), [DecoderInstruction::SectionAck(0)]);
+    }
+
+    #[test]
+    fn cancel_blocked_stream() {
+        let mut decoder = Decoder::new(4096, 1);
+        let section = dynamic_section();
+
+        assert!(matches!(
+            decoder.decode(0, &mut section.as_slice()),
+            Err(DecodeError::Blocked(1))
+        ));
+
+        decoder.cancel(0);
+        assert!(!decoder.is_blocked(0));
+
+        // The slot is free for another stream.
+        assert!(matches!(
+            decoder.decode(4, &mut section.as_slice()),
+            Err(DecodeError::Blocked(1))
+        ));
+
+        let mut buf = Vec::new();
+        decoder.encode_instructions(&mut buf);
+        assert_eq!(instructions(&buf), [DecoderInstruction::StreamCancel(0)]);
+
+        // There's nothing to cancel without a dynamic table.
+        let mut decoder = Decoder::new(0, 0);
+        decoder.cancel(0);
+
+        let mut buf = Vec::new();
+        decoder.encode_instructions(&mut buf);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn insert_count_increment() {
+        let mut decoder = Decoder::new(4096, 16);
+        decoder
+            .apply(EncoderInstruction::SetCapacity(4096))
+            .unwrap();
+        insert(&mut decoder, "x-a", "1");
+        insert(&mut decoder, "x-b", "2");
+
+        let mut buf = Vec::new();
+        decoder.encode_instructions(&mut buf);
+        assert_eq!(
+            instructions(&buf),
+            [DecoderInstruction::InsertCountIncrement(2)]
+        );
+
+        // Everything was acknowledged, so there's nothing left to send.
+        let mut buf = Vec::new();
+        decoder.encode_instructions(&mut buf);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn decoder_instruction_round_trip() {
+        // Large values overflow the prefix into continuation bytes.
+        for instruction in [
+            DecoderInstruction::SectionAck(0),
+            DecoderInstruction::SectionAck(1_000_000),
+            DecoderInstruction::StreamCancel(4),
+            DecoderInstruction::StreamCancel(1_000_000),
+            DecoderInstruction::InsertCountIncrement(1),
+            DecoderInstruction::InsertCountIncrement(1_000_000),
+        ] {
+            let mut buf = Vec::new();
+            instruction.encode(&mut buf);
+            assert_eq!(instructions(&buf), [instruction]);
+        }
+    }
+}
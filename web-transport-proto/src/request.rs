@@ -0,0 +1,196 @@
+use std::str::FromStr;
+
+use bytes::{Buf, BufMut};
+
+use super::{
+    decode_header_map, encode_header_map, qpack, ConnectError, ConnectRequest, Frame, VarInt,
+};
+
+// The first frame on a request stream, which is only a WebTransport session if it's an extended CONNECT.
+#[derive(Debug)]
+pub enum Request {
+    Connect(ConnectRequest),
+    Http(HttpRequest),
+}
+
+impl Request {
+    pub fn decode<B: Buf>(buf: &mut B) -> Result<Self, ConnectError> {
+        Self::decode_with(buf, &mut qpack::Decoder::default(), 0)
+    }
+
+    // Decode the request using the QPACK dynamic table, which may return a Blocked error.
+    pub fn decode_with<B: Buf>(
+        buf: &mut B,
+        decoder: &mut qpack::Decoder,
+        stream_id: u64,
+    ) -> Result<Self, ConnectError> {
+        let headers = decode_headers(buf, decoder, stream_id)?;
+
+        // An extended CONNECT includes the :protocol pseudo-header, otherwise it's a normal request.
+        match (headers.get(":method"), headers.get(":protocol")) {
+            (Some("CONNECT"), Some(_)) => {
+                Ok(Self::Connect(ConnectRequest::from_headers(&headers)?))
+            }
+            _ => Ok(Self::Http(HttpRequest::from_headers(&headers)?)),
+        }
+    }
+}
+
+// A plain HTTP/3 request, such as GET or POST.
+#[derive(Debug)]
+pub struct HttpRequest {
+    pub method: http::Method,
+    pub uri: http::Uri,
+
+    // Any additional headers, excluding pseudo-headers.
+    pub headers: http::HeaderMap,
+}
+
+impl HttpRequest {
+    pub fn decode<B: Buf>(buf: &mut B) -> Result<Self, ConnectError> {
+        Self::decode_with(buf, &mut qpack::Decoder::default(), 0)
+    }
+
+    // Decode the request using the QPACK dynamic table, which may return a Blocked error.
+    pub fn decode_with<B: Buf>(
+        buf: &mut B,
+        decoder: &mut qpack::Decoder,
+        stream_id: u64,
+    ) -> Result<Self, ConnectError> {
+        let headers = decode_headers(buf, decoder, stream_id)?;
+        Self::from_headers(&headers)
+    }
+
+    fn from_headers(headers: &qpack::Headers) -> Result<Self, ConnectError> {
+        let method = headers
+            .get(":method")
+            .ok_or(ConnectError::WrongMethod(None))?;
+        let method = http::Method::from_str(method).map_err(|_| ConnectError::InvalidMethod)?;
+
+        let mut uri = http::Uri::builder();
+
+        // A CONNECT request only has an authority, while everything else requires a scheme and path.
+        if method != http::Method::CONNECT {
+            let scheme = headers
+                .get(":scheme")
+                .ok_or(ConnectError::WrongScheme(None))?;
+            let path = headers.get(":path").ok_or(ConnectError::WrongPath)?;
+            uri = uri.scheme(scheme).path_and_query(path);
+        }
+
+        // The authority is optional, but falls back to the host header.
+        let authority = headers.get(":authority").or(headers.get("host"));
+        match authority {
+            Some(authority) => uri = uri.authority(authority),
+            None if method == http::Method::CONNECT => return Err(ConnectError::WrongAuthority),
+            None => {}
+        }
+
+        let uri = uri
+            .build()
+            .map_err(|_| ConnectError::InvalidHeader(":path".to_string()))?;
+
+        let headers = decode_header_map(headers)?;
+
+        Ok(Self {
+            method,
+            uri,
+            headers,
+        })
+    }
+
+    pub fn encode<B: BufMut>(&self, buf: &mut B) {
+        let mut headers = qpack::Headers::default();
+        headers.set(":method", self.method.as_str());
+
+        if let Some(scheme) = self.uri.scheme_str() {
+            headers.set(":scheme", scheme);
+        }
+
+        if let Some(authority) = self.uri.authority() {
+            headers.set(":authority", authority.as_str());
+        }
+
+        if let Some(path) = self.uri.path_and_query() {
+            headers.set(":path", path.as_str());
+        }
+
+        // Pseudo-headers must come first.
+        encode_header_map(&mut headers, &self.headers);
+        encode_headers(&headers, buf);
+    }
+}
+
+// The response to a plain HTTP/3 request, sent before any DATA frames.
+#[derive(Debug)]
+pub struct HttpResponse {
+    pub status: http::StatusCode,
+
+    // Any additional headers, excluding pseudo-headers.
+    pub headers: http::HeaderMap,
+}
+
+impl HttpResponse {
+    pub fn new(status: http::StatusCode) -> Self {
+        Self {
+            status,
+            headers: Default::default(),
+        }
+    }
+
+    pub fn decode<B: Buf>(buf: &mut B) -> Result<Self, ConnectError> {
+        Self::decode_with(buf, &mut qpack::Decoder::default(), 0)
+    }
+
+    // Decode the response using the QPACK dynamic table, which may return a Blocked error.
+    pub fn decode_with<B: Buf>(
+        buf: &mut B,
+        decoder: &mut qpack::Decoder,
+        stream_id: u64,
+    ) -> Result<Self, ConnectError> {
+        let headers = decode_headers(buf, decoder, stream_id)?;
+
+        let status = headers
+            .get(":status")
+            .ok_or(ConnectError::WrongStatus(None))?;
+        let status = http::StatusCode::from_str(status).map_err(|_| ConnectError::InvalidStatus)?;
+
+        let headers = decode_header_map(&headers)?;
+
+        Ok(Self { status, headers })
+    }
+
+    pub fn encode<B: BufMut>(&self, buf: &mut B) {
+        let mut headers = qpack::Headers::default();
+        headers.set(":status", self.status.as_str());
+
+        encode_header_map(&mut headers, &self.headers);
+        encode_headers(&headers, buf);
+    }
+}
+
+// Read a HEADERS frame, returning UnexpectedEnd if the buffer doesn't contain the full frame.
+fn decode_headers<B: Buf>(
+    buf: &mut B,
+    decoder: &mut qpack::Decoder,
+    stream_id: u64,
+) -> Result<qpack::Headers, ConnectError> {
+    let (typ, mut data) = Frame::read(buf).map_err(|_| ConnectError::UnexpectedEnd)?;
+    if typ != Frame::HEADERS {
+        return Err(ConnectError::UnexpectedFrame(typ));
+    }
+
+    // We no longer return UnexpectedEnd because we know the buffer should be large enough.
+    Ok(decoder.decode(stream_id, &mut data)?)
+}
+
+fn encode_headers<B: BufMut>(headers: &qpack::Headers, buf: &mut B) {
+    // Use a temporary buffer so we can compute the size.
+    let mut tmp = Vec::new();
+    headers.encode(&mut tmp);
+    let size = VarInt::try_from(tmp.len()).unwrap();
+
+    Frame::HEADERS.encode(buf);
+    size.encode(buf);
+    buf.put_slice(&tmp);
+}
@@ -7,7 +7,7 @@ use bytes::{Buf, BufMut};
 
 use thiserror::Error;
 
-use super::{Frame, StreamUni, VarInt, VarIntUnexpectedEnd};
+use super::{DraftVersion, Frame, StreamUni, VarInt, VarIntUnexpectedEnd};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Setting(pub VarInt);
@@ -20,6 +20,27 @@ impl Setting {
     pub fn encode<B: BufMut>(&self, buf: &mut B) {
         self.0.encode(buf)
     }
+
+    // Reserved setting IDs are of the form 0x1f * N + 0x21, and must be ignored.
+    pub fn is_grease(&self) -> bool {
+        let val = self.0.into_inner();
+        if val < 0x21 {
+            return false;
+        }
+
+        (val - 0x21).is_multiple_of(0x1f)
+    }
+
+    // Returns the Nth reserved value, which the peer must ignore.
+    pub fn grease(n: u32) -> Self {
+        let val = 0x1f * n as u64 + 0x21;
+        Self(VarInt::from_u64(val).unwrap())
+    }
+
+    // Settings from HTTP/2 that don't exist in HTTP/3, which are an error to receive.
+    pub fn is_http2(&self) -> bool {
+        matches!(self.0.into_inner(), 0x0 | 0x2 | 0x3 | 0x4 | 0x5)
+    }
 }
 
 macro_rules! settings {
@@ -47,6 +68,11 @@ settings! {
 
     // New way to enable WebTransport
     WEBTRANSPORT_MAX_SESSIONS = 0xc671706a,
+
+    // The initial flow control limits for each WebTransport session, added in draft 07
+    WEBTRANSPORT_INITIAL_MAX_DATA = 0x2b61,
+    WEBTRANSPORT_INITIAL_MAX_STREAMS_UNI = 0x2b64,
+    WEBTRANSPORT_INITIAL_MAX_STREAMS_BIDI = 0x2b65,
 }
 
 #[derive(Error, Debug, Clone)]
@@ -62,10 +88,20 @@ pub enum SettingsError {
 
     #[error("invalid size")]
     InvalidSize,
+
+    #[error("duplicate setting {0:?}")]
+    DuplicateSetting(Setting),
+
+    #[error("HTTP/2 setting {0:?} is not allowed")]
+    Http2Setting(Setting),
+
+    #[error("invalid value for setting {0:?}: {1}")]
+    InvalidValue(Setting, VarInt),
 }
 
-// A map of settings to values.
-#[derive(Default, Debug)]
+// The settings sent by one side of the connection.
+// The typed accessors return the default value for any settings that are missing.
+#[derive(Default, Debug, Clone)]
 pub struct Settings(HashMap<Setting, VarInt>);
 
 impl Settings {
@@ -75,17 +111,53 @@ impl Settings {
             return Err(SettingsError::UnexpectedStreamType(typ));
         }
 
+        Self::decode_frame(buf)
+    }
+
+    // Decode the SETTINGS frame, after the stream type was already read.
+    pub fn decode_frame<B: Buf>(buf: &mut B) -> Result<Self, SettingsError> {
         let (typ, mut data) = Frame::read(buf).map_err(|_| SettingsError::UnexpectedEnd)?;
         if typ != Frame::SETTINGS {
             return Err(SettingsError::UnexpectedFrame(typ));
         }
 
         let mut settings = Settings::default();
+        let mut grease = Vec::new();
+
         while data.has_remaining() {
             // These return a different error because retrying won't help.
             let id = Setting::decode(&mut data).map_err(|_| SettingsError::InvalidSize)?;
             let value = VarInt::decode(&mut data).map_err(|_| SettingsError::InvalidSize)?;
-            settings.0.insert(id, value);
+
+            if id.is_http2() {
+                return Err(SettingsError::Http2Setting(id));
+            }
+
+            // GREASE settings are ignored, but still can't be duplicated.
+            if id.is_grease() {
+                if grease.contains(&id) {
+                    return Err(SettingsError::DuplicateSetting(id));
+                }
+
+                grease.push(id);
+                continue;
+            }
+
+            // Boolean settings must be 0 or 1.
+            let boolean = matches!(
+                id,
+                Setting::ENABLE_CONNECT_PROTOCOL
+                    | Setting::ENABLE_DATAGRAM
+                    | Setting::ENABLE_DATAGRAM_DEPRECATED
+                    | Setting::WEBTRANSPORT_ENABLE_DEPRECATED
+            );
+            if boolean && value.into_inner() > 1 {
+                return Err(SettingsError::InvalidValue(id, value));
+            }
+
+            if settings.0.insert(id, value).is_some() {
+                return Err(SettingsError::DuplicateSetting(id));
+            }
         }
 
         Ok(settings)
@@ -106,60 +178,105 @@ impl Settings {
         buf.put_slice(&tmp);
     }
 
+    // Enable WebTransport for every supported draft, allowing up to max_sessions at once.
     pub fn enable_webtransport(&mut self, max_sessions: u32) {
-        let max = VarInt::from_u32(max_sessions);
+        for draft in DraftVersion::ALL {
+            draft.enable(self, max_sessions);
+        }
+    }
 
-        self.insert(Setting::ENABLE_CONNECT_PROTOCOL, VarInt::from_u32(1));
-        self.insert(Setting::ENABLE_DATAGRAM, VarInt::from_u32(1));
-        self.insert(Setting::ENABLE_DATAGRAM_DEPRECATED, VarInt::from_u32(1));
-        self.insert(Setting::WEBTRANSPORT_MAX_SESSIONS, max);
+    // Returns true if the peer enabled WebTransport using the given draft.
+    pub fn supports_draft(&self, draft: DraftVersion) -> bool {
+        // NOTE: The presence of ENABLE_WEBTRANSPORT implies ENABLE_CONNECT is supported.
+        if !self.datagrams() {
+            return false;
+        }
 
-        // TODO remove when 07 is in the wild
-        self.insert(Setting::WEBTRANSPORT_MAX_SESSIONS_DEPRECATED, max);
-        self.insert(Setting::WEBTRANSPORT_ENABLE_DEPRECATED, VarInt::from_u32(1));
+        match draft {
+            // Both sides send ENABLE=1 and the server may send MAX_SESSIONS=N to limit the sessions.
+            DraftVersion::Draft02 => self.value(Setting::WEBTRANSPORT_ENABLE_DEPRECATED) == 1,
+            // Both sides send MAX_SESSIONS, and a non-zero value means WebTransport is enabled.
+            DraftVersion::Draft07 => self.value(Setting::WEBTRANSPORT_MAX_SESSIONS) > 0,
+        }
     }
 
-    // Returns the maximum number of sessions supported.
-    pub fn supports_webtransport(&self) -> u64 {
-        // Sent by Chrome 114.0.5735.198 (July 19, 2023)
-        // Setting(1): 65536,              // qpack_max_table_capacity
-        // Setting(6): 16384,              // max_field_section_size
-        // Setting(7): 100,                // qpack_blocked_streams
-        // Setting(51): 1,                 // enable_datagram
-        // Setting(16765559): 1            // enable_datagram_deprecated
-        // Setting(727725890): 1,          // webtransport_max_sessions_deprecated
-        // Setting(4445614305): 454654587, // grease
+    // Returns the maximum number of sessions allowed with the given draft.
+    pub fn max_sessions(&self, draft: DraftVersion) -> u64 {
+        if !self.supports_draft(draft) {
+            return 0;
+        }
 
-        // NOTE: The presence of ENABLE_WEBTRANSPORT implies ENABLE_CONNECT is supported.
+        match draft {
+            // Only the server is allowed to set this one, so if it's None we assume it's 1.
+            DraftVersion::Draft02 => self
+                .get(&Setting::WEBTRANSPORT_MAX_SESSIONS_DEPRECATED)
+                .copied()
+                .map(|v| v.into_inner())
+                .unwrap_or(1),
+            DraftVersion::Draft07 => self.value(Setting::WEBTRANSPORT_MAX_SESSIONS),
+        }
+    }
+
+    // Returns the maximum number of sessions using the newest supported draft, or 0 if WebTransport isn't supported.
+    pub fn supports_webtransport(&self) -> u64 {
+        DraftVersion::negotiate(&DraftVersion::ALL, self)
+            .map(|draft| self.max_sessions(draft))
+            .unwrap_or(0)
+    }
 
+    // Returns true if HTTP datagrams are supported, using the deprecated setting if needed.
+    pub fn datagrams(&self) -> bool {
         let datagram = self
             .get(&Setting::ENABLE_DATAGRAM)
-            .or(self.get(&Setting::ENABLE_DATAGRAM_DEPRECATED))
-            .map(|v| v.into_inner());
+            .copied()
+            .or(self.get(&Setting::ENABLE_DATAGRAM_DEPRECATED).copied());
 
-        if datagram != Some(1) {
-            return 0;
-        }
+        datagram.map(|v| v.into_inner()) == Some(1)
+    }
 
-        // The deprecated (before draft-07) way of enabling WebTransport was to send two parameters.
-        // Both would send ENABLE=1 and the server would send MAX_SESSIONS=N to limit the sessions.
-        // Now both just send MAX_SESSIONS, and a non-zero value means WebTransport is enabled.
+    // Returns true if extended CONNECT is supported.
+    pub fn connect_protocol(&self) -> bool {
+        self.value(Setting::ENABLE_CONNECT_PROTOCOL) == 1
+    }
 
-        if let Some(max) = self.get(&Setting::WEBTRANSPORT_MAX_SESSIONS) {
-            return max.into_inner();
-        }
+    // The size of the QPACK dynamic table the peer allows us to use, which defaults to 0.
+    pub fn qpack_max_table_capacity(&self) -> u64 {
+        self.value(Setting::QPACK_MAX_TABLE_CAPACITY)
+    }
 
-        let enabled = self
-            .get(&Setting::WEBTRANSPORT_ENABLE_DEPRECATED)
-            .map(|v| v.into_inner());
-        if enabled != Some(1) {
-            return 0;
-        }
+    // The number of streams that may be blocked on the QPACK dynamic table, which defaults to 0.
+    pub fn qpack_blocked_streams(&self) -> u64 {
+        self.value(Setting::QPACK_BLOCKED_STREAMS)
+    }
 
-        // Only the server is allowed to set this one, so if it's None we assume it's 1.
-        self.get(&Setting::WEBTRANSPORT_MAX_SESSIONS_DEPRECATED)
+    // The maximum size of a header block, or None if it's unlimited.
+    pub fn max_field_section_size(&self) -> Option<u64> {
+        self.get(&Setting::MAX_FIELD_SECTION_SIZE)
+            .copied()
             .map(|v| v.into_inner())
-            .unwrap_or(1)
+    }
+
+    // The initial flow control limits for each WebTransport session, or None if not sent.
+    pub fn webtransport_initial_max_data(&self) -> Option<u64> {
+        self.get(&Setting::WEBTRANSPORT_INITIAL_MAX_DATA)
+            .copied()
+            .map(|v| v.into_inner())
+    }
+
+    pub fn webtransport_initial_max_streams_uni(&self) -> Option<u64> {
+        self.get(&Setting::WEBTRANSPORT_INITIAL_MAX_STREAMS_UNI)
+            .copied()
+            .map(|v| v.into_inner())
+    }
+
+    pub fn webtransport_initial_max_streams_bidi(&self) -> Option<u64> {
+        self.get(&Setting::WEBTRANSPORT_INITIAL_MAX_STREAMS_BIDI)
+            .copied()
+            .map(|v| v.into_inner())
+    }
+
+    fn value(&self, id: Setting) -> u64 {
+        self.get(&id).map(|v| v.into_inner()).unwrap_or(0)
     }
 }
 
@@ -176,3 +293,111 @@ impl DerefMut for Settings {
         &mut self.0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Encodes a SETTINGS frame containing the given pairs, in order.
+    fn frame(pairs: &[(Setting, u64)]) -> Vec<u8> {
+        let mut tmp = Vec::new();
+        for (id, value) in pairs {
+            id.encode(&mut tmp);
+            VarInt::from_u64(*value).unwrap().encode(&mut tmp);
+        }
+
+        let mut buf = Vec::new();
+        Frame::SETTINGS.encode(&mut buf);
+        VarInt::try_from(tmp.len()).unwrap().encode(&mut buf);
+        buf.extend_from_slice(&tmp);
+        buf
+    }
+
+    fn decode(pairs: &[(Setting, u64)]) -> Result<Settings, SettingsError> {
+        Settings::decode_frame(&mut frame(pairs).as_slice())
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut settings = Settings::default();
+        settings.enable_webtransport(4);
+        settings.insert(Setting::MAX_FIELD_SECTION_SIZE, VarInt::from_u32(1024));
+
+        let mut buf = Vec::new();
+        settings.encode(&mut buf);
+
+        let decoded = Settings::decode(&mut buf.as_slice()).unwrap();
+        assert_eq!(*decoded, *settings);
+        assert_eq!(decoded.max_field_section_size(), Some(1024));
+        assert_eq!(decoded.supports_webtransport(), 4);
+    }
+
+    #[test]
+    fn duplicate() {
+        let res = decode(&[
+            (Setting::ENABLE_CONNECT_PROTOCOL, 1),
+            (Setting::ENABLE_CONNECT_PROTOCOL, 1),
+        ]);
+        assert!(matches!(
+            res,
+            Err(SettingsError::DuplicateSetting(
+                Setting::ENABLE_CONNECT_PROTOCOL
+            ))
+        ));
+    }
+
+    #[test]
+    fn http2() {
+        for id in [0x0, 0x2, 0x3, 0x4, 0x5] {
+            let id = Setting(VarInt::from_u32(id));
+            let res = decode(&[(id, 0)]);
+            assert!(matches!(res, Err(SettingsError::Http2Setting(s)) if s == id));
+        }
+    }
+
+    #[test]
+    fn boolean() {
+        for id in [
+            Setting::ENABLE_CONNECT_PROTOCOL,
+            Setting::ENABLE_DATAGRAM,
+            Setting::ENABLE_DATAGRAM_DEPRECATED,
+            Setting::WEBTRANSPORT_ENABLE_DEPRECATED,
+        ] {
+            assert!(decode(&[(id, 1)]).is_ok());
+            let res = decode(&[(id, 2)]);
+            assert!(matches!(res, Err(SettingsError::InvalidValue(s, _)) if s == id));
+        }
+
+        // Non-boolean settings can be any value.
+        assert!(decode(&[(Setting::WEBTRANSPORT_MAX_SESSIONS, 2)]).is_ok());
+    }
+
+    #[test]
+    fn grease() {
+        let settings = decode(&[
+            (Setting::grease(0), 7),
+            (Setting::ENABLE_CONNECT_PROTOCOL, 1),
+            (Setting::grease(1), 7),
+        ])
+        .unwrap();
+        assert_eq!(settings.len(), 1);
+        assert!(settings.connect_protocol());
+
+        let res = decode(&[(Setting::grease(2), 0), (Setting::grease(2), 1)]);
+        assert!(matches!(res, Err(SettingsError::DuplicateSetting(s)) if s == Setting::grease(2)));
+    }
+
+    #[test]
+    fn truncated() {
+        // The frame length claims one more byte than the setting needs.
+        let mut buf = Vec::new();
+        Frame::SETTINGS.encode(&mut buf);
+        VarInt::from_u32(3).encode(&mut buf);
+        Setting::ENABLE_CONNECT_PROTOCOL.encode(&mut buf);
+        VarInt::from_u32(1).encode(&mut buf);
+        Setting::QPACK_BLOCKED_STREAMS.encode(&mut buf);
+
+        let res = Settings::decode_frame(&mut buf.as_slice());
+        assert!(matches!(res, Err(SettingsError::InvalidSize)));
+    }
+}
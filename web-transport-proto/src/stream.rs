@@ -21,7 +21,13 @@ impl StreamUni {
             return false;
         }
 
-        (val - 0x21) % 0x1f == 0
+        (val - 0x21).is_multiple_of(0x1f)
+    }
+
+    // Returns the Nth reserved value, which the peer must ignore.
+    pub fn grease(n: u32) -> Self {
+        let val = 0x1f * n as u64 + 0x21;
+        Self(VarInt::from_u64(val).unwrap())
     }
 }
 
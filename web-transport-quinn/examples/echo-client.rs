@@ -1,8 +1,7 @@
-use std::{fs, io, path};
+use std::{fs, path};
 
 use anyhow::Context;
 use clap::Parser;
-use rustls::Certificate;
 use url::Url;
 
 #[derive(Parser, Debug)]
@@ -24,39 +23,18 @@ async fn main() -> anyhow::Result<()> {
 
     let args = Args::parse();
 
-    // Read the PEM certificate chain
-    let chain = fs::File::open(args.tls_cert).context("failed to open cert file")?;
-    let mut chain = io::BufReader::new(chain);
-
-    let chain: Vec<Certificate> = rustls_pemfile::certs(&mut chain)?
-        .into_iter()
-        .map(Certificate)
-        .collect();
-
-    anyhow::ensure!(!chain.is_empty(), "could not find certificate");
-
-    let mut roots = rustls::RootCertStore::empty();
-    roots.add(&chain[0])?;
-
-    // Standard quinn setup, accepting only the given certificate.
+    // Accept only the given certificate.
     // You should use system roots in production.
-    let mut tls_config = rustls::ClientConfig::builder()
-        .with_safe_defaults()
-        .with_root_certificates(roots)
-        .with_no_client_auth();
-
-    tls_config.alpn_protocols = vec![web_transport_quinn::ALPN.to_vec()]; // this one is important
-
-    let config = quinn::ClientConfig::new(std::sync::Arc::new(tls_config));
+    let chain = fs::read(args.tls_cert).context("failed to read cert file")?;
 
-    let addr = "[::]:0".parse()?;
-    let mut client = quinn::Endpoint::client(addr)?;
-    client.set_default_client_config(config);
+    let client = web_transport_quinn::Client::builder()
+        .with_root_pem(&chain)?
+        .build()?;
 
     log::info!("connecting to {}", args.url);
 
     // Connect to the given URL.
-    let session = web_transport_quinn::connect(&client, &args.url).await?;
+    let session = client.connect(&args.url).await?;
 
     log::info!("connected");
 
@@ -0,0 +1,714 @@
+use std::{fmt, str::FromStr};
+
+#[cfg(any(feature = "self-signed", feature = "certificate-hashes"))]
+use std::time::{Duration, SystemTime};
+
+#[cfg(feature = "self-signed")]
+use std::sync::{Arc, Mutex};
+
+use thiserror::Error;
+
+// Browsers only accept certificates valid for at most 14 days via `serverCertificateHashes`.
+#[cfg(feature = "certificate-hashes")]
+const MAX_CERTIFICATE_VALIDITY: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+
+// Generated certificates are valid for slightly less than the maximum, leaving room for clock skew.
+#[cfg(feature = "self-signed")]
+const CERTIFICATE_VALIDITY: Duration = Duration::from_secs(13 * 24 * 60 * 60);
+
+// Rotate at least twice per validity period, so the next certificate is valid as soon as its hash is shared.
+#[cfg(feature = "self-signed")]
+const DEFAULT_ROTATION: Duration = Duration::from_secs(6 * 24 * 60 * 60);
+
+/// An error returned when generating a self-signed certificate.
+#[cfg(feature = "self-signed")]
+#[derive(Error, Debug, Clone)]
+pub enum CertificateError {
+    #[error("failed to generate certificate: {0}")]
+    Generate(String),
+
+    #[error("unsupported private key")]
+    InvalidKey,
+
+    #[error("rotation interval is too long: {0:?}")]
+    InvalidRotation(Duration),
+}
+
+#[cfg(feature = "self-signed")]
+impl From<rcgen::RcgenError> for CertificateError {
+    fn from(err: rcgen::RcgenError) -> Self {
+        Self::Generate(err.to_string())
+    }
+}
+
+/// The SHA-256 fingerprint of a certificate, as used by the browser's `serverCertificateHashes` option.
+///
+/// Pass [`CertificateHash::ALGORITHM`] as the `algorithm` and [`CertificateHash::value`] as the `value`.
+/// The [`Display`](fmt::Display) implementation is lowercase hex, which is easy to embed in a page.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CertificateHash([u8; 32]);
+
+impl CertificateHash {
+    /// The algorithm name expected by the browser.
+    pub const ALGORITHM: &'static str = "sha-256";
+
+    /// Compute the fingerprint of a DER encoded certificate.
+    #[cfg(any(feature = "self-signed", feature = "certificate-hashes"))]
+    pub fn new(cert: &rustls::Certificate) -> Self {
+        let digest = ring::digest::digest(&ring::digest::SHA256, &cert.0);
+        Self(digest.as_ref().try_into().unwrap())
+    }
+
+    /// The raw digest bytes.
+    pub fn value(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl fmt::Display for CertificateHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl From<[u8; 32]> for CertificateHash {
+    fn from(value: [u8; 32]) -> Self {
+        Self(value)
+    }
+}
+
+/// An error returned when parsing a [`CertificateHash`] from hex.
+#[derive(Error, Debug, Clone)]
+#[error("invalid certificate hash")]
+pub struct ParseCertificateHashError;
+
+impl FromStr for CertificateHash {
+    type Err = ParseCertificateHashError;
+
+    // Parse the lowercase or uppercase hex produced by Display.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 64 || !s.is_ascii() {
+            return Err(ParseCertificateHashError);
+        }
+
+        let mut value = [0; 32];
+        for (i, byte) in value.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16)
+                .map_err(|_| ParseCertificateHashError)?;
+        }
+
+        Ok(Self(value))
+    }
+}
+
+impl fmt::Debug for CertificateHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CertificateHash({})", self)
+    }
+}
+
+/// A self-signed ECDSA P-256 certificate that browsers accept via `serverCertificateHashes`.
+/// Requires the `self-signed` feature.
+#[cfg(feature = "self-signed")]
+#[derive(Clone)]
+pub struct SelfSignedCertificate {
+    chain: Vec<rustls::Certificate>,
+    key: rustls::PrivateKey,
+    hash: CertificateHash,
+    not_before: SystemTime,
+    not_after: SystemTime,
+}
+
+#[cfg(feature = "self-signed")]
+impl SelfSignedCertificate {
+    /// Generate a certificate for the provided hostnames, valid for 13 days.
+    pub fn generate(hostnames: &[String]) -> Result<Self, CertificateError> {
+        Self::generate_at(hostnames, SystemTime::now())
+    }
+
+    // Generate a certificate that's valid starting at the provided time.
+    fn generate_at(hostnames: &[String], not_before: SystemTime) -> Result<Self, CertificateError> {
+        let not_after = not_before + CERTIFICATE_VALIDITY;
+
+        let mut params = rcgen::CertificateParams::new(hostnames.to_vec());
+        params.alg = &rcgen::PKCS_ECDSA_P256_SHA256;
+        params.not_before = not_before.into();
+        params.not_after = not_after.into();
+
+        let cert = rcgen::Certificate::from_params(params)?;
+        let chain = vec![rustls::Certificate(cert.serialize_der()?)];
+        let key = rustls::PrivateKey(cert.serialize_private_key_der());
+        let hash = CertificateHash::new(&chain[0]);
+
+        Ok(Self {
+            chain,
+            key,
+            hash,
+            not_before,
+            not_after,
+        })
+    }
+
+    /// The certificate chain, encoded as DER.
+    pub fn chain(&self) -> &[rustls::Certificate] {
+        &self.chain
+    }
+
+    /// The private key, encoded as PKCS#8 DER.
+    pub fn key(&self) -> &rustls::PrivateKey {
+        &self.key
+    }
+
+    /// The fingerprint to pass to the browser.
+    pub fn hash(&self) -> CertificateHash {
+        self.hash
+    }
+
+    /// When the certificate becomes valid.
+    pub fn not_before(&self) -> SystemTime {
+        self.not_before
+    }
+
+    /// When the certificate expires.
+    pub fn not_after(&self) -> SystemTime {
+        self.not_after
+    }
+
+    fn certified_key(&self) -> Result<Arc<rustls::sign::CertifiedKey>, CertificateError> {
+        let key =
+            rustls::sign::any_ecdsa_type(&self.key).map_err(|_| CertificateError::InvalidKey)?;
+        Ok(Arc::new(rustls::sign::CertifiedKey::new(
+            self.chain.clone(),
+            key,
+        )))
+    }
+}
+
+#[cfg(feature = "self-signed")]
+impl fmt::Debug for SelfSignedCertificate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SelfSignedCertificate")
+            .field("hash", &self.hash)
+            .field("not_after", &self.not_after)
+            .finish()
+    }
+}
+
+/// Self-signed certificates that are rotated on a schedule, without restarting the endpoint.
+///
+/// Both the current and the next certificate are generated up front, so clients can be given both hashes.
+/// Once the rotation interval elapses, the next certificate is served and a new one is generated.
+/// Rotation happens lazily during a handshake or when calling [`SelfSigned::hashes`], so no background task is needed.
+/// If intervals were missed in the meantime, any certificate that would be served stale is regenerated instead.
+///
+/// Pass this to [`ServerBuilder::with_self_signed`](crate::ServerBuilder::with_self_signed) and keep a clone to read the hashes.
+/// Requires the `self-signed` feature.
+#[cfg(feature = "self-signed")]
+#[derive(Clone)]
+pub struct SelfSigned {
+    state: Arc<Mutex<SelfSignedState>>,
+}
+
+#[cfg(feature = "self-signed")]
+struct SelfSignedState {
+    hostnames: Vec<String>,
+    rotation: Duration,
+
+    current: SelfSignedCertificate,
+    current_key: Arc<rustls::sign::CertifiedKey>,
+    next: SelfSignedCertificate,
+
+    // When the next certificate should be served.
+    rotate_at: SystemTime,
+}
+
+#[cfg(feature = "self-signed")]
+impl SelfSigned {
+    /// Generate certificates for the provided hostnames, rotated every 6 days.
+    pub fn new(hostnames: &[String]) -> Result<Self, CertificateError> {
+        Self::with_rotation(hostnames, DEFAULT_ROTATION)
+    }
+
+    /// Generate certificates for the provided hostnames, rotated at the provided interval.
+    /// The interval can be at most half of the 13 day validity, so the next certificate is always valid.
+    pub fn with_rotation(
+        hostnames: &[String],
+        rotation: Duration,
+    ) -> Result<Self, CertificateError> {
+        if rotation.is_zero() || rotation > CERTIFICATE_VALIDITY / 2 {
+            return Err(CertificateError::InvalidRotation(rotation));
+        }
+
+        let current = SelfSignedCertificate::generate(hostnames)?;
+        let next = SelfSignedCertificate::generate(hostnames)?;
+
+        let state = SelfSignedState {
+            hostnames: hostnames.to_vec(),
+            rotation,
+            current_key: current.certified_key()?,
+            current,
+            next,
+            rotate_at: SystemTime::now() + rotation,
+        };
+
+        Ok(Self {
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+
+    /// The certificate currently being served.
+    pub fn current(&self) -> SelfSignedCertificate {
+        self.rotated().current.clone()
+    }
+
+    /// The hashes of the current and next certificates, which should both be given to the browser.
+    pub fn hashes(&self) -> Vec<CertificateHash> {
+        let state = self.rotated();
+        vec![state.current.hash, state.next.hash]
+    }
+
+    /// Serve the next certificate immediately, generating a new one to follow it.
+    pub fn rotate(&self) -> Result<(), CertificateError> {
+        self.state.lock().unwrap().rotate()
+    }
+
+    // Returns the state after rotating if needed, logging any errors since the current certificate is still usable.
+    fn rotated(&self) -> std::sync::MutexGuard<'_, SelfSignedState> {
+        let mut state = self.state.lock().unwrap();
+
+        if SystemTime::now() >= state.rotate_at {
+            if let Err(err) = state.rotate() {
+                log::warn!("failed to rotate certificate: {}", err);
+            }
+        }
+
+        state
+    }
+}
+
+#[cfg(feature = "self-signed")]
+impl SelfSignedState {
+    fn rotate(&mut self) -> Result<(), CertificateError> {
+        let now = SystemTime::now();
+
+        // Rotation is lazy, so there may have been no handshakes for several intervals.
+        // The next certificate was generated at the last rotation, so it could be expired or already due to be replaced.
+        let stale = self.next.not_after <= now + self.rotation
+            || self.next.not_before + 2 * self.rotation <= now;
+
+        let current = match stale {
+            true => SelfSignedCertificate::generate(&self.hostnames)?,
+            false => self.next.clone(),
+        };
+
+        self.current_key = current.certified_key()?;
+        self.next = SelfSignedCertificate::generate(&self.hostnames)?;
+
+        // The next certificate is generated one interval before it's served, so it's served until it's two intervals old.
+        // This keeps the schedule when a rotation happens late, while a fresh certificate is served for a full interval.
+        self.rotate_at = (current.not_before + 2 * self.rotation).min(now + self.rotation);
+        self.current = current;
+
+        log::debug!("rotated certificate: hash={}", self.current.hash);
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "self-signed")]
+impl rustls::server::ResolvesServerCert for SelfSigned {
+    fn resolve(
+        &self,
+        _client_hello: rustls::server::ClientHello,
+    ) -> Option<Arc<rustls::sign::CertifiedKey>> {
+        Some(self.rotated().current_key.clone())
+    }
+}
+
+#[cfg(feature = "self-signed")]
+impl fmt::Debug for SelfSigned {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock().unwrap();
+
+        f.debug_struct("SelfSigned")
+            .field("current", &state.current)
+            .field("next", &state.next)
+            .finish()
+    }
+}
+
+/// Verifies the server's certificate by hash instead of a root certificate, like the browser's `serverCertificateHashes` option.
+///
+/// The same rules as the browser are enforced: the certificate must match one of the hashes, use an ECDSA P-256 key,
+/// be valid for at most 14 days, and be currently valid. The hostname is not checked.
+///
+/// This is installed by [`ClientBuilder::with_server_certificate_hashes`](crate::ClientBuilder::with_server_certificate_hashes),
+/// or can be used with your own [`rustls::ClientConfig`]. Requires the `certificate-hashes` feature.
+#[cfg(feature = "certificate-hashes")]
+#[derive(Clone, Debug)]
+pub struct CertificateHashVerifier {
+    hashes: Vec<CertificateHash>,
+}
+
+#[cfg(feature = "certificate-hashes")]
+impl CertificateHashVerifier {
+    /// Trust certificates matching any of the provided hashes.
+    pub fn new(hashes: Vec<CertificateHash>) -> Self {
+        Self { hashes }
+    }
+
+    // Returns why the certificate is not allowed, if it isn't.
+    fn check(&self, cert: &rustls::Certificate, now: SystemTime) -> Result<(), rustls::Error> {
+        use rustls::CertificateError::*;
+        use x509_parser::oid_registry::{OID_EC_P256, OID_KEY_TYPE_EC_PUBLIC_KEY};
+
+        let hash = CertificateHash::new(cert);
+        if !self.hashes.contains(&hash) {
+            log::debug!("unknown certificate hash: {}", hash);
+            return Err(ApplicationVerificationFailure.into());
+        }
+
+        let (_, parsed) = x509_parser::parse_x509_certificate(&cert.0).map_err(|_| BadEncoding)?;
+
+        let key = &parsed.public_key().algorithm;
+        let curve = key.parameters.as_ref().and_then(|p| p.as_oid().ok());
+        if key.algorithm != OID_KEY_TYPE_EC_PUBLIC_KEY || curve != Some(OID_EC_P256) {
+            log::debug!("certificate must use an ECDSA P-256 key");
+            return Err(ApplicationVerificationFailure.into());
+        }
+
+        let validity = parsed.validity();
+        let not_before = validity.not_before.timestamp();
+        let not_after = validity.not_after.timestamp();
+
+        let max = MAX_CERTIFICATE_VALIDITY.as_secs() as i64;
+        if not_after - not_before > max {
+            log::debug!("certificate is valid for longer than 14 days");
+            return Err(ApplicationVerificationFailure.into());
+        }
+
+        let now = match now.duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(now) => now.as_secs() as i64,
+            Err(_) => return Err(rustls::Error::FailedToGetCurrentTime),
+        };
+
+        if now < not_before {
+            return Err(NotValidYet.into());
+        }
+
+        if now > not_after {
+            return Err(Expired.into());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "certificate-hashes")]
+impl rustls::client::ServerCertVerifier for CertificateHashVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        self.check(end_entity, now)?;
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_round_trip() {
+        let mut value = [0; 32];
+        for (i, byte) in value.iter_mut().enumerate() {
+            *byte = (i * 8 + 5) as u8;
+        }
+
+        let hash = CertificateHash::from(value);
+        let hex = hash.to_string();
+        assert_eq!(hex.len(), 64);
+        assert!(hex.starts_with("050d151d"));
+
+        assert_eq!(hex.parse::<CertificateHash>().unwrap(), hash);
+        assert_eq!(hex.to_uppercase().parse::<CertificateHash>().unwrap(), hash);
+        assert_eq!(hash.value(), &value);
+    }
+
+    #[test]
+    fn hash_parse_invalid() {
+        let valid = "ab".repeat(32);
+        assert!(valid.parse::<CertificateHash>().is_ok());
+
+        // Too short, too long, not hex, and a multi-byte character that would split a pair.
+        assert!(valid[2..].parse::<CertificateHash>().is_err());
+        assert!(format!("{}ab", valid).parse::<CertificateHash>().is_err());
+        assert!(format!("zz{}", &valid[2..])
+            .parse::<CertificateHash>()
+            .is_err());
+        assert!(format!("é{}", &valid[2..])
+            .parse::<CertificateHash>()
+            .is_err());
+        assert!("".parse::<CertificateHash>().is_err());
+    }
+
+    #[cfg(any(feature = "self-signed", feature = "certificate-hashes"))]
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    #[cfg(feature = "self-signed")]
+    fn hostnames() -> Vec<String> {
+        vec!["localhost".to_string()]
+    }
+
+    #[cfg(feature = "self-signed")]
+    #[test]
+    fn rotation() {
+        let certs = SelfSigned::new(&hostnames()).unwrap();
+
+        let hashes = certs.hashes();
+        assert_eq!(hashes.len(), 2);
+        assert_ne!(hashes[0], hashes[1]);
+        assert_eq!(certs.current().hash(), hashes[0]);
+
+        // The next certificate is served, and a new one is generated to follow it.
+        certs.rotate().unwrap();
+        let rotated = certs.hashes();
+        assert_eq!(rotated[0], hashes[1]);
+        assert_eq!(certs.current().hash(), hashes[1]);
+        assert!(!hashes.contains(&rotated[1]));
+
+        // The certificate served during the handshake is the current one.
+        let current = certs.current();
+        assert_eq!(CertificateHash::new(&current.chain()[0]), rotated[0]);
+        assert!(current.not_after() > SystemTime::now() + CERTIFICATE_VALIDITY / 2);
+    }
+
+    #[cfg(feature = "self-signed")]
+    #[test]
+    fn rotation_bounds() {
+        let max = CERTIFICATE_VALIDITY / 2;
+        assert!(SelfSigned::with_rotation(&hostnames(), max).is_ok());
+        assert!(SelfSigned::with_rotation(&hostnames(), Duration::from_secs(1)).is_ok());
+
+        for rotation in [
+            Duration::ZERO,
+            max + Duration::from_secs(1),
+            CERTIFICATE_VALIDITY,
+        ] {
+            match SelfSigned::with_rotation(&hostnames(), rotation) {
+                Err(CertificateError::InvalidRotation(invalid)) => assert_eq!(invalid, rotation),
+                res => panic!("expected an invalid rotation: {:?}", res),
+            }
+        }
+    }
+
+    #[cfg(feature = "self-signed")]
+    #[test]
+    fn rotation_elapsed() {
+        let certs = SelfSigned::with_rotation(&hostnames(), DAY).unwrap();
+        let hashes = backdate(&certs, DAY + DAY / 2);
+
+        // Rotation happens lazily once the interval has elapsed, serving the next certificate.
+        assert_eq!(certs.current().hash(), hashes[1]);
+        assert_eq!(certs.hashes()[0], hashes[1]);
+
+        // The schedule is kept, so it's rotated again once the certificate is two intervals old.
+        let state = certs.state.lock().unwrap();
+        assert_eq!(state.rotate_at, state.current.not_before() + 2 * DAY);
+        assert!(state.rotate_at < SystemTime::now() + DAY);
+    }
+
+    #[cfg(feature = "self-signed")]
+    #[test]
+    fn rotation_missed_intervals() {
+        // The next certificate is already expired, or would be two intervals old by the time it's served.
+        for age in [20 * DAY, 2 * DAY + DAY / 2] {
+            let certs = SelfSigned::with_rotation(&hostnames(), DAY).unwrap();
+            let hashes = backdate(&certs, age);
+
+            // Both certificates are replaced, instead of serving a stale one for another interval.
+            let current = certs.current();
+            let rotated = certs.hashes();
+            assert!(!hashes.contains(&rotated[0]));
+            assert!(!hashes.contains(&rotated[1]));
+
+            let now = SystemTime::now();
+            assert!(current.not_before() <= now);
+            assert!(current.not_after() > now + 2 * DAY);
+
+            let state = certs.state.lock().unwrap();
+            assert!(state.next.not_after() > state.rotate_at + DAY);
+            assert!(state.rotate_at > now);
+        }
+    }
+
+    // Pretend the last rotation happened `age` ago, returning the current and next hashes.
+    #[cfg(feature = "self-signed")]
+    fn backdate(certs: &SelfSigned, age: Duration) -> Vec<CertificateHash> {
+        let mut state = certs.state.lock().unwrap();
+        let then = SystemTime::now() - age;
+
+        let current = SelfSignedCertificate::generate_at(&hostnames(), then - state.rotation);
+        let next = SelfSignedCertificate::generate_at(&hostnames(), then).unwrap();
+
+        state.current = current.unwrap();
+        state.current_key = state.current.certified_key().unwrap();
+        state.next = next;
+        state.rotate_at = then + state.rotation;
+
+        vec![state.current.hash, state.next.hash]
+    }
+
+    // A self-signed RSA certificate valid for 7 days, which rcgen can't generate:
+    // openssl req -x509 -newkey rsa:2048 -nodes -keyout /dev/null -subj /CN=localhost -days 7
+    #[cfg(feature = "certificate-hashes")]
+    const RSA_CERTIFICATE: &str = "\
+-----BEGIN CERTIFICATE-----\n\
+MIIDCTCCAfGgAwIBAgIUW47MrcgbI/ohDm4ojRKLaPCcnP8wDQYJKoZIhvcNAQEL\n\
+BQAwFDESMBAGA1UEAwwJbG9jYWxob3N0MB4XDTI2MTAxODAxMjIzNVoXDTI2MTAy\n\
+NTAxMjIzNVowFDESMBAGA1UEAwwJbG9jYWxob3N0MIIBIjANBgkqhkiG9w0BAQEF\n\
+AAOCAQ8AMIIBCgKCAQEAxd9HI1lzMcSFSXYgs/BfCDCXhFPz0ckC9fF6lZF/qXee\n\
++pX3drVIWD2Mh0tgwywr0fnpJ4XEoxrhgH+uRNs8YggAhg9uxi0iC1L6rJfAuVcr\n\
+7BZBKfJq25Y3q1VXbmGYdxqfD83fX7en0HhXYgHqwyZa22DYdo+jMmGdY9SBj+KH\n\
+plP0zY88PWTR6FeR7UEh+pyNh+rYFo0kQgoxBJIGp3UpIM6P21eGiTb9SbSWuFPV\n\
+FVBP7f8EfO7zYz77v4CO1EUhwIFLoDjmW19czUicTqjhRq4wBwoUgqSKpzDeA9Go\n\
+eUNJa2+yN6/s5XwDnTKOPT7GRJXo9BzLHqjpN+dzHQIDAQABo1MwUTAdBgNVHQ4E\n\
+FgQU2dHJyas65U20dEBBz3pW9DF+EzQwHwYDVR0jBBgwFoAU2dHJyas65U20dEBB\n\
+z3pW9DF+EzQwDwYDVR0TAQH/BAUwAwEB/zANBgkqhkiG9w0BAQsFAAOCAQEAeR0B\n\
+UhsOrLV5OcFjsQM5Xvr7JY5UYL9tWLXzkmD9BEzK0SvAsKUftk7IJ3wLRf9s2wxn\n\
+vpS5+bEoKadoyuNRBKLZj4o5wy4R7r+EE166td9bMF/6evSKFWIbsKpOwmjHoMse\n\
+toGjUB/88v33f1bdB443P6nqig47hJOHVjyPAkWsQQXI9DKlRLAF+zAqCcXkg5o3\n\
+RoE62u/Sk8SE5+rxNzWaHW9vGevUlJ1LVKEYQBvUWPoj08nz4WLtSugoKr04TDeu\n\
+XfoCnbHUOekIipIg5LFr5Az7y+bB6EndmFsUgfH5OIelbevi7Ip/lB5ZO1uHbwwa\n\
+uYd+JL9VLv2xFBLW0w==\n\
+-----END CERTIFICATE-----\n\
+";
+
+    // Generate a certificate with the provided algorithm and validity, bypassing SelfSignedCertificate's defaults.
+    #[cfg(all(feature = "self-signed", feature = "certificate-hashes"))]
+    fn generate(
+        alg: &'static rcgen::SignatureAlgorithm,
+        not_before: SystemTime,
+        not_after: SystemTime,
+    ) -> rustls::Certificate {
+        let mut params = rcgen::CertificateParams::new(hostnames());
+        params.alg = alg;
+        params.not_before = not_before.into();
+        params.not_after = not_after.into();
+
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        rustls::Certificate(cert.serialize_der().unwrap())
+    }
+
+    // Check the certificate against a verifier that trusts its hash, so only the other rules apply.
+    #[cfg(feature = "certificate-hashes")]
+    fn check(cert: &rustls::Certificate, now: SystemTime) -> Result<(), rustls::Error> {
+        CertificateHashVerifier::new(vec![CertificateHash::new(cert)]).check(cert, now)
+    }
+
+    #[cfg(feature = "certificate-hashes")]
+    fn rejected() -> rustls::Error {
+        rustls::CertificateError::ApplicationVerificationFailure.into()
+    }
+
+    #[cfg(all(feature = "self-signed", feature = "certificate-hashes"))]
+    #[test]
+    fn verify_self_signed() {
+        let cert = SelfSignedCertificate::generate(&hostnames()).unwrap();
+        let verifier = CertificateHashVerifier::new(vec![cert.hash()]);
+
+        let now = SystemTime::now();
+        assert_eq!(verifier.check(&cert.chain()[0], now), Ok(()));
+        assert_eq!(verifier.check(&cert.chain()[0], now + 12 * DAY), Ok(()));
+    }
+
+    #[cfg(all(feature = "self-signed", feature = "certificate-hashes"))]
+    #[test]
+    fn verify_hash_mismatch() {
+        let cert = SelfSignedCertificate::generate(&hostnames()).unwrap();
+        let other = SelfSignedCertificate::generate(&hostnames()).unwrap();
+
+        let verifier = CertificateHashVerifier::new(vec![other.hash()]);
+        let res = verifier.check(&cert.chain()[0], SystemTime::now());
+        assert_eq!(res, Err(rejected()));
+
+        let verifier = CertificateHashVerifier::new(Vec::new());
+        let res = verifier.check(&cert.chain()[0], SystemTime::now());
+        assert_eq!(res, Err(rejected()));
+    }
+
+    #[cfg(feature = "certificate-hashes")]
+    #[test]
+    fn verify_rsa() {
+        let der = rustls_pemfile::certs(&mut RSA_CERTIFICATE.as_bytes()).unwrap();
+        let cert = rustls::Certificate(der[0].clone());
+
+        // Use a time when the certificate is valid, so only the key is wrong.
+        let (_, parsed) = x509_parser::parse_x509_certificate(&cert.0).unwrap();
+        let not_before = parsed.validity().not_before.timestamp() as u64;
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(not_before) + DAY;
+
+        assert_eq!(check(&cert, now), Err(rejected()));
+    }
+
+    #[cfg(all(feature = "self-signed", feature = "certificate-hashes"))]
+    #[test]
+    fn verify_p384() {
+        let now = SystemTime::now();
+        let cert = generate(&rcgen::PKCS_ECDSA_P384_SHA384, now, now + DAY);
+        assert_eq!(check(&cert, now), Err(rejected()));
+
+        // The same certificate with P-256 is fine.
+        let cert = generate(&rcgen::PKCS_ECDSA_P256_SHA256, now, now + DAY);
+        assert_eq!(check(&cert, now), Ok(()));
+    }
+
+    #[cfg(all(feature = "self-signed", feature = "certificate-hashes"))]
+    #[test]
+    fn verify_validity_period() {
+        let now = SystemTime::now();
+        let alg = &rcgen::PKCS_ECDSA_P256_SHA256;
+
+        // Exactly 14 days is allowed, but any longer is not.
+        let cert = generate(alg, now - DAY, now + 13 * DAY);
+        assert_eq!(check(&cert, now), Ok(()));
+
+        let cert = generate(alg, now - DAY, now + 13 * DAY + Duration::from_secs(1));
+        assert_eq!(check(&cert, now), Err(rejected()));
+
+        let cert = generate(alg, now - DAY, now + 365 * DAY);
+        assert_eq!(check(&cert, now), Err(rejected()));
+    }
+
+    #[cfg(all(feature = "self-signed", feature = "certificate-hashes"))]
+    #[test]
+    fn verify_expired() {
+        let now = SystemTime::now();
+        let cert = generate(&rcgen::PKCS_ECDSA_P256_SHA256, now - 10 * DAY, now - DAY);
+
+        let expired = rustls::CertificateError::Expired.into();
+        assert_eq!(check(&cert, now), Err(expired));
+        assert_eq!(check(&cert, now - 2 * DAY), Ok(()));
+    }
+
+    #[cfg(all(feature = "self-signed", feature = "certificate-hashes"))]
+    #[test]
+    fn verify_not_valid_yet() {
+        let now = SystemTime::now();
+        let cert = generate(&rcgen::PKCS_ECDSA_P256_SHA256, now + DAY, now + 10 * DAY);
+
+        let not_valid_yet = rustls::CertificateError::NotValidYet.into();
+        assert_eq!(check(&cert, now), Err(not_valid_yet));
+        assert_eq!(check(&cert, now + 2 * DAY), Ok(()));
+    }
+}
@@ -1,8 +1,15 @@
+use std::{io, net::SocketAddr, sync::Arc};
+
 use thiserror::Error;
 use tokio::net::lookup_host;
 use url::Url;
 
-use crate::{Connect, ConnectError, Session, Settings, SettingsError};
+use crate::{
+    ConnectError, ConnectRequest, Connection, ConnectionOptions, Session, SettingsError, ALPN,
+};
+
+#[cfg(feature = "certificate-hashes")]
+use crate::{CertificateHash, CertificateHashVerifier};
 
 /// An error returned when connecting to a WebTransport endpoint.
 #[derive(Error, Debug, Clone)]
@@ -30,12 +37,261 @@ pub enum ClientError {
 
     #[error("invalid DNS name: {0}")]
     InvalidDnsName(String),
+
+    #[error("server is going away")]
+    GoAway,
+
+    #[error("WebTransport is not supported by the server")]
+    WebTransportUnsupported,
+
+    #[error("too many sessions: {0}")]
+    SessionLimit(u64),
+}
+
+/// An error returned when building a [`Client`].
+#[derive(Error, Debug)]
+pub enum ClientBuilderError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("tls error: {0}")]
+    Tls(#[from] rustls::Error),
+
+    #[error("no root certificates provided")]
+    MissingRoots,
+}
+
+/// Builds a [`Client`], configuring TLS and the QUIC endpoint.
+///
+/// At least one source of root certificates, or a list of certificate hashes, is required to verify the server.
+pub struct ClientBuilder {
+    addr: SocketAddr,
+    transport: Option<Arc<quinn::TransportConfig>>,
+    options: ConnectionOptions,
+    roots: rustls::RootCertStore,
+    #[cfg(feature = "certificate-hashes")]
+    hashes: Vec<CertificateHash>,
+    certificate: Option<(Vec<rustls::Certificate>, rustls::PrivateKey)>,
+}
+
+impl Default for ClientBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClientBuilder {
+    /// Create a builder that binds to `[::]:0` by default, with no root certificates.
+    pub fn new() -> Self {
+        Self {
+            addr: "[::]:0".parse().unwrap(),
+            transport: None,
+            options: ConnectionOptions::default(),
+            roots: rustls::RootCertStore::empty(),
+            #[cfg(feature = "certificate-hashes")]
+            hashes: Vec::new(),
+            certificate: None,
+        }
+    }
+
+    /// Bind the local socket to the provided address.
+    pub fn with_addr(mut self, addr: SocketAddr) -> Self {
+        self.addr = addr;
+        self
+    }
+
+    /// Use the provided QUIC transport config, otherwise Quinn's defaults are used.
+    pub fn with_transport(mut self, transport: quinn::TransportConfig) -> Self {
+        self.transport = Some(Arc::new(transport));
+        self
+    }
+
+    /// Use the provided options for each connection.
+    pub fn with_options(mut self, options: ConnectionOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Trust the root certificates provided by the platform.
+    /// Any certificates that can't be parsed are skipped. Requires the `native-roots` feature.
+    #[cfg(feature = "native-roots")]
+    pub fn with_system_roots(mut self) -> Result<Self, ClientBuilderError> {
+        let certs = rustls_native_certs::load_native_certs()?;
+        let certs: Vec<_> = certs.into_iter().map(|cert| cert.0).collect();
+
+        let (_, ignored) = self.roots.add_parsable_certificates(&certs);
+        if ignored > 0 {
+            log::debug!("ignored {} invalid system root certificates", ignored);
+        }
+
+        Ok(self)
+    }
+
+    /// Trust the root certificates bundled with the `webpki-roots` crate, which are curated by Mozilla.
+    /// Requires the `webpki-roots` feature.
+    #[cfg(feature = "webpki-roots")]
+    pub fn with_webpki_roots(mut self) -> Self {
+        let anchors = webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+            rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        });
+
+        self.roots.add_server_trust_anchors(anchors);
+        self
+    }
+
+    /// Trust the provided root certificates, encoded as DER.
+    pub fn with_root_certificates(
+        mut self,
+        certs: &[rustls::Certificate],
+    ) -> Result<Self, ClientBuilderError> {
+        for cert in certs {
+            self.roots.add(cert)?;
+        }
+
+        Ok(self)
+    }
+
+    /// Trust the provided root certificates, encoded as PEM.
+    pub fn with_root_pem(self, pem: &[u8]) -> Result<Self, ClientBuilderError> {
+        let certs: Vec<_> = rustls_pemfile::certs(&mut io::Cursor::new(pem))?
+            .into_iter()
+            .map(rustls::Certificate)
+            .collect();
+
+        self.with_root_certificates(&certs)
+    }
+
+    /// Trust only certificates with one of the provided SHA-256 hashes, like the browser's `serverCertificateHashes` option.
+    /// This replaces any root certificates, and the certificate must follow the same rules as the browser.
+    /// See [`CertificateHashVerifier`] for the details. Requires the `certificate-hashes` feature.
+    #[cfg(feature = "certificate-hashes")]
+    pub fn with_server_certificate_hashes(mut self, hashes: Vec<CertificateHash>) -> Self {
+        self.hashes = hashes;
+        self
+    }
+
+    /// Authenticate to the server with the provided certificate chain and private key, encoded as DER.
+    pub fn with_client_certificate(
+        mut self,
+        chain: Vec<rustls::Certificate>,
+        key: rustls::PrivateKey,
+    ) -> Self {
+        self.certificate = Some((chain, key));
+        self
+    }
+
+    /// Bind the QUIC endpoint.
+    /// This must be called within a Tokio runtime.
+    pub fn build(mut self) -> Result<Client, ClientBuilderError> {
+        let verifier = self.verifier()?;
+
+        let tls = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(verifier);
+
+        let mut tls = match self.certificate {
+            Some((chain, key)) => tls.with_client_auth_cert(chain, key)?,
+            None => tls.with_no_client_auth(),
+        };
+
+        tls.alpn_protocols = vec![ALPN.to_vec()]; // this one is important
+
+        let mut config = quinn::ClientConfig::new(Arc::new(tls));
+        if let Some(transport) = self.transport {
+            config.transport_config(transport);
+        }
+
+        let mut endpoint = quinn::Endpoint::client(self.addr)?;
+        endpoint.set_default_client_config(config);
+
+        Ok(Client::new(endpoint, self.options))
+    }
+
+    // Certificate hashes replace any root certificates, like the browser.
+    fn verifier(
+        &mut self,
+    ) -> Result<Arc<dyn rustls::client::ServerCertVerifier>, ClientBuilderError> {
+        #[cfg(feature = "certificate-hashes")]
+        if !self.hashes.is_empty() {
+            let hashes = std::mem::take(&mut self.hashes);
+            return Ok(Arc::new(CertificateHashVerifier::new(hashes)));
+        }
+
+        if self.roots.is_empty() {
+            return Err(ClientBuilderError::MissingRoots);
+        }
+
+        let roots = std::mem::replace(&mut self.roots, rustls::RootCertStore::empty());
+        Ok(Arc::new(rustls::client::WebPkiVerifier::new(roots, None)))
+    }
+}
+
+/// A WebTransport client, which can connect to any number of servers.
+#[derive(Clone, Debug)]
+pub struct Client {
+    endpoint: quinn::Endpoint,
+    options: ConnectionOptions,
+}
+
+impl Client {
+    /// Create a [`ClientBuilder`] to configure TLS and the QUIC endpoint.
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder::new()
+    }
+
+    /// Connect using an existing QUIC endpoint, which must be configured with the HTTP/3 [`ALPN`].
+    pub fn new(endpoint: quinn::Endpoint, options: ConnectionOptions) -> Self {
+        Self { endpoint, options }
+    }
+
+    /// Connect to a WebTransport server at the given URL, using a new QUIC connection.
+    pub async fn connect(&self, url: &Url) -> Result<Session, ClientError> {
+        self.connect_request(ConnectRequest::new(url.clone())).await
+    }
+
+    /// Connect to a WebTransport server, sending any additional headers in the CONNECT request.
+    pub async fn connect_request(&self, request: ConnectRequest) -> Result<Session, ClientError> {
+        let conn = dial(&self.endpoint, &request.url).await?;
+        let conn = Connection::client_with(conn, self.options.clone()).await?;
+        conn.connect(request).await
+    }
+
+    /// Returns the underlying QUIC endpoint.
+    pub fn endpoint(&self) -> &quinn::Endpoint {
+        &self.endpoint
+    }
+
+    /// Close the endpoint and every connection with the provided error code and reason.
+    pub fn close(&self, code: u32, reason: &[u8]) {
+        self.endpoint.close(code.into(), reason);
+    }
 }
 
 /// Connect to a WebTransport server at the given URL.
 /// The UR: must be of the form `https://host:port/path` or else the server will reject it.
 /// Returns a [`Session`] which is a wrapper over [`quinn::Connection`].
 pub async fn connect(client: &quinn::Endpoint, url: &Url) -> Result<Session, ClientError> {
+    connect_request(client, ConnectRequest::new(url.clone())).await
+}
+
+/// Connect to a WebTransport server, sending any additional headers in the CONNECT request.
+/// Set [`ConnectRequest::protocols`] to offer subprotocols, and check [`Session::protocol`] for the one selected.
+pub async fn connect_request(
+    client: &quinn::Endpoint,
+    request: ConnectRequest,
+) -> Result<Session, ClientError> {
+    let conn = dial(client, &request.url).await?;
+
+    // Connect with the connection we established.
+    connect_request_with(conn, request).await
+}
+
+// Resolve the host and establish a new QUIC connection.
+async fn dial(client: &quinn::Endpoint, url: &Url) -> Result<quinn::Connection, ClientError> {
     // TODO error on username:password in host
     let host = url
         .host()
@@ -58,24 +314,21 @@ pub async fn connect(client: &quinn::Endpoint, url: &Url) -> Result<Session, Cli
 
     // Connect to the server using the addr we just resolved.
     let conn = client.connect(remote, &host)?;
-    let conn = conn.await?;
-
-    // Connect with the connection we established.
-    connect_with(conn, url).await
+    Ok(conn.await?)
 }
 
 /// Connect using an established QUIC connection if you want to create the connection yourself.
 /// This will only work with a brand new QUIC connection using the HTTP/3 ALPN.
 pub async fn connect_with(conn: quinn::Connection, url: &Url) -> Result<Session, ClientError> {
-    // Perform the H3 handshake by sending/reciving SETTINGS frames.
-    let settings = Settings::connect(&conn).await?;
-
-    // Send the HTTP/3 CONNECT request.
-    let connect = Connect::open(&conn, url).await?;
-
-    // Return the resulting session with a reference to the control/connect streams.
-    // If either stream is closed, then the session will be closed, so we need to keep them around.
-    let session = Session::new(conn, settings, connect);
+    connect_request_with(conn, ConnectRequest::new(url.clone())).await
+}
 
-    Ok(session)
+/// Connect using an established QUIC connection, sending any additional headers in the CONNECT request.
+/// Use [`Connection::client`] instead to establish multiple sessions over the same connection.
+pub async fn connect_request_with(
+    conn: quinn::Connection,
+    request: ConnectRequest,
+) -> Result<Session, ClientError> {
+    let conn = Connection::client(conn).await?;
+    conn.connect(request).await
 }
@@ -1,10 +1,25 @@
-use std::io;
-
-use web_transport_proto::{ConnectRequest, ConnectResponse, VarInt};
+use std::{
+    future::Future,
+    io,
+    pin::pin,
+    task::{ready, Context, Poll},
+};
+
+use bytes::Bytes;
+use futures::{
+    future::{self, Either},
+    FutureExt,
+};
+use web_transport_proto::{
+    Capsule, CapsuleError, CapsuleType, ConnectRequest, ConnectResponse, DraftVersion, Frame,
+    Request, VarInt,
+};
 
 use thiserror::Error;
 use url::Url;
 
+use crate::{Connection, HttpRequest, Qpack, QpackError, SessionError, WebTransportError};
+
 #[derive(Error, Debug, Clone)]
 pub enum ConnectError {
     #[error("quic stream was closed early")]
@@ -22,47 +37,112 @@ pub enum ConnectError {
     #[error("write error")]
     WriteError(#[from] quinn::WriteError),
 
-    #[error("http error status: {0}")]
-    ErrorStatus(http::StatusCode),
+    #[error("qpack error: {0}")]
+    QpackError(#[from] QpackError),
+
+    #[error("server selected a protocol that wasn't offered: {0}")]
+    UnknownProtocol(String),
+
+    #[error("field section exceeds the limit of {0} bytes")]
+    TooLarge(usize),
+
+    #[error("http error status: {status}")]
+    ErrorStatus {
+        status: http::StatusCode,
+        headers: http::HeaderMap,
+        body: Bytes,
+    },
+}
+
+// The maximum size of the body we'll buffer when a session is rejected.
+const MAX_ERROR_BODY: usize = 64 * 1024;
+
+// The most bytes of frame type and length that precede a field section.
+const MAX_FRAME_HEADER: usize = 16;
+
+// A request received by the server, which is only a WebTransport session if it's an extended CONNECT.
+pub enum Accepted {
+    Connect(Connect),
+    Http(HttpRequest),
 }
 
 pub struct Connect {
     // The request that was sent by the client.
     request: ConnectRequest,
 
+    // The application protocol selected by the server, if any.
+    protocol: Option<String>,
+
+    // The draft negotiated for the connection, which determines the headers we send.
+    draft: DraftVersion,
+
     // A reference to the send/recv stream, so we don't close it until dropped.
     send: quinn::SendStream,
-
-    #[allow(dead_code)]
     recv: quinn::RecvStream,
+
+    // Any data received after the request/response, such as capsules.
+    buf: Vec<u8>,
 }
 
 impl Connect {
-    pub async fn accept(conn: &quinn::Connection) -> Result<Self, ConnectError> {
-        // Accept the stream that will be used to send the HTTP CONNECT request.
-        // If they try to send any other type of HTTP request, we will error out.
-        let (send, mut recv) = conn.accept_bi().await?;
-        let mut buf = Vec::new();
+    // Decode the request on a stream accepted by the server, starting with any bytes already read.
+    // The streams are borrowed so the caller can still reset them if this fails or takes too long.
+    // The encoded request is limited to max_size bytes, which is never smaller than the decoded field section.
+    pub async fn decode(
+        qpack: &Qpack,
+        stream_id: u64,
+        recv: &mut quinn::RecvStream,
+        buf: &mut Vec<u8>,
+        max_size: usize,
+    ) -> Result<Request, ConnectError> {
+        let max_buffered = max_size.saturating_add(MAX_FRAME_HEADER);
+
+        // Free the blocked stream slot if we return early or this future is dropped.
+        let _blocked = CancelBlocked { qpack, stream_id };
+        let mut fin = false;
 
         // Read the request from the client, buffering more data until we get a full response.
         loop {
-            // Read more data into the buffer.
-            // We use the chunk API here instead of read_buf literally just to return a quinn::ReadError instead of io::Error.
-            let chunk = recv.read_chunk(usize::MAX, true).await?;
-            let chunk = chunk.ok_or(ConnectError::UnexpectedEnd)?;
-            buf.extend_from_slice(&chunk.bytes); // TODO avoid copying on the first loop.
-
             // Create a cursor that will tell us how much of the buffer was read.
-            let mut limit = io::Cursor::new(&buf);
+            let mut limit = io::Cursor::new(&*buf);
 
             // Try to decode the request.
-            let request = match ConnectRequest::decode(&mut limit) {
+            let res = Request::decode_with(&mut limit, &mut qpack.decoder(), stream_id);
+            let request = match res {
                 // It worked, return it.
                 Ok(req) => req,
 
                 // We didn't have enough data in the buffer, so we'll read more and try again.
                 Err(web_transport_proto::ConnectError::UnexpectedEnd) => {
                     log::debug!("buffering CONNECT request");
+
+                    // Don't let the peer make us buffer an endless HEADERS frame.
+                    if buf.len() >= max_buffered {
+                        return Err(ConnectError::TooLarge(max_size));
+                    }
+
+                    // Read more data into the buffer, but no more than the limit.
+                    // We use the chunk API here instead of read_buf literally just to return a quinn::ReadError instead of io::Error.
+                    let chunk = recv.read_chunk(max_buffered - buf.len(), true).await?;
+                    let chunk = chunk.ok_or(ConnectError::UnexpectedEnd)?;
+                    buf.extend_from_slice(&chunk.bytes); // TODO avoid copying on the first loop.
+
+                    continue;
+                }
+
+                // The request references the dynamic table, so wait for the encoder stream.
+                // The encoder stream is read by the connection, which is what's polling us.
+                Err(web_transport_proto::ConnectError::QpackError(
+                    web_transport_proto::qpack::DecodeError::Blocked(required),
+                )) => {
+                    log::debug!("blocked CONNECT request");
+                    let unblocked = qpack.unblock(required).map(Ok);
+
+                    // Stop buffering the body at the limit, relying on flow control until we're unblocked.
+                    match buf.len() < max_buffered {
+                        true => Self::unblock(unblocked, recv, buf, &mut fin).await?,
+                        false => unblocked.await?,
+                    }
                     continue;
                 }
 
@@ -70,37 +150,85 @@ impl Connect {
                 Err(e) => return Err(e.into()),
             };
 
-            log::debug!("received CONNECT request: {:?}", request);
+            log::debug!("received request: {:?}", request);
 
-            // The request was successfully decoded, so we can send a response.
-            return Ok(Self {
-                request,
-                send,
-                recv,
-            });
+            // Keep any remaining data for later.
+            let used = limit.position() as usize;
+            buf.drain(..used);
+
+            // Acknowledge any dynamic table references.
+            qpack.flush().await?;
+
+            return Ok(request);
         }
     }
 
-    // Called by the server to send a response to the client.
-    pub async fn respond(&mut self, status: http::StatusCode) -> Result<(), quinn::WriteError> {
-        let resp = ConnectResponse { status };
+    // Wrap a decoded CONNECT request, which the server can then respond to.
+    pub fn new(
+        request: ConnectRequest,
+        draft: DraftVersion,
+        send: quinn::SendStream,
+        recv: quinn::RecvStream,
+        buf: Vec<u8>,
+    ) -> Self {
+        Self {
+            request,
+            protocol: None,
+            draft,
+            send,
+            recv,
+            buf,
+        }
+    }
 
+    // Called by the server to send a response to the client.
+    // The body is sent as a DATA frame and should only be used when rejecting the session.
+    pub async fn respond(
+        &mut self,
+        mut resp: ConnectResponse,
+        body: Bytes,
+    ) -> Result<(), quinn::WriteError> {
+        resp.draft = self.draft;
         log::debug!("sending CONNECT response: {:?}", resp);
 
+        if resp.status.is_success() {
+            self.protocol.clone_from(&resp.protocol);
+        }
+
         let mut buf = Vec::new();
         resp.encode(&mut buf);
 
+        if !body.is_empty() {
+            Frame::DATA.encode(&mut buf);
+            VarInt::try_from(body.len()).unwrap().encode(&mut buf);
+            buf.extend_from_slice(&body);
+        }
+
         self.send.write_all(&buf).await?;
 
+        // Wait until a rejection is acknowledged, otherwise dropping the connection would discard it.
+        if !resp.status.is_success() {
+            self.send.finish().await?;
+        }
+
         Ok(())
     }
 
-    pub async fn open(conn: &quinn::Connection, url: &Url) -> Result<Self, ConnectError> {
+    pub async fn open(
+        conn: &Connection,
+        draft: DraftVersion,
+        mut request: ConnectRequest,
+    ) -> Result<Self, ConnectError> {
+        let qpack = &conn.qpack;
+        request.draft = draft;
+
         // Create a new stream that will be used to send the CONNECT frame.
-        let (mut send, mut recv) = conn.open_bi().await?;
+        let (mut send, mut recv) = conn.conn.open_bi().await?;
+        let stream_id = quinn::VarInt::from(send.id()).into_inner();
 
-        // Create a new CONNECT request that we'll send using HTTP/3
-        let request = ConnectRequest { url: url.clone() };
+        // Free the blocked stream slot if we return early or this future is dropped.
+        let _blocked = CancelBlocked { qpack, stream_id };
+        let mut fin = false;
 
         log::debug!("sending CONNECT request: {:?}", request);
 
@@ -113,23 +241,34 @@ impl Connect {
 
         // Read the response from the server, buffering more data until we get a full response.
         loop {
-            // Read more data into the buffer.
-            // We use the chunk API here instead of read_buf literally just to return a quinn::ReadError instead of io::Error.
-            let chunk = recv.read_chunk(usize::MAX, true).await?;
-            let chunk = chunk.ok_or(ConnectError::UnexpectedEnd)?;
-            buf.extend_from_slice(&chunk.bytes); // TODO avoid copying on the first loop.
-
             // Create a cursor that will tell us how much of the buffer was read.
             let mut limit = io::Cursor::new(&buf);
 
             // Try to decode the response.
-            let res = match ConnectResponse::decode(&mut limit) {
+            let res = ConnectResponse::decode_with(&mut limit, &mut qpack.decoder(), stream_id);
+            let res = match res {
                 // It worked, return it.
                 Ok(res) => res,
 
                 // We didn't have enough data in the buffer, so we'll read more and try again.
                 Err(web_transport_proto::ConnectError::UnexpectedEnd) => {
                     log::debug!("buffering CONNECT response");
+
+                    // Read more data into the buffer.
+                    // We use the chunk API here instead of read_buf literally just to return a quinn::ReadError instead of io::Error.
+                    let chunk = recv.read_chunk(usize::MAX, true).await?;
+                    let chunk = chunk.ok_or(ConnectError::UnexpectedEnd)?;
+                    buf.extend_from_slice(&chunk.bytes); // TODO avoid copying on the first loop.
+
+                    continue;
+                }
+
+                // The response references the dynamic table, so wait for the encoder stream.
+                Err(web_transport_proto::ConnectError::QpackError(
+                    web_transport_proto::qpack::DecodeError::Blocked(required),
+                )) => {
+                    log::debug!("blocked CONNECT response");
+                    Self::unblock(conn.unblock(required), &mut recv, &mut buf, &mut fin).await?;
                     continue;
                 }
 
@@ -139,19 +278,124 @@ impl Connect {
 
             log::debug!("received CONNECT response: {:?}", res);
 
-            // Throw an error if we didn't get a 200 OK.
-            if res.status != http::StatusCode::OK {
-                return Err(ConnectError::ErrorStatus(res.status));
+            // Keep any remaining data for later.
+            let used = limit.position() as usize;
+            buf.drain(..used);
+
+            // Acknowledge any dynamic table references.
+            qpack.flush().await?;
+
+            // Throw an error if we didn't get a 2xx, including any body sent by the server.
+            if !res.status.is_success() {
+                let body = Self::read_body(&mut recv, buf).await?;
+
+                return Err(ConnectError::ErrorStatus {
+                    status: res.status,
+                    headers: res.headers,
+                    body,
+                });
+            }
+
+            // The server must select one of the protocols we offered, if any.
+            if let Some(protocol) = &res.protocol {
+                if !request.protocols.contains(protocol) {
+                    return Err(ConnectError::UnknownProtocol(protocol.clone()));
+                }
             }
 
             return Ok(Self {
                 request,
+                protocol: res.protocol,
+                draft,
                 send,
                 recv,
+                buf,
             });
         }
     }
 
+    // Wait until the encoder stream unblocks the header block, or more data arrives on the stream.
+    // We keep reading so a reset is noticed, instead of holding a blocked stream slot until the connection closes.
+    async fn unblock(
+        unblocked: impl Future<Output = Result<(), quinn::ConnectionError>>,
+        recv: &mut quinn::RecvStream,
+        buf: &mut Vec<u8>,
+        fin: &mut bool,
+    ) -> Result<(), ConnectError> {
+        let unblock = pin!(unblocked);
+
+        // There's nothing left to read once the stream is finished.
+        if *fin {
+            return Ok(unblock.await?);
+        }
+
+        let read = pin!(recv.read_chunk(usize::MAX, true));
+        match future::select(unblock, read).await {
+            Either::Left((res, _)) => res?,
+            Either::Right((res, _)) => match res? {
+                Some(chunk) => buf.extend_from_slice(&chunk.bytes),
+                None => *fin = true,
+            },
+        }
+
+        Ok(())
+    }
+
+    // Read any DATA frames until the server closes the stream, ignoring other frames.
+    // Only frame headers are buffered; payloads are streamed into the body until it reaches MAX_ERROR_BODY.
+    async fn read_body(
+        recv: &mut quinn::RecvStream,
+        mut buf: Vec<u8>,
+    ) -> Result<Bytes, ConnectError> {
+        let mut body = Vec::new();
+
+        'frames: while body.len() < MAX_ERROR_BODY {
+            let mut limit = io::Cursor::new(&buf);
+
+            let header =
+                Frame::decode(&mut limit).and_then(|typ| Ok((typ, VarInt::decode(&mut limit)?)));
+            let (typ, size) = match header {
+                Ok(header) => header,
+                Err(_) => {
+                    match recv.read_chunk(usize::MAX, true).await? {
+                        Some(chunk) => buf.extend_from_slice(&chunk.bytes),
+                        None => break,
+                    }
+
+                    continue;
+                }
+            };
+
+            let used = limit.position() as usize;
+            buf.drain(..used);
+
+            // Read the payload, using any buffered data first.
+            let mut remaining = size.into_inner();
+            while remaining > 0 && body.len() < MAX_ERROR_BODY {
+                let max = usize::try_from(remaining).unwrap_or(usize::MAX);
+
+                let chunk = if !buf.is_empty() {
+                    let size = buf.len().min(max);
+                    Bytes::from(buf.drain(..size).collect::<Vec<u8>>())
+                } else {
+                    match recv.read_chunk(max, true).await? {
+                        Some(chunk) => chunk.bytes,
+                        None => break 'frames,
+                    }
+                };
+
+                remaining -= chunk.len() as u64;
+
+                if typ == Frame::DATA {
+                    let size = chunk.len().min(MAX_ERROR_BODY - body.len());
+                    body.extend_from_slice(&chunk[..size]);
+                }
+            }
+        }
+
+        Ok(body.into())
+    }
+
     // The session ID is the stream ID of the CONNECT request.
     pub fn session_id(&self) -> VarInt {
         // We gotta convert from the Quinn VarInt to the (forked) WebTransport VarInt.
@@ -160,8 +404,219 @@ impl Connect {
         VarInt::try_from(stream_id.into_inner()).unwrap()
     }
 
-    // The URL in the CONNECT request.
+    // Abort the request without a response, such as when there are too many sessions.
+    pub fn reset(&mut self, code: quinn::VarInt) {
+        self.send.reset(code).ok();
+        self.recv.stop(code).ok();
+    }
+
+    // Split into the streams used to send and receive capsules once the session is established.
+    pub fn into_capsules(self) -> (CapsuleSend, CapsuleRecv) {
+        let send = CapsuleSend {
+            send: self.send,
+            buf: Vec::new(),
+        };
+        let recv = CapsuleRecv {
+            recv: self.recv,
+            buf: self.buf,
+            skip: 0,
+        };
+
+        (send, recv)
+    }
+
+    // The draft negotiated for the connection, which the session uses too.
+    pub fn draft(&self) -> DraftVersion {
+        self.draft
+    }
+
+    // The URL in the CONNECT request, including the query string.
     pub fn url(&self) -> &Url {
         &self.request.url
     }
+
+    // The application protocols offered by the client, in order of preference.
+    pub fn protocols(&self) -> &[String] {
+        &self.request.protocols
+    }
+
+    // The application protocol selected by the server, if any.
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    // The headers in the CONNECT request, excluding pseudo-headers.
+    pub fn headers(&self) -> &http::HeaderMap {
+        &self.request.headers
+    }
+}
+
+// Cancels the stream with the QPACK decoder if it's still blocked when dropped.
+// Otherwise a stream that's reset or abandoned while blocked would count against QPACK_BLOCKED_STREAMS forever.
+struct CancelBlocked<'a> {
+    qpack: &'a Qpack,
+    stream_id: u64,
+}
+
+impl Drop for CancelBlocked<'_> {
+    fn drop(&mut self) {
+        let mut decoder = self.qpack.decoder();
+        if decoder.is_blocked(self.stream_id) {
+            log::debug!("cancelling blocked stream: {}", self.stream_id);
+            decoder.cancel(self.stream_id);
+        }
+    }
+}
+
+// Writes capsules to the CONNECT stream.
+pub struct CapsuleSend {
+    send: quinn::SendStream,
+
+    // Capsules that haven't been written yet, so a partial write is never interleaved with another capsule.
+    buf: Vec<u8>,
+}
+
+impl CapsuleSend {
+    // Queue a capsule to be written by poll_flush.
+    pub fn queue(&mut self, capsule: Capsule) {
+        log::debug!("sending capsule: {:?}", capsule);
+        capsule.encode(&mut self.buf);
+    }
+
+    // Write any queued capsules, waiting for flow control if needed.
+    pub fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), quinn::WriteError>> {
+        while !self.buf.is_empty() {
+            let size = ready!(pin!(self.send.write(&self.buf)).poll(cx))?;
+            self.buf.drain(..size);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    // Write any queued capsules and FIN, resolving once the peer has acknowledged everything.
+    pub fn poll_close(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), quinn::WriteError>> {
+        ready!(self.poll_flush(cx))?;
+        pin!(self.send.finish()).poll(cx)
+    }
+
+    // Try to write any queued capsules and FIN without blocking, returning false if flow control prevents it.
+    // quinn doesn't have a synchronous write, but capsules are tiny so this rarely fails.
+    pub fn try_close(&mut self) -> bool {
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        match self.poll_flush(&mut cx) {
+            Poll::Ready(Ok(())) => {
+                self.finish();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Finish the stream without a capsule, used in response to the peer closing the session.
+    // Polling once is enough to queue the FIN.
+    pub fn finish(&mut self) {
+        let _ = self.send.finish().now_or_never();
+    }
+}
+
+// Reads capsules from the CONNECT stream.
+pub struct CapsuleRecv {
+    recv: quinn::RecvStream,
+
+    // Any data we couldn't decode yet.
+    buf: Vec<u8>,
+
+    // The remaining payload of a capsule we're skipping, such as GREASE, which is never buffered.
+    skip: u64,
+}
+
+impl CapsuleRecv {
+    // Returns the next capsule, or None if the stream was finished.
+    pub async fn read(&mut self) -> Result<Option<Capsule>, SessionError> {
+        loop {
+            if let Some(capsule) = self.decode().map_err(WebTransportError::CapsuleError)? {
+                return Ok(Some(capsule));
+            }
+
+            // Only read as much as we're skipping, so the skipped payload is discarded as it arrives.
+            let max = match self.skip {
+                0 => usize::MAX,
+                skip => skip.try_into().unwrap_or(usize::MAX),
+            };
+
+            let chunk = match self.recv.read_chunk(max, true).await {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) if self.buf.is_empty() && self.skip == 0 => return Ok(None),
+                Ok(None) => {
+                    return Err(WebTransportError::CapsuleError(CapsuleError::UnexpectedEnd).into())
+                }
+                Err(quinn::ReadError::ConnectionLost(e)) => return Err(e.into()),
+                Err(e) => {
+                    return Err(
+                        WebTransportError::ReadError(quinn::ReadExactError::ReadError(e)).into(),
+                    )
+                }
+            };
+
+            self.buf.extend_from_slice(&chunk.bytes);
+        }
+    }
+
+    // Decode the next capsule if it's fully buffered, skipping any capsules we don't support.
+    // Known capsules are capped in size, so the peer can't make us buffer an arbitrary amount.
+    fn decode(&mut self) -> Result<Option<Capsule>, CapsuleError> {
+        loop {
+            let size = self.skip.min(self.buf.len() as u64);
+            self.buf.drain(..size as usize);
+            self.skip -= size;
+
+            if self.skip > 0 {
+                return Ok(None);
+            }
+
+            let mut header = io::Cursor::new(&self.buf);
+            let typ = match CapsuleType::decode(&mut header) {
+                Ok(typ) => typ,
+                Err(CapsuleError::UnexpectedEnd) => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            let size = match VarInt::decode(&mut header) {
+                Ok(size) => size.into_inner(),
+                Err(_) => return Ok(None),
+            };
+
+            match typ.max_size() {
+                None => {
+                    log::debug!("skipping capsule: type={:?} size={}", typ, size);
+
+                    let used = header.position() as usize;
+                    self.buf.drain(..used);
+                    self.skip = size;
+
+                    continue;
+                }
+                Some(max) if size > max as u64 => {
+                    return Err(match typ {
+                        CapsuleType::CLOSE_WEBTRANSPORT_SESSION => {
+                            CapsuleError::ReasonTooLong((size - 4) as usize)
+                        }
+                        _ => CapsuleError::InvalidSize,
+                    })
+                }
+                Some(_) => {}
+            }
+
+            let mut limit = io::Cursor::new(&self.buf);
+            return match Capsule::decode(&mut limit) {
+                Ok(capsule) => {
+                    let used = limit.position() as usize;
+                    self.buf.drain(..used);
+
+                    Ok(Some(capsule))
+                }
+                Err(CapsuleError::UnexpectedEnd) => Ok(None),
+                Err(e) => Err(e),
+            };
+        }
+    }
 }
@@ -0,0 +1,429 @@
+use std::{
+    fmt,
+    future::poll_fn,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{
+    Accepted, ClientError, Connect, ConnectRequest, Demux, DraftVersion, Incoming, PeerSettings,
+    Qpack, Registration, Request, Reservation, ServerError, Session, Settings, SettingsError,
+    REQUEST_REJECTED,
+};
+
+/// Options used to establish a [`Connection`].
+#[derive(Clone, Debug)]
+pub struct ConnectionOptions {
+    /// The number of sessions allowed at once, advertised via WEBTRANSPORT_MAX_SESSIONS.
+    /// Only the server enforces this limit, but clients must still advertise a non-zero value.
+    pub max_sessions: u32,
+
+    /// The WebTransport drafts we support, of which the newest draft supported by the peer is used.
+    /// Defaults to every draft, so both older and newer browsers can connect.
+    pub drafts: Vec<DraftVersion>,
+
+    /// The initial flow control limits for each session, advertised to the peer via SETTINGS.
+    /// Session-level flow control is only used if both sides advertise limits with draft 07 or later.
+    /// Defaults to None, relying on QUIC's connection-level flow control instead.
+    pub session_limits: Option<SessionLimits>,
+
+    /// Limits how long and how many incoming streams can wait for their header.
+    /// This stops a peer from pinning memory by opening streams without ever sending anything.
+    pub stream_headers: StreamHeaderLimits,
+
+    /// Called for every incoming stream that's rejected instead of being returned by `accept_uni` or `accept_bi`.
+    /// A bad stream only affects itself, so this is the only way to observe them; defaults to None.
+    pub on_stream_rejected: Option<StreamRejectedHook>,
+
+    /// Send reserved settings, frames and stream types that the peer must ignore, disabled by default.
+    /// This catches peers that would break when HTTP/3 is extended, see RFC 9114 section 9.
+    pub grease: bool,
+
+    /// The runtime used for timers, such as [`StreamHeaderLimits::timeout`], and to finish closing sessions in the background.
+    /// Defaults to None, which uses [`quinn::default_runtime`] when the connection is established.
+    /// Timeouts are disabled if there's no runtime available, and [`Session::close`] closes the connection if the capsule can't be written immediately.
+    pub runtime: Option<Arc<dyn quinn::Runtime>>,
+}
+
+impl Default for ConnectionOptions {
+    fn default() -> Self {
+        Self {
+            max_sessions: 1,
+            drafts: DraftVersion::ALL.to_vec(),
+            session_limits: None,
+            stream_headers: StreamHeaderLimits::default(),
+            on_stream_rejected: None,
+            grease: false,
+            runtime: None,
+        }
+    }
+}
+
+/// The flow control limits for each session, increased as data is read or discarded and streams are accepted.
+/// These prevent a single session from using all of the connection's resources when sessions are pooled.
+#[derive(Clone, Copy, Debug)]
+pub struct SessionLimits {
+    /// The number of bytes the peer may send on all streams in the session before receiving more credit.
+    pub max_data: u64,
+
+    /// The number of bidirectional streams the peer may open before receiving more credit.
+    pub max_streams_bidi: u64,
+
+    /// The number of unidirectional streams the peer may open before receiving more credit.
+    pub max_streams_uni: u64,
+}
+
+/// Limits on incoming streams while we wait for the stream type and session ID.
+///
+/// Streams beyond the limit are rejected with H3_EXCESSIVE_LOAD, and streams that time out are rejected with H3_REQUEST_INCOMPLETE.
+#[derive(Clone, Copy, Debug)]
+pub struct StreamHeaderLimits {
+    /// The number of unidirectional streams that can be waiting for their header at once, defaulting to 100.
+    pub max_pending_uni: usize,
+
+    /// The number of bidirectional streams that can be waiting for their header at once, defaulting to 100.
+    pub max_pending_bi: usize,
+
+    /// How long to wait for each stream header, defaulting to 10 seconds.
+    /// None disables the timeout, as does the lack of a [`ConnectionOptions::runtime`].
+    pub timeout: Option<Duration>,
+
+    /// The largest request header we'll buffer, advertised via SETTINGS_MAX_FIELD_SECTION_SIZE and defaulting to 64 KiB.
+    /// Requests beyond the limit are rejected with H3_EXCESSIVE_LOAD.
+    pub max_field_section_size: usize,
+}
+
+impl Default for StreamHeaderLimits {
+    fn default() -> Self {
+        Self {
+            max_pending_uni: 100,
+            max_pending_bi: 100,
+            timeout: Some(Duration::from_secs(10)),
+            max_field_section_size: 64 * 1024,
+        }
+    }
+}
+
+/// Counters for incoming streams that are waiting for their header, or were dropped while waiting.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StreamHeaderStats {
+    /// The number of unidirectional streams currently waiting for their header.
+    pub pending_uni: usize,
+
+    /// The number of bidirectional streams currently waiting for their header.
+    pub pending_bi: usize,
+
+    /// The number of streams rejected because too many were already pending.
+    pub rejected: u64,
+
+    /// The number of streams rejected because the header wasn't received in time.
+    pub timed_out: u64,
+}
+
+/// An incoming stream that was stopped or reset instead of being returned to the application.
+#[derive(Clone, Debug)]
+pub struct StreamRejected {
+    /// The QUIC stream ID.
+    pub id: quinn::StreamId,
+
+    /// Why the stream was rejected.
+    pub reason: StreamRejectedReason,
+}
+
+/// Why an incoming stream was rejected, see [`StreamRejected`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StreamRejectedReason {
+    /// Too many streams were already waiting for their header, see [`StreamHeaderLimits::max_pending_uni`].
+    TooManyPending,
+
+    /// The header wasn't received before [`StreamHeaderLimits::timeout`].
+    TimedOut,
+
+    /// The stream was finished or reset by the peer before the header was received.
+    Incomplete,
+
+    /// The request couldn't be decoded, such as invalid or missing headers.
+    Malformed,
+
+    /// The request header exceeded [`StreamHeaderLimits::max_field_section_size`].
+    TooLarge,
+
+    /// The request was for a WebTransport session, but WebTransport isn't supported by both sides.
+    Unsupported,
+
+    /// The stream was for a session ID that was already closed.
+    SessionGone(u64),
+
+    /// The stream was for a session that isn't established yet, and too many streams were already buffered.
+    BufferFull(u64),
+}
+
+/// A callback for each rejected stream, see [`ConnectionOptions::on_stream_rejected`].
+///
+/// This is called while the connection is being polled, so it should return quickly and must not use the [`Connection`] or any [`Session`].
+#[derive(Clone)]
+pub struct StreamRejectedHook(Arc<dyn Fn(&StreamRejected) + Send + Sync>);
+
+impl StreamRejectedHook {
+    /// Create a hook from a closure.
+    pub fn new<F: Fn(&StreamRejected) + Send + Sync + 'static>(f: F) -> Self {
+        Self(Arc::new(f))
+    }
+
+    pub(crate) fn call(&self, event: &StreamRejected) {
+        (self.0)(event)
+    }
+}
+
+impl fmt::Debug for StreamRejectedHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StreamRejectedHook").finish_non_exhaustive()
+    }
+}
+
+/// An HTTP/3 connection that can carry multiple WebTransport sessions.
+///
+/// Streams and datagrams are routed to each [`Session`] based on the session ID.
+/// The server allows up to `max_sessions` at once via WEBTRANSPORT_MAX_SESSIONS, and the client honors the server's limit.
+/// The connection stays open until it and every session are dropped.
+#[derive(Clone)]
+pub struct Connection {
+    pub(crate) conn: quinn::Connection,
+    pub(crate) settings: Arc<Settings>,
+    pub(crate) qpack: Qpack,
+    pub(crate) demux: Arc<Mutex<Demux>>,
+    pub(crate) runtime: Option<Arc<dyn quinn::Runtime>>,
+}
+
+impl Connection {
+    /// Perform the HTTP/3 handshake as a server, allowing the client to establish up to `max_sessions` sessions at once.
+    /// Call [`accept`](Self::accept) to receive each session request.
+    pub async fn server(conn: quinn::Connection, max_sessions: u32) -> Result<Self, ServerError> {
+        let options = ConnectionOptions {
+            max_sessions,
+            ..Default::default()
+        };
+
+        Self::server_with(conn, options).await
+    }
+
+    /// Perform the HTTP/3 handshake as a server with the provided options.
+    pub async fn server_with(
+        conn: quinn::Connection,
+        options: ConnectionOptions,
+    ) -> Result<Self, ServerError> {
+        Ok(Self::new(conn, options).await?)
+    }
+
+    /// Perform the HTTP/3 handshake as a client.
+    /// Call [`connect`](Self::connect) to establish each session.
+    pub async fn client(conn: quinn::Connection) -> Result<Self, ClientError> {
+        // Clients don't receive sessions, but the setting is still required to enable WebTransport.
+        Self::client_with(conn, ConnectionOptions::default()).await
+    }
+
+    /// Perform the HTTP/3 handshake as a client with the provided options.
+    pub async fn client_with(
+        conn: quinn::Connection,
+        options: ConnectionOptions,
+    ) -> Result<Self, ClientError> {
+        Ok(Self::new(conn, options).await?)
+    }
+
+    async fn new(
+        conn: quinn::Connection,
+        mut options: ConnectionOptions,
+    ) -> Result<Self, SettingsError> {
+        if options.runtime.is_none() {
+            options.runtime = quinn::default_runtime();
+        }
+
+        // Perform the H3 handshake by sending/reciving SETTINGS frames.
+        let (mut settings, early_uni) = Settings::connect(&conn, &options).await?;
+        let qpack = Qpack::new(conn.clone(), &settings);
+
+        // Route everything else on the connection to the sessions.
+        let demux = Demux::new(
+            conn.clone(),
+            settings.server,
+            qpack.clone(),
+            settings.take_control(),
+            early_uni,
+            settings.draft,
+            &options,
+        );
+
+        Ok(Self {
+            conn,
+            settings: Arc::new(settings),
+            qpack,
+            demux: Arc::new(Mutex::new(demux)),
+            runtime: options.runtime,
+        })
+    }
+
+    /// Accept the next request from the client, either a WebTransport session or a plain HTTP/3 request.
+    /// A [`Request`] is then used to accept or reject the session based on the URL.
+    ///
+    /// Session requests beyond `max_sessions` are rejected automatically.
+    pub async fn accept(&self) -> Result<Incoming, ServerError> {
+        loop {
+            // Requests are decoded in the background, so we don't send a response yet.
+            let accepted = poll_fn(|cx| self.demux.lock().unwrap().poll_request(cx)).await?;
+            let mut connect = match accepted {
+                Accepted::Connect(connect) => connect,
+                Accepted::Http(request) => return Ok(Incoming::Http(request)),
+            };
+
+            let mut demux = self.demux.lock().unwrap();
+            if demux.sessions() as u64 >= self.max_sessions() {
+                log::debug!("rejecting session over the limit: {}", connect.session_id());
+                connect.reset(REQUEST_REJECTED);
+                demux.remove(connect.session_id());
+                continue;
+            }
+
+            let registration = Registration::new(&self.demux, &mut demux, connect.session_id());
+            drop(demux);
+
+            let request = Request::new(self.clone(), connect, registration);
+            return Ok(Incoming::Session(request));
+        }
+    }
+
+    /// Establish a new WebTransport session over this connection.
+    /// Fails if the server's session limit has been reached or the server sent a GOAWAY.
+    pub async fn connect(&self, request: ConnectRequest) -> Result<Session, ClientError> {
+        let draft = self.draft().ok_or(ClientError::WebTransportUnsupported)?;
+
+        let reservation = {
+            let mut demux = self.demux.lock().unwrap();
+
+            if demux.goaway().is_some() {
+                return Err(ClientError::GoAway);
+            }
+
+            if demux.sessions() as u64 >= self.max_sessions() {
+                return Err(ClientError::SessionLimit(self.max_sessions()));
+            }
+
+            // Hold the slot while the request is in flight, so concurrent calls can't exceed the limit.
+            Reservation::new(&self.demux, &mut demux)
+        };
+
+        // Send the HTTP/3 CONNECT request.
+        let connect = Connect::open(self, draft, request).await?;
+        let registration = reservation.register(connect.session_id());
+
+        Ok(Session::new(self.clone(), connect, registration))
+    }
+
+    /// The maximum number of sessions allowed at once, as negotiated via WEBTRANSPORT_MAX_SESSIONS.
+    pub fn max_sessions(&self) -> u64 {
+        let Some(draft) = self.settings.draft else {
+            return 0;
+        };
+
+        match self.settings.server {
+            true => self.settings.local.max_sessions(draft),
+            false => self.settings.remote.max_sessions(draft),
+        }
+    }
+
+    /// The newest WebTransport draft supported by both sides, which is used by every session.
+    ///
+    /// Returns None if the peer didn't advertise WebTransport support, in which case only plain HTTP/3 requests are allowed.
+    pub fn draft(&self) -> Option<DraftVersion> {
+        self.settings.draft
+    }
+
+    /// Returns the HTTP/3 settings sent by the peer.
+    pub fn peer_settings(&self) -> &PeerSettings {
+        &self.settings.remote
+    }
+
+    /// The number of sessions that are currently established, or being established.
+    pub fn sessions(&self) -> usize {
+        self.demux.lock().unwrap().sessions()
+    }
+
+    /// Ask the peer to gracefully close the connection, such as before restarting a server.
+    ///
+    /// This sends an HTTP/3 GOAWAY frame, after which the peer can't establish any new sessions on the connection.
+    /// The server rejects any later requests with H3_REQUEST_REJECTED so the client can retry them elsewhere.
+    /// Existing sessions are unaffected; see [`Session::drain`] to drain each of them too.
+    /// Calling this again sends another GOAWAY, but the ID never increases.
+    pub async fn drain(&self) -> Result<(), quinn::WriteError> {
+        self.settings.goaway(&self.demux).await
+    }
+
+    /// Returns counters for incoming streams that are waiting for, or were dropped while waiting for, their header.
+    pub fn stream_header_stats(&self) -> StreamHeaderStats {
+        self.demux.lock().unwrap().stream_header_stats()
+    }
+
+    // Wait until the peer's encoder stream has inserted enough entries to decode a blocked header block.
+    pub(crate) async fn unblock(&self, required: u64) -> Result<(), quinn::ConnectionError> {
+        poll_fn(|cx| self.demux.lock().unwrap().poll_unblock(cx, required)).await
+    }
+}
+
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.conn.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{test, ConnectError};
+
+    #[tokio::test]
+    async fn connect_limit() {
+        let (server, client) = test::pair().await;
+        let (server, client) =
+            tokio::join!(Connection::server(server, 1), Connection::client(client));
+        let (server, client) = (server.unwrap(), client.unwrap());
+
+        // Reject the first request, then accept any others.
+        let accept = tokio::spawn(async move {
+            let request = match server.accept().await.unwrap() {
+                Incoming::Session(request) => request,
+                Incoming::Http(_) => panic!("expected a session request"),
+            };
+            request.close(http::StatusCode::NOT_FOUND).await.unwrap();
+
+            let mut sessions = Vec::new();
+            while let Ok(Incoming::Session(request)) = server.accept().await {
+                sessions.push(request.ok().await.unwrap());
+            }
+        });
+
+        let url = url::Url::parse("https://localhost/").unwrap();
+
+        // The slot is released when the request fails.
+        let err = client.connect(ConnectRequest::new(url.clone())).await;
+        assert!(matches!(
+            err,
+            Err(ClientError::ConnectError(ConnectError::ErrorStatus { .. }))
+        ));
+        assert_eq!(client.sessions(), 0);
+
+        // Only one of the concurrent requests is sent.
+        let (a, b) = tokio::join!(
+            client.connect(ConnectRequest::new(url.clone())),
+            client.connect(ConnectRequest::new(url.clone()))
+        );
+        assert!(matches!(
+            (&a, &b),
+            (Ok(_), Err(ClientError::SessionLimit(1))) | (Err(ClientError::SessionLimit(1)), Ok(_))
+        ));
+        assert_eq!(client.sessions(), 1);
+
+        drop((a, b));
+        client.conn.close(0u32.into(), b"");
+        accept.await.unwrap();
+    }
+}
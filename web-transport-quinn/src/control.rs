@@ -0,0 +1,219 @@
+use std::io;
+
+use thiserror::Error;
+use web_transport_proto::{ControlFrame, Frame, VarInt};
+
+#[derive(Error, Debug, Clone)]
+pub enum ControlError {
+    #[error("control stream was closed")]
+    ClosedCriticalStream,
+
+    #[error("unexpected frame {0:?}")]
+    FrameUnexpected(Frame),
+
+    #[error("malformed frame: {0}")]
+    FrameError(web_transport_proto::ControlError),
+
+    #[error("invalid id: {0}")]
+    IdError(VarInt),
+
+    #[error("connection error")]
+    ConnectionError(#[from] quinn::ConnectionError),
+
+    #[error("read error")]
+    ReadError(#[from] quinn::ReadError),
+}
+
+impl ControlError {
+    // The HTTP/3 error code used to close the connection, or None if it's already closed.
+    pub fn code(&self) -> Option<quinn::VarInt> {
+        let code = match self {
+            // The connection is already gone, so there's nothing to close.
+            Self::ReadError(quinn::ReadError::ConnectionLost(_)) => return None,
+            // H3_CLOSED_CRITICAL_STREAM
+            Self::ClosedCriticalStream | Self::ReadError(_) => 0x104,
+            // H3_FRAME_UNEXPECTED
+            Self::FrameUnexpected(_) => 0x105,
+            // H3_FRAME_ERROR
+            Self::FrameError(_) => 0x106,
+            // H3_ID_ERROR
+            Self::IdError(_) => 0x108,
+            Self::ConnectionError(_) => return None,
+        };
+
+        Some(quinn::VarInt::from_u32(code))
+    }
+}
+
+// A frame received on the control stream that the session cares about.
+#[derive(Debug, Clone)]
+pub enum ControlEvent {
+    // The peer is shutting down, so no new sessions should be created.
+    GoAway(VarInt),
+
+    // The client allows the server to push up to this ID, which we never do.
+    MaxPushId(VarInt),
+}
+
+// Reads and validates the frames sent on the peer's control stream after SETTINGS.
+pub struct ControlRecv {
+    recv: quinn::RecvStream,
+
+    // Any data we couldn't decode yet.
+    buf: Vec<u8>,
+
+    // The remaining payload of a frame we're skipping, such as GREASE, which is never buffered.
+    skip: u64,
+
+    // Some frames may only be sent by the client or the server.
+    server: bool,
+
+    // The last values received, which are not allowed to change in the wrong direction.
+    goaway: Option<VarInt>,
+    max_push_id: Option<VarInt>,
+}
+
+impl ControlRecv {
+    pub fn new(recv: quinn::RecvStream, buf: Vec<u8>, server: bool) -> Self {
+        Self {
+            recv,
+            buf,
+            skip: 0,
+            server,
+            goaway: None,
+            max_push_id: None,
+        }
+    }
+
+    // Returns the next event, or an error if the peer violated the protocol.
+    // The control stream must never be closed, so there's no end.
+    pub async fn read(&mut self) -> Result<ControlEvent, ControlError> {
+        loop {
+            while let Some(frame) = self.decode()? {
+                log::debug!("received control frame: {:?}", frame);
+
+                if let Some(event) = self.validate(frame)? {
+                    return Ok(event);
+                }
+            }
+
+            // Only read as much as we're skipping, so the skipped payload is discarded as it arrives.
+            let max = match self.skip {
+                0 => usize::MAX,
+                skip => skip.try_into().unwrap_or(usize::MAX),
+            };
+
+            let chunk = self.recv.read_chunk(max, true).await?;
+            let chunk = chunk.ok_or(ControlError::ClosedCriticalStream)?;
+            self.buf.extend_from_slice(&chunk.bytes); // TODO avoid copying on the first loop.
+        }
+    }
+
+    // Decode the next frame in the buffer if there's a full one, skipping any frames we don't care about.
+    // Known frames are capped in size, so the peer can't make us buffer an arbitrary amount.
+    fn decode(&mut self) -> Result<Option<ControlFrame>, ControlError> {
+        loop {
+            let size = self.skip.min(self.buf.len() as u64);
+            self.buf.drain(..size as usize);
+            self.skip -= size;
+
+            if self.skip > 0 {
+                return Ok(None);
+            }
+
+            let mut header = io::Cursor::new(&self.buf);
+            let typ = match Frame::decode(&mut header) {
+                Ok(typ) => typ,
+                Err(_) => return Ok(None),
+            };
+            let size = match VarInt::decode(&mut header) {
+                Ok(size) => size.into_inner(),
+                Err(_) => return Ok(None),
+            };
+
+            match ControlFrame::max_size(typ).map_err(Self::proto_error)? {
+                None => {
+                    log::debug!("skipping control frame: typ={:?} size={}", typ, size);
+
+                    let used = header.position() as usize;
+                    self.buf.drain(..used);
+                    self.skip = size;
+
+                    continue;
+                }
+                Some(max) if size > max => {
+                    return Err(Self::proto_error(
+                        web_transport_proto::ControlError::InvalidSize,
+                    ))
+                }
+                Some(_) => {}
+            }
+
+            let mut limit = io::Cursor::new(&self.buf);
+            let frame = match ControlFrame::decode(&mut limit) {
+                Ok(frame) => frame,
+                Err(web_transport_proto::ControlError::UnexpectedEnd) => return Ok(None),
+                Err(e) => return Err(Self::proto_error(e)),
+            };
+
+            let used = limit.position() as usize;
+            self.buf.drain(..used);
+
+            return Ok(Some(frame));
+        }
+    }
+
+    fn proto_error(err: web_transport_proto::ControlError) -> ControlError {
+        match err {
+            web_transport_proto::ControlError::UnexpectedFrame(typ) => {
+                ControlError::FrameUnexpected(typ)
+            }
+            err => ControlError::FrameError(err),
+        }
+    }
+
+    // Check that the frame is allowed, returning an event if the session should know about it.
+    fn validate(&mut self, frame: ControlFrame) -> Result<Option<ControlEvent>, ControlError> {
+        match frame {
+            // SETTINGS may only be sent once, at the start of the stream.
+            ControlFrame::Settings => Err(ControlError::FrameUnexpected(Frame::SETTINGS)),
+            ControlFrame::GoAway(id) => {
+                // The server must send a client-initiated bidirectional stream ID.
+                if !self.server && !id.into_inner().is_multiple_of(4) {
+                    return Err(ControlError::IdError(id));
+                }
+
+                // The ID may only stay the same or decrease.
+                if self.goaway.is_some_and(|prev| id > prev) {
+                    return Err(ControlError::IdError(id));
+                }
+
+                self.goaway = Some(id);
+                Ok(Some(ControlEvent::GoAway(id)))
+            }
+            ControlFrame::MaxPushId(id) => {
+                // Only the client may send MAX_PUSH_ID.
+                if !self.server {
+                    return Err(ControlError::FrameUnexpected(Frame::MAX_PUSH_ID));
+                }
+
+                // The ID may not decrease.
+                if self.max_push_id.is_some_and(|prev| id < prev) {
+                    return Err(ControlError::IdError(id));
+                }
+
+                self.max_push_id = Some(id);
+                Ok(Some(ControlEvent::MaxPushId(id)))
+            }
+            ControlFrame::CancelPush(id) => {
+                // We never send MAX_PUSH_ID, so the server can't have pushed anything.
+                // The client can only cancel a push ID that it allowed.
+                match self.max_push_id {
+                    Some(max) if self.server && id <= max => Ok(None),
+                    _ => Err(ControlError::IdError(id)),
+                }
+            }
+            ControlFrame::Unknown(_) => Ok(None),
+        }
+    }
+}
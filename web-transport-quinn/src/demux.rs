@@ -0,0 +1,1303 @@
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    future::{poll_fn, Future},
+    io::Cursor,
+    pin::{pin, Pin},
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use futures::{
+    future::{self, Either},
+    stream::{FuturesUnordered, Stream, StreamExt},
+    task::ArcWake,
+};
+
+use crate::{
+    Accepted, Connect, ConnectError, ConnectionOptions, ControlError, ControlEvent, ControlRecv,
+    DraftVersion, HttpRequest, Qpack, QpackError, SessionError, StreamHeaderLimits,
+    StreamHeaderStats, StreamRejected, StreamRejectedHook, StreamRejectedReason, WebTransportError,
+};
+
+use web_transport_proto::{Frame, Request, StreamUni, VarInt};
+
+// The maximum number of streams and datagrams buffered for sessions that haven't been established yet.
+const MAX_BUFFERED: usize = 64;
+
+// How long a session that hasn't been established can hold on to buffered streams, once the buffer is full.
+const MAX_BUFFERED_AGE: Duration = Duration::from_secs(10);
+
+// The maximum number of datagrams buffered per session, dropping the oldest once full.
+const MAX_DATAGRAMS: usize = 1024;
+
+// The maximum number of closed sessions we remember individually, before raising the low-water mark instead.
+const MAX_GONE: usize = 1024;
+
+// H3_STREAM_CREATION_ERROR
+const STREAM_CREATION_ERROR: quinn::VarInt = quinn::VarInt::from_u32(0x103);
+
+// WEBTRANSPORT_BUFFERED_STREAM_REJECTED
+const BUFFERED_STREAM_REJECTED: quinn::VarInt = quinn::VarInt::from_u32(0x3994bd84);
+
+// WEBTRANSPORT_SESSION_GONE
+pub(crate) const SESSION_GONE: quinn::VarInt = quinn::VarInt::from_u32(0x170d7b68);
+
+// H3_EXCESSIVE_LOAD
+const EXCESSIVE_LOAD: quinn::VarInt = quinn::VarInt::from_u32(0x107);
+
+// H3_REQUEST_REJECTED
+pub(crate) const REQUEST_REJECTED: quinn::VarInt = quinn::VarInt::from_u32(0x10b);
+
+// H3_MESSAGE_ERROR
+const MESSAGE_ERROR: quinn::VarInt = quinn::VarInt::from_u32(0x10e);
+
+// H3_REQUEST_INCOMPLETE
+const REQUEST_INCOMPLETE: quinn::VarInt = quinn::VarInt::from_u32(0x10d);
+
+// Type aliases just so clippy doesn't complain about the complexity.
+type AcceptUni = dyn Stream<Item = Result<quinn::RecvStream, quinn::ConnectionError>> + Send;
+type AcceptBi = dyn Stream<Item = Result<(quinn::SendStream, quinn::RecvStream), quinn::ConnectionError>>
+    + Send;
+type AcceptDatagram = dyn Stream<Item = Result<Bytes, quinn::ConnectionError>> + Send;
+pub(crate) type PendingUni = dyn Future<Output = Result<(StreamUni, Option<StreamSession>, quinn::RecvStream), HeaderError>>
+    + Send;
+
+// Unidirectional streams accepted before the peer's control stream, see Settings::connect.
+pub(crate) type EarlyUni = FuturesUnordered<Pin<Box<PendingUni>>>;
+
+type PendingBi = dyn Future<Output = Result<PendingStream, HeaderError>> + Send;
+type PendingRequest = dyn Future<Output = Result<Accepted, HeaderError>> + Send;
+type PendingQpack = dyn Future<Output = Result<(), QpackError>> + Send;
+type ControlStream = dyn Stream<Item = Result<ControlEvent, ControlError>> + Send;
+
+// The session a WebTransport stream belongs to, read from the stream header.
+#[derive(Clone, Copy)]
+pub(crate) struct StreamSession {
+    id: VarInt,
+
+    // The size of the stream header, which isn't counted against session-level flow control.
+    header: u64,
+}
+
+// A bidirectional stream once we've read enough of it to know where it goes.
+enum PendingStream {
+    // A WebTransport stream for the given session.
+    WebTransport(StreamSession, quinn::SendStream, quinn::RecvStream),
+
+    // A new HTTP/3 request, along with the bytes we already read.
+    Request(quinn::SendStream, quinn::RecvStream, Vec<u8>),
+}
+
+// Why a stream was dropped before we read the header.
+pub(crate) enum HeaderError {
+    // The stream was already stopped or reset, and only needs to be reported.
+    Rejected(quinn::StreamId, StreamRejectedReason),
+
+    // The connection was closed, which is the only error that's fatal.
+    Connection(quinn::ConnectionError),
+}
+
+// The streams and datagrams waiting to be accepted by a session.
+#[derive(Default)]
+struct SessionQueue {
+    // Set once the session's CONNECT request was received or sent.
+    registered: bool,
+
+    // When we first buffered something for the session before it was registered.
+    buffered_at: Option<Instant>,
+
+    // Each stream is paired with the size of its header, which was already read.
+    uni: VecDeque<(quinn::RecvStream, u64)>,
+    bi: VecDeque<(quinn::SendStream, quinn::RecvStream, u64)>,
+    datagrams: VecDeque<Bytes>,
+}
+
+impl SessionQueue {
+    fn len(&self) -> usize {
+        self.uni.len() + self.bi.len() + self.datagrams.len()
+    }
+
+    // Reject anything that was queued, since the session is gone or took too long.
+    fn reject(self, code: quinn::VarInt) {
+        for (mut recv, _) in self.uni {
+            recv.stop(code).ok();
+        }
+
+        for (mut send, mut recv, _) in self.bi {
+            send.reset(code).ok();
+            recv.stop(code).ok();
+        }
+    }
+}
+
+// Wakes every waiting task, since shared state could be polled by multiple tasks at once.
+// Otherwise only the last task to poll a future would be woken.
+#[derive(Default)]
+pub(crate) struct WakerSet(Mutex<Vec<Waker>>);
+
+impl WakerSet {
+    pub fn register(&self, waker: &Waker) {
+        let mut wakers = self.0.lock().unwrap();
+        if !wakers.iter().any(|w| w.will_wake(waker)) {
+            wakers.push(waker.clone());
+        }
+    }
+}
+
+impl ArcWake for WakerSet {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        let wakers = std::mem::take(&mut *arc_self.0.lock().unwrap());
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+// Accepts every stream and datagram on the connection, routing them to sessions by session ID.
+// The server also receives new requests, which may start additional sessions.
+//
+// There's no background task, so the demultiplexer is driven by whichever session is polling it.
+pub(crate) struct Demux {
+    conn: quinn::Connection,
+    server: bool,
+
+    // The QPACK state, fed by the peer's encoder and decoder streams once accepted.
+    qpack: Qpack,
+    qpack_streams: FuturesUnordered<Pin<Box<PendingQpack>>>,
+
+    // The peer's control stream, read after SETTINGS until there's an error.
+    control_recv: Option<Pin<Box<ControlStream>>>,
+
+    accept_uni: Pin<Box<AcceptUni>>,
+    accept_bi: Pin<Box<AcceptBi>>,
+    accept_datagram: Pin<Box<AcceptDatagram>>,
+
+    // Keep track of work being done to read the stream header.
+    pending_uni: FuturesUnordered<Pin<Box<PendingUni>>>,
+    pending_bi: FuturesUnordered<Pin<Box<PendingBi>>>,
+
+    // Limits on the streams above, and counters for those dropped.
+    header_limits: StreamHeaderLimits,
+    header_stats: StreamHeaderStats,
+
+    // The timeout for each stream header, if enabled and there's a runtime to create timers.
+    timeout: Option<Timeout>,
+
+    // Called for every stream that's rejected instead of being returned to the application.
+    on_stream_rejected: Option<StreamRejectedHook>,
+
+    // Anything waiting to be accepted, indexed by session ID.
+    sessions: HashMap<VarInt, SessionQueue>,
+
+    // Sessions that we're still establishing, which count towards the session limit.
+    reserved: usize,
+
+    // The number of streams and datagrams queued for unregistered sessions.
+    buffered: usize,
+
+    // Sessions that were closed, so any new streams are rejected.
+    // Every unregistered session below the low-water mark is also considered closed, so this doesn't grow forever.
+    gone: BTreeSet<VarInt>,
+    gone_below: VarInt,
+
+    // Set once the peer opens each of its QPACK streams, since only one of each is allowed.
+    qpack_encoder: bool,
+    qpack_decoder: bool,
+
+    // Requests received by the server that are still being decoded.
+    // They're decoded in parallel, so one slow request can't hold up the others.
+    pending_requests: FuturesUnordered<Pin<Box<PendingRequest>>>,
+
+    // New requests received by the server, waiting to be accepted.
+    requests: VecDeque<Accepted>,
+
+    // The draft negotiated for the connection, or None if WebTransport isn't supported by both sides.
+    draft: Option<DraftVersion>,
+
+    // The largest request stream ID received by the server.
+    last_request: Option<VarInt>,
+
+    // Set once the peer sends a GOAWAY frame.
+    goaway: Option<VarInt>,
+
+    // The ID in the last GOAWAY frame we sent, which must never increase.
+    // The server rejects any requests at or beyond it.
+    sent_goaway: Option<VarInt>,
+
+    // Set once the connection is closed.
+    closed: Option<quinn::ConnectionError>,
+
+    // Every task waiting on the demultiplexer.
+    wakers: Arc<WakerSet>,
+}
+
+impl Demux {
+    pub fn new(
+        conn: quinn::Connection,
+        server: bool,
+        qpack: Qpack,
+        control_recv: Option<ControlRecv>,
+        early_uni: EarlyUni,
+        draft: Option<DraftVersion>,
+        options: &ConnectionOptions,
+    ) -> Self {
+        // Create a stream that just outputs new streams, so it's easy to call from poll.
+        let accept_uni = Box::pin(futures::stream::unfold(conn.clone(), |conn| async {
+            Some((conn.accept_uni().await, conn))
+        }));
+
+        let accept_bi = Box::pin(futures::stream::unfold(conn.clone(), |conn| async {
+            Some((conn.accept_bi().await, conn))
+        }));
+
+        let accept_datagram = Box::pin(futures::stream::unfold(conn.clone(), |conn| async {
+            Some((conn.read_datagram().await, conn))
+        }));
+
+        Self {
+            conn,
+            server,
+
+            qpack,
+            qpack_streams: FuturesUnordered::new(),
+
+            control_recv: control_recv.map(|control| {
+                let control = futures::stream::unfold(control, |mut control| async {
+                    Some((control.read().await, control))
+                });
+                Box::pin(control) as Pin<Box<ControlStream>>
+            }),
+
+            accept_uni,
+            accept_bi,
+            accept_datagram,
+
+            pending_uni: early_uni,
+            pending_bi: FuturesUnordered::new(),
+
+            header_limits: options.stream_headers,
+            header_stats: Default::default(),
+            timeout: Timeout::new(options),
+            on_stream_rejected: options.on_stream_rejected.clone(),
+
+            sessions: HashMap::new(),
+            reserved: 0,
+            buffered: 0,
+            gone: BTreeSet::new(),
+            gone_below: VarInt::from_u32(0),
+            qpack_encoder: false,
+            qpack_decoder: false,
+
+            pending_requests: FuturesUnordered::new(),
+            requests: VecDeque::new(),
+            draft,
+            last_request: None,
+
+            goaway: None,
+            sent_goaway: None,
+            closed: None,
+            wakers: Default::default(),
+        }
+    }
+
+    // Start routing streams and datagrams to the session.
+    pub fn register(&mut self, session_id: VarInt) {
+        let queue = self.sessions.entry(session_id).or_default();
+        if !queue.registered {
+            queue.registered = true;
+            self.buffered -= queue.len();
+        }
+    }
+
+    // Stop routing to the session, rejecting anything that wasn't accepted.
+    pub fn remove(&mut self, session_id: VarInt) {
+        if let Some(queue) = self.sessions.remove(&session_id) {
+            if !queue.registered {
+                self.buffered -= queue.len();
+            }
+
+            queue.reject(SESSION_GONE);
+        }
+
+        if session_id < self.gone_below {
+            return;
+        }
+
+        self.gone.insert(session_id);
+
+        // Forget the oldest session once we're remembering too many, raising the low-water mark instead.
+        if self.gone.len() > MAX_GONE {
+            let oldest = self.gone.pop_first().unwrap();
+            self.gone_below = VarInt::try_from(oldest.into_inner() + 1).unwrap();
+        }
+    }
+
+    // The number of sessions that are established or being established.
+    pub fn sessions(&self) -> usize {
+        let registered = self
+            .sessions
+            .values()
+            .filter(|queue| queue.registered)
+            .count();
+
+        registered + self.reserved
+    }
+
+    pub fn stream_header_stats(&self) -> StreamHeaderStats {
+        StreamHeaderStats {
+            pending_uni: self.pending_uni.len(),
+            pending_bi: self.pending_bi.len() + self.pending_requests.len(),
+            ..self.header_stats
+        }
+    }
+
+    // The GOAWAY frame we received from the peer, if any.
+    pub fn goaway(&self) -> Option<VarInt> {
+        self.goaway
+    }
+
+    // The ID to send in a GOAWAY frame, which is remembered so it never increases.
+    // The server sends the next request stream ID, while the client sends a push ID of 0 since we never allow server push.
+    pub fn goaway_id(&mut self) -> VarInt {
+        let id = match self.last_request {
+            Some(id) => VarInt::try_from(id.into_inner() + 4).unwrap(),
+            None => VarInt::from_u32(0),
+        };
+
+        let id = self.sent_goaway.map_or(id, |prev| prev.min(id));
+        self.sent_goaway = Some(id);
+        id
+    }
+
+    pub fn poll_accept_uni(
+        &mut self,
+        cx: &mut Context<'_>,
+        session_id: VarInt,
+    ) -> Poll<Result<(quinn::RecvStream, u64), quinn::ConnectionError>> {
+        self.poll(cx);
+
+        let queue = self.sessions.get_mut(&session_id);
+        match queue.and_then(|queue| queue.uni.pop_front()) {
+            Some(stream) => Poll::Ready(Ok(stream)),
+            None => self.poll_err(),
+        }
+    }
+
+    pub fn poll_accept_bi(
+        &mut self,
+        cx: &mut Context<'_>,
+        session_id: VarInt,
+    ) -> Poll<Result<(quinn::SendStream, quinn::RecvStream, u64), quinn::ConnectionError>> {
+        self.poll(cx);
+
+        let queue = self.sessions.get_mut(&session_id);
+        match queue.and_then(|queue| queue.bi.pop_front()) {
+            Some(stream) => Poll::Ready(Ok(stream)),
+            None => self.poll_err(),
+        }
+    }
+
+    pub fn poll_read_datagram(
+        &mut self,
+        cx: &mut Context<'_>,
+        session_id: VarInt,
+    ) -> Poll<Result<Bytes, quinn::ConnectionError>> {
+        self.poll(cx);
+
+        let queue = self.sessions.get_mut(&session_id);
+        match queue.and_then(|queue| queue.datagrams.pop_front()) {
+            Some(datagram) => Poll::Ready(Ok(datagram)),
+            None => self.poll_err(),
+        }
+    }
+
+    // Returns the next request received by the server, once it's been decoded.
+    pub fn poll_request(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Accepted, quinn::ConnectionError>> {
+        self.poll(cx);
+
+        match self.requests.pop_front() {
+            Some(request) => Poll::Ready(Ok(request)),
+            None => self.poll_err(),
+        }
+    }
+
+    // Returns once the peer's encoder stream has inserted enough entries to decode a blocked header block.
+    pub fn poll_unblock(
+        &mut self,
+        cx: &mut Context<'_>,
+        required: u64,
+    ) -> Poll<Result<(), quinn::ConnectionError>> {
+        self.poll(cx);
+
+        if self.qpack.decoder().table().inserted() >= required {
+            return Poll::Ready(Ok(()));
+        }
+
+        self.poll_err()
+    }
+
+    // Returns once the peer sends a GOAWAY frame or the connection is closed.
+    pub fn poll_goaway(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        self.poll(cx);
+
+        if self.goaway.is_some() || self.closed.is_some() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+
+    fn poll_err<T>(&self) -> Poll<Result<T, quinn::ConnectionError>> {
+        match &self.closed {
+            Some(err) => Poll::Ready(Err(err.clone())),
+            None => Poll::Pending,
+        }
+    }
+
+    // Make as much progress as possible, waking every waiting task when any of the underlying streams are ready.
+    fn poll(&mut self, cx: &mut Context<'_>) {
+        self.wakers.register(cx.waker());
+
+        let waker = futures::task::waker(self.wakers.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        while self.closed.is_none() && self.poll_once(&mut cx) {}
+    }
+
+    // Returns true if any progress was made.
+    fn poll_once(&mut self, cx: &mut Context<'_>) -> bool {
+        // Keep reading the QPACK streams, closing the connection on error.
+        if let Poll::Ready(Some(res)) = self.qpack_streams.poll_next_unpin(cx) {
+            if let Err(err) = res {
+                log::warn!("qpack error: {}", err);
+                self.conn.close(err.code(), err.to_string().as_bytes());
+            }
+
+            return true;
+        }
+
+        if let Some(control) = &mut self.control_recv {
+            if let Poll::Ready(res) = control.poll_next_unpin(cx) {
+                self.handle_control(res);
+                return true;
+            }
+        }
+
+        // Accept any new streams, and start decoding the header.
+        // Streams are rejected if too many are already pending, so they can't pin an unbounded amount of memory.
+        if let Poll::Ready(Some(res)) = self.accept_uni.poll_next_unpin(cx) {
+            match res {
+                Ok(mut recv) if self.pending_uni.len() >= self.header_limits.max_pending_uni => {
+                    let reason = StreamRejectedReason::TooManyPending;
+                    recv.stop(Self::reject_code(&reason)).ok();
+                    self.rejected(recv.id(), reason);
+                }
+                Ok(recv) => {
+                    let timeout = self.timeout.clone();
+                    self.pending_uni
+                        .push(Box::pin(Self::decode_uni(recv, timeout)));
+                }
+                Err(err) => self.closed = Some(err),
+            }
+
+            return true;
+        }
+
+        if let Poll::Ready(Some(res)) = self.accept_bi.poll_next_unpin(cx) {
+            match res {
+                Ok((mut send, mut recv))
+                    if self.pending_bi.len() + self.pending_requests.len()
+                        >= self.header_limits.max_pending_bi =>
+                {
+                    let reason = StreamRejectedReason::TooManyPending;
+                    send.reset(Self::reject_code(&reason)).ok();
+                    recv.stop(Self::reject_code(&reason)).ok();
+                    self.rejected(send.id(), reason);
+                }
+                Ok((send, recv)) => {
+                    let timeout = self.timeout.clone();
+                    self.pending_bi
+                        .push(Box::pin(Self::decode_bi(send, recv, timeout)));
+                }
+                Err(err) => self.closed = Some(err),
+            }
+
+            return true;
+        }
+
+        if let Poll::Ready(Some(res)) = self.pending_uni.poll_next_unpin(cx) {
+            match res {
+                Ok((typ, session_id, recv)) => self.route_uni(typ, session_id, recv),
+                Err(err) => self.stream_error(err),
+            }
+
+            return true;
+        }
+
+        if let Poll::Ready(Some(res)) = self.pending_bi.poll_next_unpin(cx) {
+            match res {
+                Ok(stream) => self.route_bi(stream),
+                Err(err) => self.stream_error(err),
+            }
+
+            return true;
+        }
+
+        if let Poll::Ready(Some(res)) = self.pending_requests.poll_next_unpin(cx) {
+            // Anything buffered for a plain HTTP/3 or rejected request can be rejected, since it'll never be a session.
+            match res {
+                Ok(request) => {
+                    if let Accepted::Http(request) = &request {
+                        self.remove(Self::session_id(request.stream_id()));
+                    }
+
+                    self.requests.push_back(request)
+                }
+                Err(err) => {
+                    if let HeaderError::Rejected(id, _) = &err {
+                        self.remove(Self::session_id(*id));
+                    }
+
+                    self.stream_error(err)
+                }
+            }
+
+            return true;
+        }
+
+        if let Poll::Ready(Some(res)) = self.accept_datagram.poll_next_unpin(cx) {
+            match res {
+                Ok(datagram) => self.route_datagram(datagram),
+                Err(err) => self.closed = Some(err),
+            }
+
+            return true;
+        }
+
+        false
+    }
+
+    // Process any events on the peer's control stream, closing the connection on error.
+    fn handle_control(&mut self, res: Option<Result<ControlEvent, ControlError>>) {
+        match res {
+            Some(Ok(ControlEvent::GoAway(id))) => {
+                log::debug!("received GOAWAY: {}", id);
+                self.goaway = Some(id);
+            }
+            Some(Ok(ControlEvent::MaxPushId(id))) => {
+                log::debug!("ignoring MAX_PUSH_ID: {}", id);
+            }
+            Some(Err(err)) => {
+                if let Some(code) = err.code() {
+                    log::warn!("control stream error: {}", err);
+                    self.conn.close(code, err.to_string().as_bytes());
+                }
+
+                self.control_recv = None;
+            }
+            None => self.control_recv = None,
+        }
+    }
+
+    // An error reading the header only affects that stream, unless the connection was closed.
+    fn stream_error(&mut self, err: HeaderError) {
+        match err {
+            HeaderError::Rejected(id, reason) => self.rejected(id, reason),
+            HeaderError::Connection(err) => self.closed = Some(err),
+        }
+    }
+
+    // Record a stream that was stopped or reset instead of being returned to the application.
+    fn rejected(&mut self, id: quinn::StreamId, reason: StreamRejectedReason) {
+        log::debug!("rejected stream: id={} reason={:?}", id, reason);
+
+        match reason {
+            StreamRejectedReason::TooManyPending => self.header_stats.rejected += 1,
+            StreamRejectedReason::TimedOut => self.header_stats.timed_out += 1,
+            _ => {}
+        }
+
+        if let Some(hook) = &self.on_stream_rejected {
+            hook.call(&StreamRejected { id, reason });
+        }
+    }
+
+    // The error code used to stop or reset a rejected stream.
+    pub fn reject_code(reason: &StreamRejectedReason) -> quinn::VarInt {
+        match reason {
+            StreamRejectedReason::TooManyPending | StreamRejectedReason::TooLarge => EXCESSIVE_LOAD,
+            StreamRejectedReason::TimedOut | StreamRejectedReason::Incomplete => REQUEST_INCOMPLETE,
+            StreamRejectedReason::Malformed => MESSAGE_ERROR,
+            StreamRejectedReason::Unsupported => REQUEST_REJECTED,
+            StreamRejectedReason::SessionGone(_) => SESSION_GONE,
+            StreamRejectedReason::BufferFull(_) => BUFFERED_STREAM_REJECTED,
+        }
+    }
+
+    fn route_uni(
+        &mut self,
+        typ: StreamUni,
+        session: Option<StreamSession>,
+        mut recv: quinn::RecvStream,
+    ) {
+        match typ {
+            StreamUni::WEBTRANSPORT => {
+                let session = session.expect("session ID is read for WebTransport streams");
+
+                match self.queue(session.id) {
+                    Ok(queue) => queue.uni.push_back((recv, session.header)),
+                    Err(reason) => {
+                        recv.stop(Self::reject_code(&reason)).ok();
+                        self.rejected(recv.id(), reason);
+                    }
+                }
+            }
+            // We already have the peer's control stream from the handshake.
+            StreamUni::CONTROL => self.duplicate_stream("control"),
+            StreamUni::QPACK_DECODER if self.qpack_decoder => {
+                self.duplicate_stream("QPACK decoder")
+            }
+            StreamUni::QPACK_ENCODER if self.qpack_encoder => {
+                self.duplicate_stream("QPACK encoder")
+            }
+            StreamUni::QPACK_DECODER => {
+                self.qpack_decoder = true;
+
+                let qpack = self.qpack.clone();
+                self.qpack_streams
+                    .push(Box::pin(async move { qpack.run_decoder(recv).await }));
+            }
+            StreamUni::QPACK_ENCODER => {
+                self.qpack_encoder = true;
+
+                let qpack = self.qpack.clone();
+                self.qpack_streams
+                    .push(Box::pin(async move { qpack.run_encoder(recv).await }));
+            }
+            _ => {
+                // ignore unknown streams
+                log::debug!("ignoring unknown unidirectional stream: {:?}", typ);
+            }
+        }
+    }
+
+    // Only one of each critical stream is allowed, so a second one is a connection error.
+    fn duplicate_stream(&mut self, name: &str) {
+        log::warn!("duplicate {} stream", name);
+
+        let reason = format!("duplicate {} stream", name);
+        self.conn.close(STREAM_CREATION_ERROR, reason.as_bytes());
+    }
+
+    fn route_bi(&mut self, stream: PendingStream) {
+        match stream {
+            PendingStream::WebTransport(session, mut send, mut recv) => {
+                match self.queue(session.id) {
+                    Ok(queue) => queue.bi.push_back((send, recv, session.header)),
+                    Err(reason) => {
+                        send.reset(Self::reject_code(&reason)).ok();
+                        recv.stop(Self::reject_code(&reason)).ok();
+                        self.rejected(send.id(), reason);
+                    }
+                }
+            }
+            PendingStream::Request(mut send, mut recv, buf) => {
+                if !self.server {
+                    // The server can't send requests, so ignore it like any other unknown stream.
+                    log::debug!("ignoring unknown bidirectional stream: {:?}", send.id());
+                    return;
+                }
+
+                let id = Self::session_id(send.id());
+
+                // The client may retry any request at or beyond our GOAWAY on a new connection.
+                if self.sent_goaway.is_some_and(|goaway| id >= goaway) {
+                    log::debug!("rejecting request after GOAWAY: {}", id);
+                    send.reset(REQUEST_REJECTED).ok();
+                    recv.stop(REQUEST_REJECTED).ok();
+                    return;
+                }
+
+                self.last_request = self.last_request.max(Some(id));
+
+                let request = Self::decode_request(
+                    self.conn.clone(),
+                    self.qpack.clone(),
+                    self.draft,
+                    (send, recv, buf),
+                    self.header_limits.max_field_section_size,
+                    self.timeout.clone(),
+                );
+                self.pending_requests.push(Box::pin(request));
+            }
+        }
+    }
+
+    fn route_datagram(&mut self, mut datagram: Bytes) {
+        let mut cursor = Cursor::new(&datagram);
+
+        // Datagrams start with the quarter stream ID, which is the session ID divided by 4.
+        let session_id = match VarInt::decode(&mut cursor) {
+            Ok(id) => id
+                .into_inner()
+                .checked_mul(4)
+                .and_then(|id| VarInt::try_from(id).ok()),
+            Err(_) => None,
+        };
+
+        let session_id = match session_id {
+            Some(id) => id,
+            None => {
+                log::debug!("ignoring invalid datagram");
+                return;
+            }
+        };
+
+        // Return the datagram without the session ID.
+        let datagram = datagram.split_off(cursor.position() as usize);
+
+        match self.queue(session_id) {
+            Ok(queue) => {
+                if queue.datagrams.len() >= MAX_DATAGRAMS {
+                    queue.datagrams.pop_front();
+                }
+
+                queue.datagrams.push_back(datagram);
+            }
+            Err(_) => log::debug!("dropping datagram for session: {}", session_id),
+        }
+    }
+
+    // Returns the queue for the session, or the reason to reject the stream.
+    // Streams may arrive before their session is established, so we buffer a limited number of them.
+    fn queue(&mut self, session_id: VarInt) -> Result<&mut SessionQueue, StreamRejectedReason> {
+        let below = session_id < self.gone_below && !self.sessions.contains_key(&session_id);
+        if below || self.gone.contains(&session_id) {
+            return Err(StreamRejectedReason::SessionGone(session_id.into_inner()));
+        }
+
+        let registered = self
+            .sessions
+            .get(&session_id)
+            .is_some_and(|queue| queue.registered);
+
+        if !registered {
+            if self.buffered >= MAX_BUFFERED {
+                self.evict_buffered();
+            }
+
+            if self.buffered >= MAX_BUFFERED {
+                return Err(StreamRejectedReason::BufferFull(session_id.into_inner()));
+            }
+
+            self.buffered += 1;
+        }
+
+        let queue = self.sessions.entry(session_id).or_default();
+        if !queue.registered {
+            queue.buffered_at.get_or_insert_with(Instant::now);
+        }
+
+        Ok(queue)
+    }
+
+    // Reject anything buffered for sessions that weren't established within MAX_BUFFERED_AGE.
+    // Otherwise a peer could use up the buffer forever with a session ID that will never be registered.
+    fn evict_buffered(&mut self) {
+        let now = Instant::now();
+
+        let expired: Vec<VarInt> = self
+            .sessions
+            .iter()
+            .filter(|(_, queue)| !queue.registered)
+            .filter(|(_, queue)| {
+                queue
+                    .buffered_at
+                    .is_some_and(|at| now - at >= MAX_BUFFERED_AGE)
+            })
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in expired {
+            log::debug!("evicting buffered streams for session: {}", id);
+
+            let queue = self.sessions.remove(&id).unwrap();
+            self.buffered -= queue.len();
+            queue.reject(BUFFERED_STREAM_REJECTED);
+        }
+    }
+
+    // Session IDs are the ID of the request stream.
+    fn session_id(id: quinn::StreamId) -> VarInt {
+        VarInt::try_from(quinn::VarInt::from(id).into_inner()).unwrap()
+    }
+
+    // Reads the stream header, returning the stream type and the session ID for WebTransport streams.
+    // The stream is rejected if the header isn't received before the timeout.
+    pub async fn decode_uni(
+        mut recv: quinn::RecvStream,
+        timeout: Option<Timeout>,
+    ) -> Result<(StreamUni, Option<StreamSession>, quinn::RecvStream), HeaderError> {
+        let header = Timeout::run(timeout, Self::read_uni_header(&mut recv)).await;
+        let (typ, session_id) = match Self::check_header(recv.id(), header) {
+            Ok(header) => header,
+            Err(err) => {
+                if let HeaderError::Rejected(_, reason) = &err {
+                    recv.stop(Self::reject_code(reason)).ok();
+                }
+
+                return Err(err);
+            }
+        };
+
+        // We need the qpack streams to decode headers, so return everything.
+        Ok((typ, session_id, recv))
+    }
+
+    async fn read_uni_header(
+        recv: &mut quinn::RecvStream,
+    ) -> Result<(StreamUni, Option<StreamSession>), SessionError> {
+        // Read the VarInt at the start of the stream.
+        let (typ, size) = Self::read_varint(recv).await?;
+        let typ = StreamUni(typ);
+
+        let session = match typ {
+            StreamUni::WEBTRANSPORT => Some(Self::read_session(recv, size).await?),
+            _ => None,
+        };
+
+        Ok((typ, session))
+    }
+
+    // Reads the stream header, returning whether it's a WebTransport stream or a new request.
+    // The stream is rejected if the header isn't received before the timeout.
+    async fn decode_bi(
+        mut send: quinn::SendStream,
+        mut recv: quinn::RecvStream,
+        timeout: Option<Timeout>,
+    ) -> Result<PendingStream, HeaderError> {
+        let header = Timeout::run(timeout, Self::read_bi_header(&mut recv)).await;
+        let (typ, session) = match Self::check_header(send.id(), header) {
+            Ok(header) => header,
+            Err(err) => {
+                if let HeaderError::Rejected(_, reason) = &err {
+                    send.reset(Self::reject_code(reason)).ok();
+                    recv.stop(Self::reject_code(reason)).ok();
+                }
+
+                return Err(err);
+            }
+        };
+
+        if let Some(session) = session {
+            return Ok(PendingStream::WebTransport(session, send, recv));
+        }
+
+        // Anything else is the start of a request, so keep the frame type we already read.
+        let mut buf = Vec::new();
+        typ.encode(&mut buf);
+
+        Ok(PendingStream::Request(send, recv, buf))
+    }
+
+    // Decodes a request received by the server, rejecting it if it isn't received before the timeout.
+    // Only QPACK errors affect the connection, since the dynamic table is shared by every request.
+    async fn decode_request(
+        conn: quinn::Connection,
+        qpack: Qpack,
+        draft: Option<DraftVersion>,
+        (mut send, mut recv, mut buf): (quinn::SendStream, quinn::RecvStream, Vec<u8>),
+        max_size: usize,
+        timeout: Option<Timeout>,
+    ) -> Result<Accepted, HeaderError> {
+        let id = send.id();
+        let stream_id = quinn::VarInt::from(id).into_inner();
+
+        let decode = Connect::decode(&qpack, stream_id, &mut recv, &mut buf, max_size);
+        let reason = match Timeout::run(timeout, decode).await {
+            Some(Ok(Request::Http(request))) => {
+                return Ok(Accepted::Http(HttpRequest::new(request, send, recv, buf)))
+            }
+            Some(Ok(Request::Connect(request))) => match draft {
+                Some(draft) => {
+                    let connect = Connect::new(request, draft, send, recv, buf);
+                    return Ok(Accepted::Connect(connect));
+                }
+                None => StreamRejectedReason::Unsupported,
+            },
+            Some(Err(err)) => Self::request_error(&conn, err)?,
+            None => StreamRejectedReason::TimedOut,
+        };
+
+        send.reset(Self::reject_code(&reason)).ok();
+        recv.stop(Self::reject_code(&reason)).ok();
+
+        Err(HeaderError::Rejected(id, reason))
+    }
+
+    // Returns why a request that failed to decode is rejected, or closes the connection if it's a QPACK error.
+    fn request_error(
+        conn: &quinn::Connection,
+        err: ConnectError,
+    ) -> Result<StreamRejectedReason, HeaderError> {
+        log::debug!("failed to decode request: {}", err);
+
+        match err {
+            ConnectError::ConnectionError(err)
+            | ConnectError::ReadError(quinn::ReadError::ConnectionLost(err))
+            | ConnectError::WriteError(quinn::WriteError::ConnectionLost(err))
+            | ConnectError::QpackError(QpackError::ConnectionError(err)) => {
+                Err(HeaderError::Connection(err))
+            }
+            ConnectError::ProtoError(web_transport_proto::ConnectError::QpackError(err)) => {
+                // QPACK_DECOMPRESSION_FAILED
+                conn.close(quinn::VarInt::from_u32(0x200), err.to_string().as_bytes());
+                Err(HeaderError::Connection(
+                    quinn::ConnectionError::LocallyClosed,
+                ))
+            }
+            ConnectError::QpackError(err) => {
+                conn.close(err.code(), err.to_string().as_bytes());
+                Err(HeaderError::Connection(
+                    quinn::ConnectionError::LocallyClosed,
+                ))
+            }
+            ConnectError::UnexpectedEnd | ConnectError::ReadError(_) => {
+                Ok(StreamRejectedReason::Incomplete)
+            }
+            ConnectError::TooLarge(_) => Ok(StreamRejectedReason::TooLarge),
+            _ => Ok(StreamRejectedReason::Malformed),
+        }
+    }
+
+    // Returns the frame type, and the session ID for WebTransport streams.
+    async fn read_bi_header(
+        recv: &mut quinn::RecvStream,
+    ) -> Result<(VarInt, Option<StreamSession>), SessionError> {
+        let (typ, size) = Self::read_varint(recv).await?;
+
+        let session = match Frame(typ) == Frame::WEBTRANSPORT {
+            true => Some(Self::read_session(recv, size).await?),
+            false => None,
+        };
+
+        Ok((typ, session))
+    }
+
+    // Reads the session ID after the stream type, which was size bytes.
+    async fn read_session(
+        recv: &mut quinn::RecvStream,
+        size: u64,
+    ) -> Result<StreamSession, SessionError> {
+        let (id, header) = Self::read_varint(recv).await?;
+
+        Ok(StreamSession {
+            id,
+            header: size + header,
+        })
+    }
+
+    // Returns the stream header, or why the stream should be rejected if it couldn't be read in time.
+    // Only connection errors are fatal; the peer may have just reset the stream before sending the header.
+    fn check_header<T>(
+        id: quinn::StreamId,
+        header: Option<Result<T, SessionError>>,
+    ) -> Result<T, HeaderError> {
+        match header {
+            Some(Ok(header)) => Ok(header),
+            Some(Err(SessionError::ConnectionError(err))) => Err(HeaderError::Connection(err)),
+            Some(Err(err)) => {
+                log::debug!("failed to read stream header: {}", err);
+                Err(HeaderError::Rejected(id, StreamRejectedReason::Incomplete))
+            }
+            None => Err(HeaderError::Rejected(id, StreamRejectedReason::TimedOut)),
+        }
+    }
+
+    // Read into the provided buffer and cast any errors to SessionError.
+    async fn read_full(recv: &mut quinn::RecvStream, buf: &mut [u8]) -> Result<(), SessionError> {
+        match recv.read_exact(buf).await {
+            Ok(()) => Ok(()),
+            Err(quinn::ReadExactError::ReadError(quinn::ReadError::ConnectionLost(err))) => {
+                Err(err.into())
+            }
+            Err(err) => Err(WebTransportError::ReadError(err).into()),
+        }
+    }
+
+    // Read a varint from the stream.
+    // Also returns the encoded size, since the peer doesn't have to use the shortest encoding.
+    async fn read_varint(recv: &mut quinn::RecvStream) -> Result<(VarInt, u64), SessionError> {
+        // 8 bytes is the max size of a varint
+        let mut buf = [0; 8];
+
+        // Read the first byte because it includes the length.
+        Self::read_full(recv, &mut buf[0..1]).await?;
+
+        // 0b00 = 1, 0b01 = 2, 0b10 = 4, 0b11 = 8
+        let size = 1 << (buf[0] >> 6);
+        Self::read_full(recv, &mut buf[1..size]).await?;
+
+        // Use a cursor to read the varint on the stack.
+        let mut cursor = Cursor::new(&buf[..size]);
+        let v = VarInt::decode(&mut cursor).unwrap();
+
+        Ok((v, size as u64))
+    }
+}
+
+// Limits how long we wait for something, using the runtime's timers so we're not tied to Tokio.
+#[derive(Clone)]
+pub(crate) struct Timeout {
+    runtime: Arc<dyn quinn::Runtime>,
+    duration: Duration,
+}
+
+impl Timeout {
+    // Returns the stream header timeout, or None if it's disabled or there's no runtime.
+    pub fn new(options: &ConnectionOptions) -> Option<Self> {
+        let duration = options.stream_headers.timeout?;
+
+        match options.runtime.clone() {
+            Some(runtime) => Some(Self { runtime, duration }),
+            None => {
+                log::warn!("no runtime available, stream header timeout is disabled");
+                None
+            }
+        }
+    }
+
+    // Returns None if the future doesn't complete before the timeout, if any.
+    pub async fn run<F: Future>(timeout: Option<Self>, f: F) -> Option<F::Output> {
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => return Some(f.await),
+        };
+
+        let mut timer = timeout.runtime.new_timer(Instant::now() + timeout.duration);
+        let expired = poll_fn(|cx| timer.as_mut().poll(cx));
+
+        match future::select(pin!(f), pin!(expired)).await {
+            Either::Left((res, _)) => Some(res),
+            Either::Right(_) => None,
+        }
+    }
+}
+
+// Holds a slot under the session limit while the CONNECT request is in flight, released if dropped.
+pub(crate) struct Reservation {
+    demux: Arc<Mutex<Demux>>,
+
+    // Set once the slot was handed to a registration instead.
+    registered: bool,
+}
+
+impl Reservation {
+    // Takes the locked demultiplexer too, so the caller can check the session limit first.
+    pub fn new(demux: &Arc<Mutex<Demux>>, locked: &mut Demux) -> Self {
+        locked.reserved += 1;
+
+        Self {
+            demux: demux.clone(),
+            registered: false,
+        }
+    }
+
+    // Register the session once its ID is known, atomically moving it from the reserved slot.
+    pub fn register(mut self, session_id: VarInt) -> Registration {
+        let demux = self.demux.clone();
+        let mut locked = demux.lock().unwrap();
+
+        locked.reserved -= 1;
+        self.registered = true;
+
+        Registration::new(&demux, &mut locked, session_id)
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if !self.registered {
+            self.demux.lock().unwrap().reserved -= 1;
+        }
+    }
+}
+
+// Routes streams to a session until dropped, at which point any new streams are rejected.
+pub(crate) struct Registration {
+    demux: Arc<Mutex<Demux>>,
+    session_id: VarInt,
+}
+
+impl Registration {
+    // Takes the locked demultiplexer too, so the caller can check the session limit first.
+    pub fn new(demux: &Arc<Mutex<Demux>>, locked: &mut Demux, session_id: VarInt) -> Self {
+        locked.register(session_id);
+
+        Self {
+            demux: demux.clone(),
+            session_id,
+        }
+    }
+
+    pub fn session_id(&self) -> VarInt {
+        self.session_id
+    }
+
+    pub fn demux(&self) -> &Arc<Mutex<Demux>> {
+        &self.demux
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.demux.lock().unwrap().remove(self.session_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{test, Connection, Incoming};
+
+    // Opens a WebTransport stream for the session, which the server has to buffer until the session is established.
+    async fn open_uni(conn: &Connection, session_id: u32) -> quinn::SendStream {
+        let mut send = conn.conn.open_uni().await.unwrap();
+
+        let mut buf = Vec::new();
+        StreamUni::WEBTRANSPORT.encode(&mut buf);
+        VarInt::from_u32(session_id).encode(&mut buf);
+        send.write_all(&buf).await.unwrap();
+
+        send
+    }
+
+    // Waits until the server has buffered the expected number of streams.
+    async fn buffered(demux: &Mutex<Demux>, expected: usize) {
+        while demux.lock().unwrap().buffered != expected {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    // Returns the server's demultiplexer and a connected client, with the server accepting requests in the background.
+    async fn serve() -> (Arc<Mutex<Demux>>, Connection) {
+        let (server, client) = test::pair().await;
+        let (server, client) =
+            tokio::join!(Connection::server(server, 1), Connection::client(client));
+        let (server, client) = (server.unwrap(), client.unwrap());
+
+        // Streams are only routed while the connection is polled, so keep accepting and dropping requests.
+        let demux = server.demux.clone();
+        tokio::spawn(async move { while server.accept().await.is_ok() {} });
+
+        (demux, client)
+    }
+
+    #[tokio::test]
+    async fn buffered_evicted() {
+        let (demux, client) = serve().await;
+
+        // Fill the buffer with streams for a session that's never established.
+        let mut streams = Vec::new();
+        for _ in 0..MAX_BUFFERED {
+            streams.push(open_uni(&client, 400).await);
+        }
+        buffered(&demux, MAX_BUFFERED).await;
+
+        // Streams for other sessions are rejected while the buffer is full.
+        let mut rejected = open_uni(&client, 404).await;
+        assert_eq!(rejected.stopped().await, Ok(BUFFERED_STREAM_REJECTED));
+
+        // Until the buffered streams are old enough to be evicted.
+        let expired = Instant::now().checked_sub(MAX_BUFFERED_AGE).unwrap();
+        let session_id = VarInt::from_u32(400);
+        demux
+            .lock()
+            .unwrap()
+            .sessions
+            .get_mut(&session_id)
+            .unwrap()
+            .buffered_at = Some(expired);
+
+        let _accepted = open_uni(&client, 404).await;
+        assert_eq!(streams[0].stopped().await, Ok(BUFFERED_STREAM_REJECTED));
+        buffered(&demux, 1).await;
+    }
+
+    #[tokio::test]
+    async fn buffered_http_request() {
+        let (demux, client) = serve().await;
+
+        let mut stream = open_uni(&client, 0).await;
+        buffered(&demux, 1).await;
+
+        // The session ID turns out to be a plain HTTP/3 request, so it'll never be established.
+        let (mut send, _recv) = client.conn.open_bi().await.unwrap();
+        assert_eq!(quinn::VarInt::from(send.id()).into_inner(), 0);
+        send.write_all(&test::request("/")).await.unwrap();
+
+        assert_eq!(stream.stopped().await, Ok(SESSION_GONE));
+        buffered(&demux, 0).await;
+    }
+
+    #[tokio::test]
+    async fn request_too_large() {
+        let (server, client) = test::pair().await;
+
+        let rejected = Arc::new(Mutex::new(Vec::new()));
+        let hook = rejected.clone();
+
+        let mut options = ConnectionOptions::default();
+        options.stream_headers.max_field_section_size = 1024;
+        options.on_stream_rejected = Some(StreamRejectedHook::new(move |event| {
+            hook.lock().unwrap().push(event.reason.clone())
+        }));
+
+        let (server, client) = tokio::join!(
+            Connection::server_with(server, options),
+            Connection::client(client)
+        );
+        let (server, client) = (server.unwrap(), client.unwrap());
+
+        assert_eq!(client.peer_settings().max_field_section_size(), Some(1024));
+
+        let accept = tokio::spawn(async move { server.accept().await });
+
+        // Send a HEADERS frame that never ends.
+        let (mut send, mut recv) = client.conn.open_bi().await.unwrap();
+        let mut buf = Vec::new();
+        Frame::HEADERS.encode(&mut buf);
+        VarInt::from_u32(1 << 30).encode(&mut buf);
+        send.write_all(&buf).await.unwrap();
+
+        let junk = vec![0; 4096];
+        let err = loop {
+            if let Err(err) = send.write_all(&junk).await {
+                break err;
+            }
+        };
+
+        assert_eq!(err, quinn::WriteError::Stopped(EXCESSIVE_LOAD));
+        assert!(matches!(
+            recv.read_chunk(usize::MAX, true).await,
+            Err(quinn::ReadError::Reset(EXCESSIVE_LOAD))
+        ));
+        assert_eq!(*rejected.lock().unwrap(), [StreamRejectedReason::TooLarge]);
+
+        // Smaller requests are still accepted.
+        let (mut send, _recv) = client.conn.open_bi().await.unwrap();
+        send.write_all(&test::request("/small")).await.unwrap();
+        send.finish().await.unwrap();
+
+        match accept.await.unwrap().unwrap() {
+            Incoming::Http(request) => assert_eq!(request.uri().path(), "/small"),
+            Incoming::Session(_) => panic!("expected an HTTP request"),
+        }
+    }
+}
@@ -11,6 +11,10 @@ pub enum SessionError {
 
     #[error("send datagram error: {0}")]
     SendDatagramError(#[from] quinn::SendDatagramError),
+
+    /// The peer closed the session with an application error code and reason.
+    #[error("session closed: code={code} reason={reason}")]
+    Closed { code: u32, reason: String },
 }
 
 /// An error that can occur when reading/writing the WebTransport stream header.
@@ -24,6 +28,12 @@ pub enum WebTransportError {
 
     #[error("write error: {0}")]
     WriteError(#[from] quinn::WriteError),
+
+    #[error("capsule error: {0}")]
+    CapsuleError(#[from] web_transport_proto::CapsuleError),
+
+    #[error("flow control error")]
+    FlowControlError,
 }
 
 /// An error when writing to [`crate::SendStream`]. Similar to [`quinn::WriteError`].
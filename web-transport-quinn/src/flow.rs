@@ -0,0 +1,296 @@
+use web_transport_proto::{Capsule, DraftVersion, Settings};
+
+// Session-level flow control, added in draft 07 so sessions pooled on a connection can't starve each other.
+// QUIC still enforces its own connection and stream limits; these are applied on top.
+//
+// NOTE: Quinn doesn't expose how much data was received on a stream, so we count the highest offset it returned instead.
+// Any buffered data is read and credited when a stream is stopped or dropped, but Quinn discards data when a stream is reset
+// and hides its final size. The peer tells us when it's blocked at our limit, so we count any credit we didn't see it use then.
+pub struct FlowControl {
+    // The limits set by the peer, which we must not exceed when sending.
+    send_data: Credit,
+    send_bi: Credit,
+    send_uni: Credit,
+
+    // The limits we set for the peer, increased as data is read or discarded and streams are accepted.
+    recv_data: Window,
+    recv_bi: Window,
+    recv_uni: Window,
+}
+
+// Returned when the peer exceeds one of our limits.
+#[derive(Debug)]
+pub struct FlowControlExceeded;
+
+// Returned when a capsule isn't used for flow control.
+#[derive(Debug)]
+pub struct NotFlowControl;
+
+impl FlowControl {
+    // Returns None unless both sides advertised initial limits, in which case flow control is disabled.
+    pub fn new(draft: DraftVersion, local: &Settings, remote: &Settings) -> Option<Self> {
+        if draft < DraftVersion::Draft07 {
+            return None;
+        }
+
+        let local_data = local.webtransport_initial_max_data()?;
+        let remote_data = remote.webtransport_initial_max_data()?;
+
+        // Any missing stream limits default to 0.
+        Some(Self {
+            send_data: Credit::new(remote_data),
+            send_bi: Credit::new(remote.webtransport_initial_max_streams_bidi().unwrap_or(0)),
+            send_uni: Credit::new(remote.webtransport_initial_max_streams_uni().unwrap_or(0)),
+            recv_data: Window::new(local_data),
+            recv_bi: Window::new(local.webtransport_initial_max_streams_bidi().unwrap_or(0)),
+            recv_uni: Window::new(local.webtransport_initial_max_streams_uni().unwrap_or(0)),
+        })
+    }
+
+    // Process a flow control capsule from the peer, returning the capsule to send in response, if any.
+    pub fn recv_capsule(&mut self, capsule: &Capsule) -> Result<Option<Capsule>, NotFlowControl> {
+        match capsule {
+            Capsule::MaxData(max) => self.send_data.increase(*max),
+            Capsule::MaxStreamsBidi(max) => self.send_bi.increase(*max),
+            Capsule::MaxStreamsUni(max) => self.send_uni.increase(*max),
+            // The peer may have used credit on data that was discarded, such as when a stream was reset.
+            Capsule::DataBlocked(max) => {
+                return Ok(self.recv_data.blocked(*max).map(Capsule::MaxData))
+            }
+            // The peer is blocked on our stream limits, which we increase on our own schedule anyway.
+            Capsule::StreamsBlockedBidi(_) | Capsule::StreamsBlockedUni(_) => {}
+            // We don't limit individual streams beyond what QUIC already does.
+            Capsule::MaxStreamData { .. } | Capsule::StreamDataBlocked { .. } => {}
+            _ => return Err(NotFlowControl),
+        }
+
+        Ok(None)
+    }
+
+    // Try to use credit to open a stream, otherwise returning the BLOCKED capsule to send, if any.
+    pub fn open(&mut self, bi: bool) -> Result<(), Option<Capsule>> {
+        let credit = match bi {
+            true => &mut self.send_bi,
+            false => &mut self.send_uni,
+        };
+
+        if credit.available() > 0 {
+            credit.used += 1;
+            return Ok(());
+        }
+
+        let blocked = credit.blocked().map(|max| match bi {
+            true => Capsule::StreamsBlockedBidi(max),
+            false => Capsule::StreamsBlockedUni(max),
+        });
+
+        Err(blocked)
+    }
+
+    // Try to reserve up to size bytes of credit, otherwise returning the BLOCKED capsule to send, if any.
+    // Any reserved credit that isn't written must be returned with unreserve.
+    pub fn reserve(&mut self, size: usize) -> Result<usize, Option<Capsule>> {
+        let available = self.send_data.available();
+        if available == 0 && size > 0 {
+            return Err(self.send_data.blocked().map(Capsule::DataBlocked));
+        }
+
+        let size = (size as u64).min(available);
+        self.send_data.used += size;
+
+        Ok(size as usize)
+    }
+
+    pub fn unreserve(&mut self, size: usize) {
+        self.send_data.used -= size as u64;
+    }
+
+    // Record that the application read some data, returning the capsule to send if the limit should be increased.
+    pub fn read(&mut self, size: u64) -> Result<Option<Capsule>, FlowControlExceeded> {
+        let max = self.recv_data.consume(size)?;
+        Ok(max.map(Capsule::MaxData))
+    }
+
+    // Record that the application accepted a stream, returning the capsule to send if the limit should be increased.
+    pub fn accept(&mut self, bi: bool) -> Result<Option<Capsule>, FlowControlExceeded> {
+        match bi {
+            true => Ok(self.recv_bi.consume(1)?.map(Capsule::MaxStreamsBidi)),
+            false => Ok(self.recv_uni.consume(1)?.map(Capsule::MaxStreamsUni)),
+        }
+    }
+}
+
+// A limit set by the peer and how much of it we've used.
+struct Credit {
+    max: u64,
+    used: u64,
+
+    // The limit we last sent a BLOCKED capsule for, so it's only sent once per limit.
+    blocked: Option<u64>,
+}
+
+impl Credit {
+    fn new(max: u64) -> Self {
+        Self {
+            max,
+            used: 0,
+            blocked: None,
+        }
+    }
+
+    fn available(&self) -> u64 {
+        self.max.saturating_sub(self.used)
+    }
+
+    // Limits can only increase, so any smaller value is ignored.
+    fn increase(&mut self, max: u64) {
+        self.max = self.max.max(max);
+    }
+
+    fn blocked(&mut self) -> Option<u64> {
+        if self.blocked == Some(self.max) {
+            return None;
+        }
+
+        self.blocked = Some(self.max);
+        Some(self.max)
+    }
+}
+
+// A limit we set for the peer, which is increased by the window size as it's used.
+struct Window {
+    size: u64,
+    max: u64,
+    used: u64,
+
+    // Credit the peer used that we haven't seen yet, learned when it was blocked at the limit.
+    // This is either data that was discarded when a stream was reset, or data that's still buffered and is absorbed when read.
+    unseen: u64,
+}
+
+impl Window {
+    fn new(size: u64) -> Self {
+        Self {
+            size,
+            max: size,
+            used: 0,
+            unseen: 0,
+        }
+    }
+
+    // Returns the new limit once over half of the window has been used.
+    fn consume(&mut self, amount: u64) -> Result<Option<u64>, FlowControlExceeded> {
+        let unseen = amount.min(self.unseen);
+        self.unseen -= unseen;
+        self.used += amount - unseen;
+        if self.used > self.max {
+            return Err(FlowControlExceeded);
+        }
+
+        if self.size == 0 || self.max - self.used > self.size / 2 {
+            return Ok(None);
+        }
+
+        self.max = self.used + self.size;
+        Ok(Some(self.max))
+    }
+
+    // The peer is blocked at the provided limit, so any credit we haven't seen it use must've been discarded or buffered.
+    // Returns the new limit, so the peer isn't blocked forever by data that Quinn hid from us.
+    fn blocked(&mut self, max: u64) -> Option<u64> {
+        if max != self.max || self.used >= self.max || self.size == 0 {
+            return None;
+        }
+
+        self.unseen += self.max - self.used;
+        self.used = self.max;
+        self.max = self.used + self.size;
+
+        Some(self.max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    use crate::{test, ConnectRequest, Connection, ConnectionOptions, Incoming, SessionLimits};
+
+    #[test]
+    fn window_blocked() {
+        let mut window = Window::new(100);
+        assert_eq!(window.consume(40).unwrap(), None);
+
+        // The other 60 bytes were discarded by a reset, so the peer is blocked.
+        assert_eq!(window.blocked(100), Some(200));
+        assert_eq!(window.blocked(100), None);
+
+        // Anything we see afterwards absorbs the unseen credit first, so it's not counted twice.
+        assert_eq!(window.consume(60).unwrap(), None);
+        assert_eq!(window.used, 100);
+        assert_eq!(window.consume(100).unwrap(), Some(300));
+        assert!(window.consume(101).is_err());
+    }
+
+    #[tokio::test]
+    async fn reset_loop() {
+        let options = ConnectionOptions {
+            session_limits: Some(SessionLimits {
+                max_data: 4096,
+                max_streams_bidi: 100,
+                max_streams_uni: 100,
+            }),
+            ..Default::default()
+        };
+
+        let (server, client) = test::pair().await;
+        let (server, client) = tokio::join!(
+            Connection::server_with(server, options.clone()),
+            Connection::client_with(client, options)
+        );
+        let (server, client) = (server.unwrap(), client.unwrap());
+
+        let url = url::Url::parse("https://localhost/").unwrap();
+        let (client, server) = tokio::join!(client.connect(ConnectRequest::new(url)), async {
+            match server.accept().await.unwrap() {
+                Incoming::Session(request) => request.ok().await.unwrap(),
+                Incoming::Http(_) => panic!("expected a session request"),
+            }
+        });
+        let client = client.unwrap();
+
+        // Capsules are only processed while the session is polled, such as by an application waiting for it to close.
+        let closed = server.clone();
+        tokio::spawn(async move { closed.closed().await });
+
+        let data = vec![0; 1000];
+
+        let run = async {
+            // Reset each stream after the server buffered the data but before it's read, so it's discarded by Quinn.
+            for _ in 0..20 {
+                let mut send = client.open_uni().await.unwrap();
+                send.write_all(&data).await.unwrap();
+
+                let recv = server.accept_uni().await.unwrap();
+                tokio::time::sleep(Duration::from_millis(10)).await;
+
+                send.reset(0).unwrap();
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                drop(recv);
+            }
+
+            // The peer still has credit for more data.
+            let mut send = client.open_uni().await.unwrap();
+            send.write_all(&data).await.unwrap();
+            send.finish().await.unwrap();
+
+            let mut recv = server.accept_uni().await.unwrap();
+            recv.read_to_end(data.len()).await.unwrap()
+        };
+
+        let received = tokio::time::timeout(Duration::from_secs(10), run).await;
+        assert_eq!(received.unwrap().len(), data.len());
+    }
+}
@@ -17,33 +17,63 @@
 //! Both endpoints can send datagrams below the MTU size (~1.2kb minimum) and they might arrive out of order or not at all.
 //! They are basically UDP packets, except they are encrypted and congestion controlled.
 //!
-//! # Limitations
-//! WebTransport is able to be pooled with HTTP/3 and multiple WebTransport sessions.
-//! This crate avoids that complexity, doing the bare minimum to support a single WebTransport session that owns the entire QUIC connection.
-//! If you want to support HTTP/3 on the same host/port, you should use another crate (ex. `h3-webtransport`).
-//! If you want to support multiple WebTransport sessions over the same QUIC connection... you should just dial a new QUIC connection instead.
+//! # Pooling
+//! Multiple WebTransport sessions can share a QUIC connection, like a browser using `allowPooling`.
+//! Use [`Connection`] to accept or establish each session; [`accept`] and [`connect`] are shortcuts for a single session per connection.
+//!
+//! # HTTP/3
+//! The server also receives plain HTTP/3 requests as an [`HttpRequest`], so the same port can serve a landing page or health check.
+//! This is intentionally minimal; if you need a full HTTP/3 server, you should use another crate (ex. `h3-webtransport`).
 
 // External
+mod cert;
 mod client;
+mod connection;
 mod error;
 mod recv;
+mod request;
 mod send;
 mod server;
 mod session;
 
+pub use cert::*;
 pub use client::*;
+pub use connection::*;
 pub use error::*;
 pub use recv::*;
+pub use request::*;
 pub use send::*;
 pub use server::*;
 pub use session::*;
 
 // Internal
 mod connect;
+mod control;
+mod demux;
+mod flow;
+mod qpack;
 mod settings;
 
+#[cfg(test)]
+mod test;
+
 use connect::*;
+use control::*;
+use demux::*;
+use flow::*;
+use qpack::*;
 use settings::*;
 
+/// A CONNECT request, used to send additional headers or offer subprotocols to the server.
+pub use web_transport_proto::ConnectRequest;
+
+/// The version of the WebTransport draft used by a connection.
+pub use web_transport_proto::DraftVersion;
+
+/// The HTTP/3 settings sent by the peer, with typed accessors for the values used by WebTransport.
+pub use web_transport_proto::Settings as PeerSettings;
+
+use web_transport_proto::ConnectResponse;
+
 /// The HTTP/3 ALPN is required when negotiating a QUIC connection.
 pub const ALPN: &[u8] = b"h3";
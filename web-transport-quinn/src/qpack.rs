@@ -0,0 +1,254 @@
+use std::{
+    future::{poll_fn, Future},
+    io::Cursor,
+    sync::{Arc, Mutex, MutexGuard},
+    task::Poll,
+};
+
+use futures::{lock::Mutex as AsyncMutex, task::ArcWake};
+use thiserror::Error;
+use web_transport_proto::{qpack, StreamUni};
+
+use crate::{Settings, WakerSet};
+
+#[derive(Error, Debug, Clone)]
+pub enum QpackError {
+    #[error("quic stream was closed early")]
+    UnexpectedEnd,
+
+    #[error("encoder stream error: {0}")]
+    EncoderStreamError(qpack::DecodeError),
+
+    #[error("decoder stream error: {0}")]
+    DecoderStreamError(qpack::DecodeError),
+
+    #[error("connection error")]
+    ConnectionError(#[from] quinn::ConnectionError),
+
+    #[error("read error")]
+    ReadError(#[from] quinn::ReadError),
+
+    #[error("write error")]
+    WriteError(#[from] quinn::WriteError),
+}
+
+impl QpackError {
+    // The HTTP/3 error code used to close the connection.
+    pub fn code(&self) -> quinn::VarInt {
+        match self {
+            // H3_CLOSED_CRITICAL_STREAM
+            Self::UnexpectedEnd => quinn::VarInt::from_u32(0x104),
+            // QPACK_ENCODER_STREAM_ERROR
+            Self::EncoderStreamError(_) => quinn::VarInt::from_u32(0x201),
+            // QPACK_DECODER_STREAM_ERROR
+            Self::DecoderStreamError(_) => quinn::VarInt::from_u32(0x202),
+            // H3_INTERNAL_ERROR
+            _ => quinn::VarInt::from_u32(0x102),
+        }
+    }
+}
+
+// Implemented by both types of QPACK instructions so we can decode them generically.
+trait Instruction: Sized {
+    fn decode_from(buf: &mut Cursor<&Vec<u8>>) -> Result<Self, qpack::DecodeError>;
+}
+
+impl Instruction for qpack::EncoderInstruction {
+    fn decode_from(buf: &mut Cursor<&Vec<u8>>) -> Result<Self, qpack::DecodeError> {
+        Self::decode(buf)
+    }
+}
+
+impl Instruction for qpack::DecoderInstruction {
+    fn decode_from(buf: &mut Cursor<&Vec<u8>>) -> Result<Self, qpack::DecodeError> {
+        Self::decode(buf)
+    }
+}
+
+// A unidirectional stream carrying QPACK instructions, along with any bytes we couldn't decode yet.
+struct InstructionStream {
+    recv: quinn::RecvStream,
+    buf: Vec<u8>,
+}
+
+impl InstructionStream {
+    fn new(recv: quinn::RecvStream) -> Self {
+        Self {
+            recv,
+            buf: Vec::new(),
+        }
+    }
+
+    // Return the next batch of instructions, reading more data from the stream if needed.
+    async fn read<T: Instruction>(
+        &mut self,
+        error: fn(qpack::DecodeError) -> QpackError,
+    ) -> Result<Vec<T>, QpackError> {
+        loop {
+            // Decode as many instructions as possible, keeping any partial instruction for later.
+            let mut instructions = Vec::new();
+            let mut limit = Cursor::new(&self.buf);
+
+            loop {
+                let start = limit.position();
+                match T::decode_from(&mut limit) {
+                    Ok(instruction) => instructions.push(instruction),
+                    Err(qpack::DecodeError::UnexpectedEnd) => {
+                        limit.set_position(start);
+                        break;
+                    }
+                    Err(e) => return Err(error(e)),
+                }
+            }
+
+            let used = limit.position() as usize;
+            self.buf.drain(..used);
+
+            if !instructions.is_empty() {
+                return Ok(instructions);
+            }
+
+            let chunk = self.recv.read_chunk(usize::MAX, true).await?;
+            let chunk = chunk.ok_or(QpackError::UnexpectedEnd)?;
+            self.buf.extend_from_slice(&chunk.bytes); // TODO avoid copying on the first loop.
+        }
+    }
+}
+
+/// The QPACK state for a connection, fed by the peer's encoder and decoder streams.
+#[derive(Clone)]
+pub(crate) struct Qpack(Arc<QpackState>);
+
+struct QpackState {
+    conn: quinn::Connection,
+
+    decoder: Mutex<qpack::Decoder>,
+
+    // Every task waiting for the encoder stream to unblock a header block.
+    unblocked: Arc<WakerSet>,
+
+    // Our decoder stream, opened the first time we have an instruction to send.
+    decoder_send: AsyncMutex<Option<quinn::SendStream>>,
+}
+
+impl Qpack {
+    pub fn new(conn: quinn::Connection, settings: &Settings) -> Self {
+        let decoder = qpack::Decoder::new(
+            settings.local.qpack_max_table_capacity() as usize,
+            settings.local.qpack_blocked_streams() as usize,
+        );
+
+        Self(Arc::new(QpackState {
+            conn,
+            decoder: Mutex::new(decoder),
+            unblocked: Default::default(),
+            decoder_send: Default::default(),
+        }))
+    }
+
+    pub fn decoder(&self) -> MutexGuard<'_, qpack::Decoder> {
+        self.0.decoder.lock().unwrap()
+    }
+
+    async fn read_encoder(&self, stream: &mut InstructionStream) -> Result<(), QpackError> {
+        let instructions: Vec<qpack::EncoderInstruction> =
+            stream.read(QpackError::EncoderStreamError).await?;
+
+        let mut decoder = self.decoder();
+        for instruction in instructions {
+            log::trace!("received QPACK encoder instruction: {:?}", instruction);
+            decoder
+                .apply(instruction)
+                .map_err(QpackError::EncoderStreamError)?;
+        }
+
+        ArcWake::wake_by_ref(&self.0.unblocked);
+
+        Ok(())
+    }
+
+    // Wait until the encoder stream has inserted enough entries to decode a blocked header block.
+    // This doesn't read the encoder stream, so the caller must be polled by whatever does.
+    pub fn unblock(&self, required: u64) -> impl Future<Output = ()> + '_ {
+        poll_fn(move |cx| {
+            self.0.unblocked.register(cx.waker());
+
+            match self.decoder().table().inserted() >= required {
+                true => Poll::Ready(()),
+                false => Poll::Pending,
+            }
+        })
+    }
+
+    async fn read_decoder(&self, stream: &mut InstructionStream) -> Result<(), QpackError> {
+        let instructions: Vec<qpack::DecoderInstruction> =
+            stream.read(QpackError::DecoderStreamError).await?;
+
+        // We never reference the dynamic table, so there's nothing for the peer to acknowledge.
+        for instruction in instructions {
+            log::trace!("received QPACK decoder instruction: {:?}", instruction);
+            match instruction {
+                qpack::DecoderInstruction::StreamCancel(_) => {}
+                qpack::DecoderInstruction::SectionAck(id) => {
+                    return Err(QpackError::DecoderStreamError(
+                        qpack::DecodeError::UnknownStream(id),
+                    ))
+                }
+                qpack::DecoderInstruction::InsertCountIncrement(_) => {
+                    return Err(QpackError::DecoderStreamError(
+                        qpack::DecodeError::InvalidIncrement,
+                    ))
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Send any pending decoder instructions, opening our decoder stream if needed.
+    pub async fn flush(&self) -> Result<(), QpackError> {
+        let mut buf = Vec::new();
+        self.decoder().encode_instructions(&mut buf);
+
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        let mut send = self.0.decoder_send.lock().await;
+        let send = match &mut *send {
+            Some(send) => send,
+            None => {
+                let mut stream = self.0.conn.open_uni().await?;
+
+                let mut header = Vec::new();
+                StreamUni::QPACK_DECODER.encode(&mut header);
+                stream.write_all(&header).await?;
+
+                send.insert(stream)
+            }
+        };
+
+        send.write_all(&buf).await?;
+
+        Ok(())
+    }
+
+    // Called when the peer's encoder stream is accepted, reading it until there's an error.
+    pub async fn run_encoder(&self, recv: quinn::RecvStream) -> Result<(), QpackError> {
+        let mut stream = InstructionStream::new(recv);
+
+        loop {
+            self.read_encoder(&mut stream).await?;
+            self.flush().await?;
+        }
+    }
+
+    // Called when the peer's decoder stream is accepted, reading it until there's an error.
+    pub async fn run_decoder(&self, recv: quinn::RecvStream) -> Result<(), QpackError> {
+        let mut stream = InstructionStream::new(recv);
+
+        loop {
+            self.read_decoder(&mut stream).await?;
+        }
+    }
+}
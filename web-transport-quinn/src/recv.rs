@@ -1,42 +1,100 @@
 use std::{
-    io,
+    fmt, io,
     pin::Pin,
-    task::{Context, Poll},
+    sync::{Arc, Mutex, OnceLock},
+    task::{ready, Context, Poll},
 };
 
 use bytes::Bytes;
+use futures::FutureExt;
 
-use crate::{ReadError, ReadExactError, ReadToEndError};
+use web_transport_proto::DraftVersion;
+
+use crate::{ReadError, ReadExactError, ReadToEndError, SessionAccept, SessionError, SESSION_GONE};
 
 /// A stream that can be used to recieve bytes. See [`quinn::RecvStream`].
-#[derive(Debug)]
 pub struct RecvStream {
     inner: quinn::RecvStream,
+
+    // The draft used by the session, which determines the range of error codes.
+    draft: DraftVersion,
+
+    // The session, used to return credit to the peer if session-level flow control is enabled.
+    flow: Option<Arc<Mutex<SessionAccept>>>,
+
+    // Set once the session is closed, after which the stream is stopped with WT_SESSION_GONE.
+    closed: Option<Arc<OnceLock<SessionError>>>,
+
+    // The highest stream offset received so far, which is what the peer counts against the session's data limit.
+    offset: u64,
+
+    // Set once the stream has been read to the end, or failed, so there's nothing left to credit.
+    done: bool,
 }
 
 impl RecvStream {
-    pub(crate) fn new(stream: quinn::RecvStream) -> Self {
-        Self { inner: stream }
+    pub(crate) fn new(
+        stream: quinn::RecvStream,
+        draft: DraftVersion,
+        flow: Option<Arc<Mutex<SessionAccept>>>,
+        closed: Option<Arc<OnceLock<SessionError>>>,
+        offset: u64,
+    ) -> Self {
+        Self {
+            inner: stream,
+            draft,
+            flow,
+            closed,
+            offset,
+            done: false,
+        }
     }
 
     /// Tell the other end to stop sending data with the given error code. See [`quinn::RecvStream::stop`].
     /// This is a u32 with WebTransport since it shares the error space with HTTP/3.
     pub fn stop(&mut self, code: u32) -> Result<(), quinn::UnknownStream> {
-        let code = web_transport_proto::error_to_http3(code);
+        let code = self.draft.error_to_http3(code);
         let code = quinn::VarInt::try_from(code).unwrap();
-        self.inner.stop(code)
+
+        // Don't report an unknown stream if we only finished it by discarding the buffered data.
+        let done = self.done;
+        self.discard();
+
+        match self.inner.stop(code) {
+            Err(_) if self.done && !done => Ok(()),
+            res => res,
+        }
     }
 
     // Unfortunately, we have to wrap ReadError for a bunch of functions.
 
     /// Read some data into the buffer and return the amount read. See [`quinn::RecvStream::read`].
     pub async fn read(&mut self, buf: &mut [u8]) -> Result<Option<usize>, ReadError> {
-        self.inner.read(buf).await.map_err(Into::into)
+        self.check_session()?;
+
+        let size = self.inner.read(buf).await;
+        self.consumed(size.as_ref().map(|size| *size));
+        Ok(size?)
     }
 
     /// Fill the entire buffer with data. See [`quinn::RecvStream::read_exact`].
     pub async fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), ReadExactError> {
-        self.inner.read_exact(buf).await.map_err(Into::into)
+        self.check_session()?;
+
+        if self.flow.is_none() {
+            return self.inner.read_exact(buf).await.map_err(Into::into);
+        }
+
+        // Read incrementally so credit is returned as we go, otherwise a large buffer could block forever.
+        let mut offset = 0;
+        while offset < buf.len() {
+            match self.read(&mut buf[offset..]).await? {
+                Some(size) => offset += size,
+                None => return Err(ReadExactError::FinishedEarly),
+            }
+        }
+
+        Ok(())
     }
 
     /// Read a chunk of data from the stream. See [`quinn::RecvStream::read_chunk`].
@@ -45,20 +103,101 @@ impl RecvStream {
         max_length: usize,
         ordered: bool,
     ) -> Result<Option<quinn::Chunk>, ReadError> {
-        self.inner
-            .read_chunk(max_length, ordered)
-            .await
-            .map_err(Into::into)
+        self.check_session()?;
+
+        let chunk = self.inner.read_chunk(max_length, ordered).await;
+        if let Ok(Some(chunk)) = &chunk {
+            // Unordered chunks may skip ahead, which the peer has still counted against the limit.
+            self.received(chunk.offset + chunk.bytes.len() as u64);
+        } else {
+            self.done = true;
+        }
+
+        Ok(chunk?)
     }
 
     /// Read chunks of data from the stream. See [`quinn::RecvStream::read_chunks`].
     pub async fn read_chunks(&mut self, bufs: &mut [Bytes]) -> Result<Option<usize>, ReadError> {
-        self.inner.read_chunks(bufs).await.map_err(Into::into)
+        self.check_session()?;
+
+        let count = self.inner.read_chunks(bufs).await;
+        self.consumed(
+            count
+                .as_ref()
+                .map(|count| count.map(|count| bufs[..count].iter().map(Bytes::len).sum())),
+        );
+
+        Ok(count?)
     }
 
     /// Read until the end of the stream or the limit is hit. See [`quinn::RecvStream::read_to_end`].
     pub async fn read_to_end(&mut self, size_limit: usize) -> Result<Vec<u8>, ReadToEndError> {
-        self.inner.read_to_end(size_limit).await.map_err(Into::into)
+        self.check_session()?;
+
+        if self.flow.is_none() {
+            return self.inner.read_to_end(size_limit).await.map_err(Into::into);
+        }
+
+        // Read incrementally so credit is returned as we go, otherwise a large stream could block forever.
+        let mut data = Vec::new();
+        while let Some(chunk) = self.read_chunk(usize::MAX, true).await? {
+            if data.len() + chunk.bytes.len() > size_limit {
+                return Err(ReadToEndError::TooLong);
+            }
+
+            data.extend_from_slice(&chunk.bytes);
+        }
+
+        Ok(data)
+    }
+
+    // Record the result of an ordered read, which continues from the previous offset.
+    // A finished or failed stream has nothing left to credit, so it's not discarded later.
+    fn consumed<E>(&mut self, res: Result<Option<usize>, &E>) {
+        match res {
+            Ok(Some(size)) => self.received(self.offset + size as u64),
+            Ok(None) | Err(_) => self.done = true,
+        }
+    }
+
+    // Return credit to the peer as the received offset increases, if session-level flow control is enabled.
+    fn received(&mut self, offset: u64) {
+        if offset <= self.offset {
+            return;
+        }
+
+        if let Some(flow) = &self.flow {
+            flow.lock().unwrap().read(offset - self.offset);
+        }
+
+        self.offset = offset;
+    }
+
+    // Read and credit any data that's already buffered, since it's discarded when the stream is stopped.
+    // Otherwise the peer would count it against the session's data limit forever.
+    fn discard(&mut self) {
+        if self.flow.is_none() {
+            return;
+        }
+
+        while !self.done {
+            match self.inner.read_chunk(usize::MAX, false).now_or_never() {
+                Some(Ok(Some(chunk))) => self.received(chunk.offset + chunk.bytes.len() as u64),
+                Some(_) => self.done = true,
+                None => break,
+            }
+        }
+    }
+
+    // Returns the session's error if it was closed, stopping the stream with WT_SESSION_GONE.
+    fn check_session(&mut self) -> Result<(), ReadError> {
+        let err = match self.closed.as_ref().and_then(|closed| closed.get()) {
+            Some(err) => err.clone(),
+            None => return Ok(()),
+        };
+
+        self.inner.stop(SESSION_GONE).ok();
+        Err(err.into())
     }
 
     // We purposely don't expose the stream ID or 0RTT because it's not valid with WebTransport
@@ -70,6 +209,31 @@ impl tokio::io::AsyncRead for RecvStream {
         cx: &mut Context<'_>,
         buf: &mut tokio::io::ReadBuf,
     ) -> Poll<io::Result<()>> {
-        Pin::new(&mut self.inner).poll_read(cx, buf)
+        self.check_session().map_err(io::Error::other)?;
+
+        let before = buf.filled().len();
+        let empty = buf.remaining() == 0;
+        let res = ready!(Pin::new(&mut self.inner).poll_read(cx, buf));
+
+        // Reading nothing into a non-empty buffer means the stream is finished.
+        let size = buf.filled().len() - before;
+        self.consumed(res.as_ref().map(|_| (size > 0 || empty).then_some(size)));
+        Poll::Ready(res)
+    }
+}
+
+impl Drop for RecvStream {
+    fn drop(&mut self) {
+        // Otherwise quinn would stop the stream with a generic error code.
+        self.check_session().ok();
+
+        // Quinn stops the stream if it wasn't read to the end, discarding any buffered data.
+        self.discard();
+    }
+}
+
+impl fmt::Debug for RecvStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
     }
 }
@@ -0,0 +1,246 @@
+use std::io;
+
+use bytes::Bytes;
+use thiserror::Error;
+use web_transport_proto::{Frame, HttpResponse, VarInt};
+
+/// An error when reading or responding to a [`HttpRequest`].
+#[derive(Error, Debug, Clone)]
+pub enum HttpError {
+    #[error("unexpected end of stream")]
+    UnexpectedEnd,
+
+    #[error("unexpected frame {0:?}")]
+    UnexpectedFrame(Frame),
+
+    #[error("body is too long")]
+    TooLong,
+
+    #[error("response was already sent")]
+    AlreadyResponded,
+
+    #[error("response must be sent before the body")]
+    NotResponded,
+
+    #[error("read error: {0}")]
+    ReadError(#[from] quinn::ReadError),
+
+    #[error("write error: {0}")]
+    WriteError(#[from] quinn::WriteError),
+}
+
+/// A plain HTTP/3 request, such as a GET for a landing page or health check.
+///
+/// The request body is read with [`read_chunk`](Self::read_chunk) and the response is sent with [`respond`](Self::respond).
+/// Dropping the request without responding will reset the stream.
+pub struct HttpRequest {
+    request: web_transport_proto::HttpRequest,
+
+    send: quinn::SendStream,
+    recv: quinn::RecvStream,
+
+    // Any data received after the request headers.
+    buf: Vec<u8>,
+
+    // The remaining size of the DATA frame we're reading, or the frame we're skipping.
+    remaining: u64,
+    skipping: bool,
+
+    // Set once the response headers were sent.
+    responded: bool,
+}
+
+impl HttpRequest {
+    pub(crate) fn new(
+        request: web_transport_proto::HttpRequest,
+        send: quinn::SendStream,
+        recv: quinn::RecvStream,
+        buf: Vec<u8>,
+    ) -> Self {
+        Self {
+            request,
+            send,
+            recv,
+            buf,
+            remaining: 0,
+            skipping: false,
+            responded: false,
+        }
+    }
+
+    // The ID of the request stream, which is never a session ID.
+    pub(crate) fn stream_id(&self) -> quinn::StreamId {
+        self.send.id()
+    }
+
+    /// Returns the request method, such as GET or POST.
+    pub fn method(&self) -> &http::Method {
+        &self.request.method
+    }
+
+    /// Returns the request URI, including the path and query string.
+    pub fn uri(&self) -> &http::Uri {
+        &self.request.uri
+    }
+
+    /// Returns the headers provided by the client, excluding pseudo-headers.
+    pub fn headers(&self) -> &http::HeaderMap {
+        &self.request.headers
+    }
+
+    /// Read the next chunk of the request body, or None once the body is finished.
+    pub async fn read_chunk(&mut self) -> Result<Option<Bytes>, HttpError> {
+        loop {
+            if self.remaining > 0 {
+                let chunk = self.read_frame().await?;
+                if self.skipping {
+                    continue;
+                }
+
+                return Ok(Some(chunk));
+            }
+
+            let mut limit = io::Cursor::new(&self.buf);
+
+            // Read the frame header, skipping the payload of anything that isn't DATA.
+            // Trailers are ignored, so there's no need to decode a HEADERS frame.
+            let header =
+                Frame::decode(&mut limit).and_then(|typ| Ok((typ, VarInt::decode(&mut limit)?)));
+            match header {
+                Ok((typ, size)) => {
+                    if Self::is_unexpected(typ) {
+                        return Err(HttpError::UnexpectedFrame(typ));
+                    }
+
+                    let used = limit.position() as usize;
+                    self.buf.drain(..used);
+
+                    self.remaining = size.into_inner();
+                    self.skipping = typ != Frame::DATA;
+                }
+                Err(_) => match self.recv.read_chunk(usize::MAX, true).await? {
+                    Some(chunk) => self.buf.extend_from_slice(&chunk.bytes),
+                    None if self.buf.is_empty() => return Ok(None),
+                    None => return Err(HttpError::UnexpectedEnd),
+                },
+            }
+        }
+    }
+
+    // Control frames and reserved HTTP/2 frame types are not allowed on a request stream.
+    fn is_unexpected(typ: Frame) -> bool {
+        matches!(
+            typ,
+            Frame::CANCEL_PUSH
+                | Frame::SETTINGS
+                | Frame::PUSH_PROMISE
+                | Frame::GOAWAY
+                | Frame::MAX_PUSH_ID
+        ) || matches!(typ.0.into_inner(), 0x02 | 0x06 | 0x08 | 0x09)
+    }
+
+    // Read up to the remaining size of the current frame, using any buffered data first.
+    async fn read_frame(&mut self) -> Result<Bytes, HttpError> {
+        let max = usize::try_from(self.remaining).unwrap_or(usize::MAX);
+
+        let chunk = if !self.buf.is_empty() {
+            let size = self.buf.len().min(max);
+            Bytes::from(self.buf.drain(..size).collect::<Vec<u8>>())
+        } else {
+            match self.recv.read_chunk(max, true).await? {
+                Some(chunk) => chunk.bytes,
+                None => return Err(HttpError::UnexpectedEnd),
+            }
+        };
+
+        self.remaining -= chunk.len() as u64;
+        Ok(chunk)
+    }
+
+    /// Read the entire request body, returning an error if it's larger than `limit` bytes.
+    pub async fn read_to_end(&mut self, limit: usize) -> Result<Bytes, HttpError> {
+        let mut body = Vec::new();
+
+        while let Some(chunk) = self.read_chunk().await? {
+            if body.len() + chunk.len() > limit {
+                return Err(HttpError::TooLong);
+            }
+
+            body.extend_from_slice(&chunk);
+        }
+
+        Ok(body.into())
+    }
+
+    /// Send the response status and headers, followed by any number of calls to [`write`](Self::write).
+    pub async fn respond(&mut self, response: http::Response<()>) -> Result<(), HttpError> {
+        if self.responded {
+            return Err(HttpError::AlreadyResponded);
+        }
+
+        let (parts, _) = response.into_parts();
+        let resp = HttpResponse {
+            status: parts.status,
+            headers: parts.headers,
+        };
+
+        log::debug!("sending HTTP response: {:?}", resp);
+
+        let mut buf = Vec::new();
+        resp.encode(&mut buf);
+        self.send.write_all(&buf).await?;
+
+        self.responded = true;
+
+        Ok(())
+    }
+
+    /// Write part of the response body as a DATA frame.
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), HttpError> {
+        if !self.responded {
+            return Err(HttpError::NotResponded);
+        }
+
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let mut buf = Vec::new();
+        Frame::DATA.encode(&mut buf);
+        VarInt::try_from(data.len()).unwrap().encode(&mut buf);
+
+        self.send.write_all(&buf).await?;
+        self.send.write_all(data).await?;
+
+        Ok(())
+    }
+
+    /// Finish the response, waiting until the client has received it.
+    pub async fn finish(mut self) -> Result<(), HttpError> {
+        if !self.responded {
+            return Err(HttpError::NotResponded);
+        }
+
+        self.send.finish().await?;
+        Ok(())
+    }
+
+    /// Send the entire response, including the body, and finish the stream.
+    pub async fn respond_with(mut self, response: http::Response<Bytes>) -> Result<(), HttpError> {
+        let (parts, body) = response.into_parts();
+
+        self.respond(http::Response::from_parts(parts, ())).await?;
+        self.write(&body).await?;
+        self.finish().await
+    }
+}
+
+impl Drop for HttpRequest {
+    fn drop(&mut self) {
+        // Reset the stream if the application didn't respond, so the client isn't left waiting.
+        // H3_REQUEST_REJECTED
+        if !self.responded {
+            self.send.reset(quinn::VarInt::from_u32(0x10b)).ok();
+        }
+    }
+}
@@ -1,31 +1,55 @@
 use std::{
+    fmt,
+    future::{poll_fn, Future},
     io,
-    pin::Pin,
-    task::{Context, Poll},
+    pin::{pin, Pin},
+    sync::{Arc, Mutex, OnceLock},
+    task::{ready, Context, Poll},
 };
 
-use bytes::Bytes;
+use bytes::{Buf, Bytes};
 
-use crate::{StoppedError, StreamClosed, WriteError};
+use web_transport_proto::DraftVersion;
+
+use crate::{SessionAccept, SessionError, StoppedError, StreamClosed, WriteError, SESSION_GONE};
 
 /// A stream that can be used to send bytes. See [`quinn::SendStream`].
 ///
 /// This wrapper is mainly needed for error codes, which is unfortunate.
 /// WebTransport uses u32 error codes and they're mapped in a reserved HTTP/3 error space.
-#[derive(Debug)]
+/// Writes are also limited by the session's flow control, if enabled, on top of QUIC's own limits.
 pub struct SendStream {
     stream: quinn::SendStream,
+
+    // The draft used by the session, which determines the range of error codes.
+    draft: DraftVersion,
+
+    // The session, used to wait for credit if session-level flow control is enabled.
+    flow: Option<Arc<Mutex<SessionAccept>>>,
+
+    // Set once the session is closed, after which the stream is reset with WT_SESSION_GONE.
+    closed: Option<Arc<OnceLock<SessionError>>>,
 }
 
 impl SendStream {
-    pub(crate) fn new(stream: quinn::SendStream) -> Self {
-        Self { stream }
+    pub(crate) fn new(
+        stream: quinn::SendStream,
+        draft: DraftVersion,
+        flow: Option<Arc<Mutex<SessionAccept>>>,
+        closed: Option<Arc<OnceLock<SessionError>>>,
+    ) -> Self {
+        Self {
+            stream,
+            draft,
+            flow,
+            closed,
+        }
     }
 
     /// Abruptly reset the stream with the provided error code. See [`quinn::SendStream::reset`].
     /// This is a u32 with WebTransport because we share the error space with HTTP/3.
     pub fn reset(&mut self, code: u32) -> Result<(), StreamClosed> {
-        let code = web_transport_proto::error_to_http3(code);
+        let code = self.draft.error_to_http3(code);
         let code = quinn::VarInt::try_from(code).unwrap();
         self.stream.reset(code).map_err(Into::into)
     }
@@ -33,20 +57,40 @@ impl SendStream {
     /// Wait until the stream has been stopped and return the error code. See [`quinn::SendStream::stopped`].
     /// Unlike Quinn, this returns None if the code is not a valid WebTransport error code.
     pub async fn stopped(&mut self) -> Result<Option<u32>, StoppedError> {
+        if let Some(err) = self.session_gone() {
+            return Err(err.into());
+        }
+
         let code = self.stream.stopped().await?;
-        Ok(web_transport_proto::error_from_http3(code.into_inner()))
+        Ok(self.draft.error_from_http3(code.into_inner()))
     }
 
     // Unfortunately, we have to wrap WriteError for a bunch of functions.
 
     /// Write some data to the stream, returning the size written. See [`quinn::SendStream::write`].
     pub async fn write(&mut self, buf: &[u8]) -> Result<usize, WriteError> {
-        self.stream.write(buf).await.map_err(Into::into)
+        self.check_session()?;
+
+        match self.flow.is_some() {
+            true => poll_fn(|cx| self.poll_write_flow(cx, buf)).await,
+            false => self.stream.write(buf).await.map_err(Into::into),
+        }
     }
 
     /// Write all of the data to the stream. See [`quinn::SendStream::write_all`].
-    pub async fn write_all(&mut self, buf: &[u8]) -> Result<(), WriteError> {
-        self.stream.write_all(buf).await.map_err(Into::into)
+    pub async fn write_all(&mut self, mut buf: &[u8]) -> Result<(), WriteError> {
+        self.check_session()?;
+
+        if self.flow.is_none() {
+            return self.stream.write_all(buf).await.map_err(Into::into);
+        }
+
+        while !buf.is_empty() {
+            let size = self.write(buf).await?;
+            buf = &buf[size..];
+        }
+
+        Ok(())
     }
 
     /// Write chunks of data to the stream. See [`quinn::SendStream::write_chunks`].
@@ -54,21 +98,83 @@ impl SendStream {
         &mut self,
         bufs: &mut [Bytes],
     ) -> Result<quinn_proto::Written, WriteError> {
-        self.stream.write_chunks(bufs).await.map_err(Into::into)
+        self.check_session()?;
+
+        if self.flow.is_none() {
+            return self.stream.write_chunks(bufs).await.map_err(Into::into);
+        }
+
+        // Write from the first chunk with any data, skipping over any empty chunks.
+        let mut written = quinn_proto::Written::default();
+        for buf in bufs.iter_mut() {
+            if buf.is_empty() {
+                written.chunks += 1;
+                continue;
+            }
+
+            let size = self.write(buf).await?;
+            buf.advance(size);
+
+            written.bytes += size;
+            if buf.is_empty() {
+                written.chunks += 1;
+            }
+
+            break;
+        }
+
+        Ok(written)
     }
 
     /// Write a chunk of data to the stream. See [`quinn::SendStream::write_chunk`].
     pub async fn write_chunk(&mut self, buf: Bytes) -> Result<(), WriteError> {
-        self.stream.write_chunk(buf).await.map_err(Into::into)
+        self.check_session()?;
+
+        match self.flow.is_some() {
+            true => self.write_all(&buf).await,
+            false => self.stream.write_chunk(buf).await.map_err(Into::into),
+        }
     }
 
     /// Write all of the chunks of data to the stream. See [`quinn::SendStream::write_all_chunks`].
     pub async fn write_all_chunks(&mut self, bufs: &mut [Bytes]) -> Result<(), WriteError> {
-        self.stream.write_all_chunks(bufs).await.map_err(Into::into)
+        self.check_session()?;
+
+        if self.flow.is_none() {
+            return self.stream.write_all_chunks(bufs).await.map_err(Into::into);
+        }
+
+        for buf in bufs.iter_mut() {
+            self.write_all(buf).await?;
+            buf.clear();
+        }
+
+        Ok(())
+    }
+
+    // Write as much as the session's credit allows, waiting if there's none.
+    // The credit is reserved and returned within a single poll, so it's never leaked if the future is dropped.
+    fn poll_write_flow(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, WriteError>> {
+        let mut flow = self.flow.as_ref().unwrap().lock().unwrap();
+        let credit = ready!(flow.poll_reserve(cx, buf.len()))?;
+
+        let res = pin!(self.stream.write(&buf[..credit])).poll(cx);
+        let written = match res {
+            Poll::Ready(Ok(size)) => size,
+            _ => 0,
+        };
+
+        flow.unreserve(credit - written);
+        res.map_err(Into::into)
     }
 
     /// Wait until all of the data has been written to the stream. See [`quinn::SendStream::finish`].
     pub async fn finish(&mut self) -> Result<(), WriteError> {
+        self.check_session()?;
         self.stream.finish().await.map_err(Into::into)
     }
 
@@ -79,6 +185,20 @@ impl SendStream {
     pub fn priority(&self) -> Result<i32, StreamClosed> {
         self.stream.priority().map_err(Into::into)
     }
+
+    // Returns the session's error if it was closed, resetting the stream with WT_SESSION_GONE.
+    fn session_gone(&mut self) -> Option<SessionError> {
+        let err = self.closed.as_ref()?.get()?.clone();
+        self.stream.reset(SESSION_GONE).ok();
+        Some(err)
+    }
+
+    fn check_session(&mut self) -> Result<(), WriteError> {
+        match self.session_gone() {
+            Some(err) => Err(err.into()),
+            None => Ok(()),
+        }
+    }
 }
 
 impl tokio::io::AsyncWrite for SendStream {
@@ -87,7 +207,13 @@ impl tokio::io::AsyncWrite for SendStream {
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<io::Result<usize>> {
-        Pin::new(&mut self.stream).poll_write(cx, buf)
+        self.check_session().map_err(io::Error::other)?;
+
+        if self.flow.is_none() {
+            return Pin::new(&mut self.stream).poll_write(cx, buf);
+        }
+
+        self.poll_write_flow(cx, buf).map_err(io::Error::other)
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
@@ -95,6 +221,20 @@ impl tokio::io::AsyncWrite for SendStream {
     }
 
     fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.check_session().map_err(io::Error::other)?;
         Pin::new(&mut self.stream).poll_shutdown(cx)
     }
 }
+
+impl Drop for SendStream {
+    fn drop(&mut self) {
+        // Otherwise quinn would finish the stream, as if the session was still open.
+        self.session_gone();
+    }
+}
+
+impl fmt::Debug for SendStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.stream.fmt(f)
+    }
+}
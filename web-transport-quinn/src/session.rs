@@ -1,19 +1,24 @@
 use std::{
     fmt,
-    future::{poll_fn, Future},
-    io::Cursor,
+    future::poll_fn,
     ops::Deref,
     pin::Pin,
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, OnceLock},
     task::{ready, Context, Poll},
 };
 
 use bytes::{Bytes, BytesMut};
-use futures::stream::{FuturesUnordered, Stream, StreamExt};
+use futures::{
+    stream::{Stream, StreamExt},
+    task::ArcWake,
+};
 
-use crate::{Connect, RecvStream, SendStream, SessionError, Settings, WebTransportError};
+use crate::{
+    CapsuleRecv, CapsuleSend, Connect, Connection, FlowControl, NotFlowControl, PeerSettings,
+    RecvStream, Registration, SendStream, SessionError, WakerSet, WebTransportError,
+};
 
-use web_transport_proto::{Frame, StreamUni, VarInt};
+use web_transport_proto::{Capsule, CapsuleType, DraftVersion, Frame, StreamUni, VarInt};
 
 /// An established WebTransport session, acting like a full QUIC connection. See [`quinn::Connection`].
 ///
@@ -28,9 +33,6 @@ use web_transport_proto::{Frame, StreamUni, VarInt};
 pub struct Session {
     conn: quinn::Connection,
 
-    // The session ID, as determined by the stream ID of the connect request.
-    session_id: Option<VarInt>,
-
     // The accept logic is stateful, so use an Arc<Mutex> to share it.
     accept: Option<Arc<Mutex<SessionAccept>>>,
 
@@ -39,15 +41,29 @@ pub struct Session {
     header_bi: Vec<u8>,
     header_datagram: Vec<u8>,
 
-    // Keep a reference to the settings and connect stream to avoid closing them until dropped.
-    #[allow(dead_code)]
-    settings: Option<Arc<Settings>>,
-    #[allow(dead_code)]
-    connect: Option<Arc<Connect>>,
+    // The application protocol negotiated during the handshake, if any.
+    protocol: Option<String>,
+
+    // The draft negotiated for the connection, or the newest draft for a raw QUIC session.
+    draft: DraftVersion,
+
+    // The accept logic again, but only if session-level flow control is enabled.
+    // Each stream uses it to wait for credit from the peer and to return credit as data is read.
+    flow: Option<Arc<Mutex<SessionAccept>>>,
+
+    // Keep a reference to the HTTP/3 connection to avoid closing the control streams until dropped, and to send GOAWAY.
+    connection: Option<Connection>,
+
+    // Set once the session is closed, shared with each stream so it's reset with WT_SESSION_GONE.
+    closed: Option<Arc<OnceLock<SessionError>>>,
 }
 
 impl Session {
-    pub(crate) fn new(conn: quinn::Connection, settings: Settings, connect: Connect) -> Self {
+    pub(crate) fn new(
+        connection: Connection,
+        connect: Connect,
+        registration: Registration,
+    ) -> Self {
         // The session ID is the stream ID of the CONNECT request.
         let session_id = connect.session_id();
 
@@ -60,52 +76,75 @@ impl Session {
         Frame::WEBTRANSPORT.encode(&mut header_bi);
         session_id.encode(&mut header_bi);
 
+        // Datagrams use the quarter stream ID, since session IDs are always a multiple of 4.
         let mut header_datagram = Vec::new();
-        session_id.encode(&mut header_datagram);
+        VarInt::try_from(session_id.into_inner() / 4)
+            .unwrap()
+            .encode(&mut header_datagram);
+
+        let protocol = connect.protocol().map(str::to_string);
+        let draft = connect.draft();
+        let flow = FlowControl::new(
+            draft,
+            &connection.settings.local,
+            &connection.settings.remote,
+        );
+
+        // The CONNECT stream is now used for capsules, owned by the accept logic.
+        let (capsule_send, capsule_recv) = connect.into_capsules();
 
         // Accept logic is stateful, so use an Arc<Mutex> to share it.
-        let accept = SessionAccept::new(conn.clone(), session_id);
+        let conn = connection.conn.clone();
+        let flow_enabled = flow.is_some();
+        let accept =
+            SessionAccept::new(conn.clone(), registration, capsule_send, capsule_recv, flow);
+        let closed = accept.closed.clone();
+        let accept = Arc::new(Mutex::new(accept));
 
         Self {
             conn,
-            accept: Some(Arc::new(Mutex::new(accept))),
-            session_id: Some(session_id),
+            accept: Some(accept.clone()),
             header_uni,
             header_bi,
             header_datagram,
-            settings: Some(Arc::new(settings)),
-            connect: Some(Arc::new(connect)),
+            protocol,
+            draft,
+            flow: flow_enabled.then_some(accept),
+            connection: Some(connection),
+            closed: Some(closed),
         }
     }
 
     /// Accept a new unidirectional stream. See [`quinn::Connection::accept_uni`].
     pub async fn accept_uni(&self) -> Result<RecvStream, SessionError> {
-        if let Some(accept) = &self.accept {
-            poll_fn(|cx| accept.lock().unwrap().poll_accept_uni(cx)).await
-        } else {
-            self.conn
-                .accept_uni()
-                .await
-                .map(RecvStream::new)
-                .map_err(Into::into)
-        }
+        let (recv, header) = match &self.accept {
+            Some(accept) => poll_fn(|cx| accept.lock().unwrap().poll_accept_uni(cx)).await?,
+            None => (self.conn.accept_uni().await?, 0),
+        };
+
+        Ok(self.recv_stream(recv, header))
     }
 
     /// Accept a new bidirectional stream. See [`quinn::Connection::accept_bi`].
     pub async fn accept_bi(&self) -> Result<(SendStream, RecvStream), SessionError> {
-        if let Some(accept) = &self.accept {
-            poll_fn(|cx| accept.lock().unwrap().poll_accept_bi(cx)).await
-        } else {
-            self.conn
-                .accept_bi()
-                .await
-                .map(|(send, recv)| (SendStream::new(send), RecvStream::new(recv)))
-                .map_err(Into::into)
-        }
+        let (send, recv, header) = match &self.accept {
+            Some(accept) => poll_fn(|cx| accept.lock().unwrap().poll_accept_bi(cx)).await?,
+            None => {
+                let (send, recv) = self.conn.accept_bi().await?;
+                (send, recv, 0)
+            }
+        };
+
+        Ok((self.send_stream(send), self.recv_stream(recv, header)))
     }
 
     /// Open a new unidirectional stream. See [`quinn::Connection::open_uni`].
+    /// This waits for stream credit from the peer if session-level flow control is enabled.
     pub async fn open_uni(&self) -> Result<SendStream, SessionError> {
+        if let Some(flow) = &self.flow {
+            poll_fn(|cx| flow.lock().unwrap().poll_open(cx, false)).await?;
+        }
+
         let mut send = self.conn.open_uni().await?;
 
         // Set the stream priority to max and then write the stream header.
@@ -116,11 +155,16 @@ impl Session {
 
         // Reset the stream priority back to the default of 0.
         send.set_priority(0).ok();
-        Ok(SendStream::new(send))
+        Ok(self.send_stream(send))
     }
 
     /// Open a new bidirectional stream. See [`quinn::Connection::open_bi`].
+    /// This waits for stream credit from the peer if session-level flow control is enabled.
     pub async fn open_bi(&self) -> Result<(SendStream, RecvStream), SessionError> {
+        if let Some(flow) = &self.flow {
+            poll_fn(|cx| flow.lock().unwrap().poll_open(cx, true)).await?;
+        }
+
         let (mut send, recv) = self.conn.open_bi().await?;
 
         // Set the stream priority to max and then write the stream header.
@@ -131,7 +175,7 @@ impl Session {
 
         // Reset the stream priority back to the default of 0.
         send.set_priority(0).ok();
-        Ok((SendStream::new(send), RecvStream::new(recv)))
+        Ok((self.send_stream(send), self.recv_stream(recv, 0)))
     }
 
     /// Asynchronously receives an application datagram from the remote peer.
@@ -140,23 +184,11 @@ impl Session {
     /// peer over the connection.
     /// It waits for a datagram to become available and returns the received [`Datagram`].
     pub async fn read_datagram(&self) -> Result<Bytes, SessionError> {
-        let mut datagram = self.conn.read_datagram().await?;
-
-        let mut cursor = Cursor::new(&datagram);
-
-        if let Some(session_id) = self.session_id {
-            // We have to check and strip the session ID from the datagram.
-            let actual_id = VarInt::decode(&mut cursor)
-                .map_err(|_| WebTransportError::ReadError(quinn::ReadExactError::FinishedEarly))?;
-            if actual_id != session_id {
-                return Err(WebTransportError::UnknownSession.into());
-            }
+        match &self.accept {
+            // Datagrams are routed by session ID, which is stripped before they're queued.
+            Some(accept) => poll_fn(|cx| accept.lock().unwrap().poll_read_datagram(cx)).await,
+            None => Ok(self.conn.read_datagram().await?),
         }
-
-        // Return the datagram without the session ID.
-        let datagram = datagram.split_off(cursor.position() as usize);
-
-        Ok(datagram)
     }
 
     /// Sends an application datagram to the remote peer.
@@ -189,34 +221,147 @@ impl Session {
             .map(|mtu| mtu.saturating_sub(self.header_datagram.len()))
     }
 
-    /// Immediately close the connection with an error code and reason. See [`quinn::Connection::close`].
+    /// Close the session with an error code and reason.
+    ///
+    /// This sends a CLOSE_WEBTRANSPORT_SESSION capsule followed by a FIN on the CONNECT stream, like [`WebTransport.close()`](https://developer.mozilla.org/en-US/docs/Web/API/WebTransport/close).
+    /// They're written in the background using [`ConnectionOptions::runtime`](crate::ConnectionOptions::runtime), which keeps the connection open until the peer acknowledges them.
+    /// Any streams still open are reset with WT_SESSION_GONE the next time they're used, or when dropped.
+    ///
+    /// The connection is closed immediately instead for a raw QUIC session, or if there's no runtime and the capsule can't be written without blocking. See [`quinn::Connection::close`].
     pub fn close(&self, code: u32, reason: &[u8]) {
-        let code = if self.session_id.is_some() {
-            web_transport_proto::error_to_http3(code)
-                .try_into()
-                .unwrap()
-        } else {
-            code.into()
+        let accept = match &self.accept {
+            Some(accept) => accept,
+            None => return self.conn.close(code.into(), reason),
         };
 
-        self.conn.close(code, reason)
+        if !accept.lock().unwrap().close(code, reason) {
+            return;
+        }
+
+        let runtime = self
+            .connection
+            .as_ref()
+            .and_then(|connection| connection.runtime.clone());
+
+        let runtime = match runtime {
+            Some(runtime) => runtime,
+            None => {
+                if !accept.lock().unwrap().capsule_send.try_close() {
+                    let code = self.draft.error_to_http3(code);
+                    self.conn.close(code.try_into().unwrap(), reason);
+                }
+
+                return;
+            }
+        };
+
+        // The connection is held until we're done, otherwise dropping it would close the control streams.
+        let accept = accept.clone();
+        let connection = self.connection.clone();
+
+        runtime.spawn(Box::pin(async move {
+            let res = poll_fn(|cx| accept.lock().unwrap().capsule_send.poll_close(cx)).await;
+            if let Err(err) = res {
+                log::debug!("failed to close session: {}", err);
+            }
+
+            drop(connection);
+        }));
+    }
+
+    /// Ask the peer to gracefully close the session, such as before restarting a server.
+    ///
+    /// This sends a DRAIN_WEBTRANSPORT_SESSION capsule, which only applies to this session.
+    /// Nothing is sent with draft 02, which doesn't support the capsule; use [`Connection::drain`] to drain the whole connection instead.
+    /// The session remains open until either side calls [`close`](Self::close).
+    pub async fn drain(&self) -> Result<(), SessionError> {
+        let accept = match &self.accept {
+            Some(accept) => accept,
+            None => return Ok(()),
+        };
+
+        if !self
+            .draft
+            .supports_capsule(CapsuleType::DRAIN_WEBTRANSPORT_SESSION)
+        {
+            return Ok(());
+        }
+
+        accept
+            .lock()
+            .unwrap()
+            .capsule_send
+            .queue(Capsule::DrainWebTransportSession);
+
+        let res = poll_fn(|cx| accept.lock().unwrap().capsule_send.poll_flush(cx)).await;
+        res.map_err(Self::write_error)
+    }
+
+    /// Wait until the peer asks us to drain the session, via DRAIN_WEBTRANSPORT_SESSION or GOAWAY.
+    /// This also returns if the session is closed.
+    pub async fn draining(&self) {
+        match &self.accept {
+            Some(accept) => poll_fn(|cx| accept.lock().unwrap().poll_draining(cx)).await,
+            None => {
+                self.conn.closed().await;
+            }
+        }
     }
 
-    /// Wait until the session is closed, returning the error. See [`quinn::Connection::closed`].
+    /// Wait until the session is closed, returning the error.
+    /// Returns [`SessionError::Closed`] with the peer's code and reason if it closed the session.
     pub async fn closed(&self) -> SessionError {
-        self.conn.closed().await.into()
+        match &self.accept {
+            Some(accept) => poll_fn(|cx| accept.lock().unwrap().poll_closed(cx)).await,
+            None => self.conn.closed().await.into(),
+        }
     }
 
     /// Return why the session was closed, or None if it's not closed. See [`quinn::Connection::close_reason`].
     pub fn close_reason(&self) -> Option<SessionError> {
+        if let Some(err) = self.closed.as_ref().and_then(|closed| closed.get()) {
+            return Some(err.clone());
+        }
+
         self.conn.close_reason().map(Into::into)
     }
 
+    /// Returns the application protocol negotiated via `wt-protocol`, if any.
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    /// Returns the WebTransport draft negotiated for the connection.
+    /// This is None for a raw QUIC session.
+    pub fn draft(&self) -> Option<DraftVersion> {
+        self.connection.as_ref().and_then(Connection::draft)
+    }
+
+    /// Returns the HTTP/3 settings sent by the peer, useful for diagnostics.
+    /// This is None for a raw QUIC session.
+    pub fn peer_settings(&self) -> Option<&PeerSettings> {
+        self.connection.as_ref().map(Connection::peer_settings)
+    }
+
+    // Wrap the streams in our own types for correct error codes and flow control.
+    fn send_stream(&self, send: quinn::SendStream) -> SendStream {
+        SendStream::new(send, self.draft, self.flow.clone(), self.closed.clone())
+    }
+
+    // The header is skipped when counting the received offset, since it's not part of the stream's data.
+    fn recv_stream(&self, recv: quinn::RecvStream, header: u64) -> RecvStream {
+        let flow = self.flow.clone();
+        RecvStream::new(recv, self.draft, flow, self.closed.clone(), header)
+    }
+
     async fn write_full(send: &mut quinn::SendStream, buf: &[u8]) -> Result<(), SessionError> {
-        match send.write_all(buf).await {
-            Ok(_) => Ok(()),
-            Err(quinn::WriteError::ConnectionLost(err)) => Err(err.into()),
-            Err(err) => Err(WebTransportError::WriteError(err).into()),
+        send.write_all(buf).await.map_err(Self::write_error)
+    }
+
+    fn write_error(err: quinn::WriteError) -> SessionError {
+        match err {
+            quinn::WriteError::ConnectionLost(err) => err.into(),
+            err => WebTransportError::WriteError(err).into(),
         }
     }
 }
@@ -241,212 +386,343 @@ impl From<quinn::Connection> for Session {
     fn from(conn: quinn::Connection) -> Self {
         Self {
             conn,
-            session_id: None,
             header_uni: Default::default(),
             header_bi: Default::default(),
             header_datagram: Default::default(),
             accept: None,
-            settings: None,
-            connect: None,
+            protocol: None,
+            draft: DraftVersion::default(),
+            flow: None,
+            connection: None,
+            closed: None,
         }
     }
 }
 
 // Type aliases just so clippy doesn't complain about the complexity.
-type AcceptUni = dyn Stream<Item = Result<quinn::RecvStream, quinn::ConnectionError>> + Send;
-type AcceptBi = dyn Stream<Item = Result<(quinn::SendStream, quinn::RecvStream), quinn::ConnectionError>>
-    + Send;
-type PendingUni = dyn Future<Output = Result<(StreamUni, quinn::RecvStream), SessionError>> + Send;
-type PendingBi = dyn Future<Output = Result<Option<(quinn::SendStream, quinn::RecvStream)>, SessionError>>
-    + Send;
-
-// Logic just for accepting streams, which is annoying because of the stream header.
-pub struct SessionAccept {
-    session_id: VarInt,
-
-    // We also need to keep a reference to the qpack streams if the endpoint (incorrectly) creates them.
-    // Again, this is just so they don't get closed until we drop the session.
-    qpack_encoder: Option<quinn::RecvStream>,
-    qpack_decoder: Option<quinn::RecvStream>,
+type CapsuleStream = dyn Stream<Item = Result<Option<Capsule>, SessionError>> + Send;
 
-    accept_uni: Pin<Box<AcceptUni>>,
-    accept_bi: Pin<Box<AcceptBi>>,
+// Logic just for accepting streams, which are routed to each session by the connection.
+pub struct SessionAccept {
+    conn: quinn::Connection,
 
-    // Keep track of work being done to read/write the WebTransport stream header.
-    pending_uni: FuturesUnordered<Pin<Box<PendingUni>>>,
-    pending_bi: FuturesUnordered<Pin<Box<PendingBi>>>,
-}
+    // Our entry in the connection's demultiplexer, removed when dropped.
+    registration: Registration,
 
-impl SessionAccept {
-    pub(crate) fn new(conn: quinn::Connection, session_id: VarInt) -> Self {
-        // Create a stream that just outputs new streams, so it's easy to call from poll.
-        let accept_uni = Box::pin(futures::stream::unfold(conn.clone(), |conn| async {
-            Some((conn.accept_uni().await, conn))
-        }));
+    // The CONNECT stream, used to send capsules and read them until the session is closed.
+    capsule_send: CapsuleSend,
+    capsule_recv: Pin<Box<CapsuleStream>>,
 
-        let accept_bi = Box::pin(futures::stream::unfold(conn, |conn| async {
-            Some((conn.accept_bi().await, conn))
-        }));
+    // Session-level flow control, if enabled by both sides.
+    flow: Option<FlowControl>,
 
-        Self {
-            session_id,
+    // Every task waiting on the CONNECT stream.
+    wakers: Arc<WakerSet>,
 
-            qpack_decoder: None,
-            qpack_encoder: None,
+    // Set once the session is closed by either side, shared with the session's streams.
+    closed: Arc<OnceLock<SessionError>>,
 
-            accept_uni,
-            accept_bi,
+    // Set once the peer asks us to drain the session.
+    draining: bool,
+}
 
-            pending_uni: FuturesUnordered::new(),
-            pending_bi: FuturesUnordered::new(),
+impl SessionAccept {
+    pub(crate) fn new(
+        conn: quinn::Connection,
+        registration: Registration,
+        capsule_send: CapsuleSend,
+        capsule_recv: CapsuleRecv,
+        flow: Option<FlowControl>,
+    ) -> Self {
+        Self {
+            conn,
+            registration,
+
+            capsule_send,
+            capsule_recv: Box::pin(futures::stream::unfold(capsule_recv, |mut recv| async {
+                Some((recv.read().await, recv))
+            })),
+            flow,
+            wakers: Default::default(),
+            closed: Default::default(),
+            draining: false,
         }
     }
 
-    // This is poll-based because we accept and decode streams in parallel.
+    // This is poll-based because the connection accepts and decodes streams in parallel.
     // In async land I would use tokio::JoinSet, but that requires a runtime.
     // It's better to use FuturesUnordered instead because it's agnostic.
     pub fn poll_accept_uni(
         &mut self,
         cx: &mut Context<'_>,
-    ) -> Poll<Result<RecvStream, SessionError>> {
-        loop {
-            // Accept any new streams.
-            if let Poll::Ready(Some(res)) = self.accept_uni.poll_next_unpin(cx) {
-                // Start decoding the header and add the future to the list of pending streams.
-                let recv = res?;
-                let pending = Self::decode_uni(recv, self.session_id);
-                self.pending_uni.push(Box::pin(pending));
-
-                continue;
-            }
+    ) -> Poll<Result<(quinn::RecvStream, u64), SessionError>> {
+        // Stop accepting streams once the session is closed.
+        if let Poll::Ready(err) = self.poll_closed(cx) {
+            return Poll::Ready(Err(err));
+        }
 
-            // Poll the list of pending streams.
-            let (typ, recv) = match ready!(self.pending_uni.poll_next_unpin(cx)) {
-                Some(res) => res?,
-                None => return Poll::Pending,
-            };
+        let session_id = self.registration.session_id();
+        let mut demux = self.registration.demux().lock().unwrap();
+        let stream = ready!(demux.poll_accept_uni(cx, session_id))?;
+        drop(demux);
 
-            // Decide if we keep looping based on the type.
-            match typ {
-                StreamUni::WEBTRANSPORT => {
-                    let recv = RecvStream::new(recv);
-                    return Poll::Ready(Ok(recv));
-                }
-                StreamUni::QPACK_DECODER => {
-                    self.qpack_decoder = Some(recv);
-                }
-                StreamUni::QPACK_ENCODER => {
-                    self.qpack_encoder = Some(recv);
-                }
-                _ => {
-                    // ignore unknown streams
-                    log::debug!("ignoring unknown unidirectional stream: {:?}", typ);
-                }
-            }
+        self.accepted(false)?;
+        Poll::Ready(Ok(stream))
+    }
+
+    pub fn poll_accept_bi(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(quinn::SendStream, quinn::RecvStream, u64), SessionError>> {
+        // Stop accepting streams once the session is closed.
+        if let Poll::Ready(err) = self.poll_closed(cx) {
+            return Poll::Ready(Err(err));
         }
+
+        let session_id = self.registration.session_id();
+        let mut demux = self.registration.demux().lock().unwrap();
+        let stream = ready!(demux.poll_accept_bi(cx, session_id))?;
+        drop(demux);
+
+        self.accepted(true)?;
+        Poll::Ready(Ok(stream))
     }
 
-    // Reads the stream header, returning the stream type.
-    async fn decode_uni(
-        mut recv: quinn::RecvStream,
-        expected_session: VarInt,
-    ) -> Result<(StreamUni, quinn::RecvStream), SessionError> {
-        // Read the VarInt at the start of the stream.
-        let typ = Self::read_varint(&mut recv).await?;
-        let typ = StreamUni(typ);
-
-        if typ == StreamUni::WEBTRANSPORT {
-            // Read the session_id and validate it
-            let session_id = Self::read_varint(&mut recv).await?;
-            if session_id != expected_session {
-                return Err(WebTransportError::UnknownSession.into());
-            }
+    pub fn poll_read_datagram(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Bytes, SessionError>> {
+        if let Poll::Ready(err) = self.poll_closed(cx) {
+            return Poll::Ready(Err(err));
         }
 
-        // We need to keep a reference to the qpack streams if the endpoint (incorrectly) creates them, so return everything.
-        Ok((typ, recv))
+        let session_id = self.registration.session_id();
+        let mut demux = self.registration.demux().lock().unwrap();
+        let datagram = ready!(demux.poll_read_datagram(cx, session_id))?;
+
+        Poll::Ready(Ok(datagram))
     }
 
-    pub fn poll_accept_bi(
+    // Wait for credit to open a stream, sending a BLOCKED capsule if we're out.
+    pub fn poll_open(&mut self, cx: &mut Context<'_>, bi: bool) -> Poll<Result<(), SessionError>> {
+        // This also processes any MAX_STREAMS capsules.
+        if let Poll::Ready(err) = self.poll_closed(cx) {
+            return Poll::Ready(Err(err));
+        }
+
+        let blocked = match self.flow.as_mut().map(|flow| flow.open(bi)) {
+            None | Some(Ok(())) => return Poll::Ready(Ok(())),
+            Some(Err(blocked)) => blocked,
+        };
+
+        if let Some(capsule) = blocked {
+            self.send_capsule(capsule);
+        }
+
+        Poll::Pending
+    }
+
+    // Wait for credit to send up to size bytes, which must be returned with unreserve if not used.
+    pub fn poll_reserve(
         &mut self,
         cx: &mut Context<'_>,
-    ) -> Poll<Result<(SendStream, RecvStream), SessionError>> {
-        loop {
-            // Accept any new streams.
-            if let Poll::Ready(Some(res)) = self.accept_bi.poll_next_unpin(cx) {
-                // Start decoding the header and add the future to the list of pending streams.
-                let (send, recv) = res?;
-                let pending = Self::decode_bi(send, recv, self.session_id);
-                self.pending_bi.push(Box::pin(pending));
-
-                continue;
-            }
+        size: usize,
+    ) -> Poll<Result<usize, SessionError>> {
+        // This also processes any MAX_DATA capsules.
+        if let Poll::Ready(err) = self.poll_closed(cx) {
+            return Poll::Ready(Err(err));
+        }
 
-            // Poll the list of pending streams.
-            let res = match ready!(self.pending_bi.poll_next_unpin(cx)) {
-                Some(res) => res?,
-                None => return Poll::Pending,
-            };
+        let blocked = match self.flow.as_mut().map(|flow| flow.reserve(size)) {
+            None => return Poll::Ready(Ok(size)),
+            Some(Ok(size)) => return Poll::Ready(Ok(size)),
+            Some(Err(blocked)) => blocked,
+        };
+
+        if let Some(capsule) = blocked {
+            self.send_capsule(capsule);
+        }
+
+        Poll::Pending
+    }
 
-            if let Some((send, recv)) = res {
-                // Wrap the streams in our own types for correct error codes.
-                let send = SendStream::new(send);
-                let recv = RecvStream::new(recv);
-                return Poll::Ready(Ok((send, recv)));
+    pub fn unreserve(&mut self, size: usize) {
+        if let Some(flow) = &mut self.flow {
+            flow.unreserve(size);
+        }
+    }
+
+    // Return credit to the peer once some data has been received, whether it was read or discarded.
+    pub fn read(&mut self, size: u64) {
+        let res = match &mut self.flow {
+            Some(flow) => flow.read(size),
+            None => return,
+        };
+
+        match res {
+            Ok(Some(capsule)) => self.send_capsule(capsule),
+            Ok(None) => {}
+            Err(_) => self.flow_control_error(),
+        }
+    }
+
+    // Return stream credit to the peer once the application has accepted a stream.
+    fn accepted(&mut self, bi: bool) -> Result<(), SessionError> {
+        let res = match &mut self.flow {
+            Some(flow) => flow.accept(bi),
+            None => return Ok(()),
+        };
+
+        match res {
+            Ok(Some(capsule)) => self.send_capsule(capsule),
+            Ok(None) => {}
+            Err(_) => {
+                self.flow_control_error();
+                return Err(WebTransportError::FlowControlError.into());
             }
+        }
+
+        Ok(())
+    }
+
+    // Queue a capsule and try to write it now, otherwise it's written the next time the session is polled.
+    fn send_capsule(&mut self, capsule: Capsule) {
+        self.capsule_send.queue(capsule);
 
-            // Keep looping if it's a stream we want to ignore.
+        let waker = futures::task::waker(self.wakers.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        if let Poll::Ready(Err(err)) = self.capsule_send.poll_flush(&mut cx) {
+            log::debug!("failed to write capsules: {}", err);
         }
     }
 
-    // Reads the stream header, returning Some if it's a WebTransport stream.
-    async fn decode_bi(
-        send: quinn::SendStream,
-        mut recv: quinn::RecvStream,
-        expected_session: VarInt,
-    ) -> Result<Option<(quinn::SendStream, quinn::RecvStream)>, SessionError> {
-        let typ = Self::read_varint(&mut recv).await?;
-        if Frame(typ) != Frame::WEBTRANSPORT {
-            log::debug!("ignoring unknown bidirectional stream: {:?}", typ);
-            return Ok(None);
+    // The peer exceeded one of our limits, so close the connection.
+    fn flow_control_error(&mut self) {
+        // WT_FLOW_CONTROL_ERROR
+        self.conn.close(
+            quinn::VarInt::from_u32(0x045d4487),
+            b"session flow control error",
+        );
+        self.set_closed(WebTransportError::FlowControlError.into());
+    }
+
+    // Queue a CLOSE_WEBTRANSPORT_SESSION capsule, returning false if the session was already closed.
+    // The caller is responsible for writing it and the FIN via capsule_send.
+    pub fn close(&mut self, code: u32, reason: &[u8]) -> bool {
+        if self.closed.get().is_some() {
+            return false;
         }
 
-        // Read the session ID and validate it.
-        let session_id = Self::read_varint(&mut recv).await?;
-        if session_id != expected_session {
-            return Err(WebTransportError::UnknownSession.into());
+        self.capsule_send.queue(Capsule::CloseWebTransportSession {
+            code,
+            reason: String::from_utf8_lossy(reason).into_owned(),
+        });
+
+        self.set_closed(quinn::ConnectionError::LocallyClosed.into());
+        true
+    }
+
+    // Mark the session as closed and reject any streams the application hasn't accepted yet.
+    // Streams that were already accepted or opened notice the next time they're used.
+    fn set_closed(&mut self, err: SessionError) {
+        if self.closed.set(err).is_err() {
+            return;
         }
 
-        Ok(Some((send, recv)))
+        let session_id = self.registration.session_id();
+        self.registration.demux().lock().unwrap().remove(session_id);
+
+        // Wake any tasks waiting on the session, such as accept_uni, so they return the error.
+        ArcWake::wake_by_ref(&self.wakers);
     }
 
-    // Read into the provided buffer and cast any errors to SessionError.
-    async fn read_full(recv: &mut quinn::RecvStream, buf: &mut [u8]) -> Result<(), SessionError> {
-        match recv.read_exact(buf).await {
-            Ok(()) => Ok(()),
-            Err(quinn::ReadExactError::ReadError(quinn::ReadError::ConnectionLost(err))) => {
-                Err(err.into())
-            }
-            Err(err) => Err(WebTransportError::ReadError(err).into()),
+    // Returns the reason once the session is closed by either side.
+    pub fn poll_closed(&mut self, cx: &mut Context<'_>) -> Poll<SessionError> {
+        self.poll_capsules(cx);
+
+        match self.closed.get() {
+            Some(err) => Poll::Ready(err.clone()),
+            None => Poll::Pending,
+        }
+    }
+
+    // Returns once the peer asks us to drain the session, or the session is closed.
+    // This also drives the connection in the background, so we notice a GOAWAY or protocol violation.
+    pub fn poll_draining(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        self.poll_capsules(cx);
+
+        let demux = self.registration.demux();
+        let goaway = demux.lock().unwrap().poll_goaway(cx).is_ready();
+
+        if goaway || self.draining || self.closed.get().is_some() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
         }
     }
 
-    // Read a varint from the stream.
-    async fn read_varint(recv: &mut quinn::RecvStream) -> Result<VarInt, SessionError> {
-        // 8 bytes is the max size of a varint
-        let mut buf = [0; 8];
+    // Process any capsules on the CONNECT stream until the session is closed.
+    fn poll_capsules(&mut self, cx: &mut Context<'_>) {
+        self.wakers.register(cx.waker());
 
-        // Read the first byte because it includes the length.
-        Self::read_full(recv, &mut buf[0..1]).await?;
+        let waker = futures::task::waker(self.wakers.clone());
+        let mut cx = Context::from_waker(&waker);
 
-        // 0b00 = 1, 0b01 = 2, 0b10 = 4, 0b11 = 8
-        let size = 1 << (buf[0] >> 6);
-        Self::read_full(recv, &mut buf[1..size]).await?;
+        // Write any capsules we queued earlier, such as flow control updates.
+        if let Poll::Ready(Err(err)) = self.capsule_send.poll_flush(&mut cx) {
+            log::debug!("failed to write capsules: {}", err);
+        }
 
-        // Use a cursor to read the varint on the stack.
-        let mut cursor = Cursor::new(&buf[..size]);
-        let v = VarInt::decode(&mut cursor).unwrap();
+        while self.closed.get().is_none() {
+            let res = match self.capsule_recv.poll_next_unpin(&mut cx) {
+                Poll::Ready(Some(res)) => res,
+                _ => return,
+            };
 
-        Ok(v)
+            let err = match res {
+                Ok(Some(Capsule::CloseWebTransportSession { code, reason })) => {
+                    SessionError::Closed { code, reason }
+                }
+                Ok(Some(Capsule::DrainWebTransportSession)) => {
+                    log::debug!("received DRAIN_WEBTRANSPORT_SESSION");
+                    self.draining = true;
+                    continue;
+                }
+                Ok(Some(capsule)) => {
+                    let res = match &mut self.flow {
+                        Some(flow) => flow.recv_capsule(&capsule),
+                        None => Err(NotFlowControl),
+                    };
+
+                    match res {
+                        Ok(Some(reply)) => self.send_capsule(reply),
+                        Ok(None) => {}
+                        Err(_) => log::debug!("ignoring capsule: {:?}", capsule),
+                    }
+
+                    continue;
+                }
+                // A FIN without a capsule is the same as closing with no error.
+                Ok(None) => SessionError::Closed {
+                    code: 0,
+                    reason: String::new(),
+                },
+                Err(err) => err,
+            };
+
+            match &err {
+                // Reply with a FIN, which is all that's left to do.
+                SessionError::Closed { .. } => self.capsule_send.finish(),
+                // H3_MESSAGE_ERROR
+                SessionError::WebTransportError(WebTransportError::CapsuleError(e)) => {
+                    self.conn
+                        .close(quinn::VarInt::from_u32(0x10e), e.to_string().as_bytes());
+                }
+                _ => {}
+            }
+
+            self.set_closed(err);
+        }
     }
 }
@@ -1,7 +1,26 @@
-use futures::try_join;
-use std::io;
+use futures::{
+    future::{self, poll_fn},
+    lock::Mutex as AsyncMutex,
+    stream::StreamExt,
+    try_join,
+};
+use std::{io, pin::Pin, sync::Mutex, task::Poll};
 
+use rand::Rng;
 use thiserror::Error;
+use web_transport_proto::{ControlFrame, DraftVersion, Frame, Setting, StreamUni, VarInt};
+
+use crate::{
+    ConnectionOptions, ControlRecv, Demux, EarlyUni, HeaderError, PendingUni, StreamHeaderLimits,
+    StreamRejectedReason, Timeout,
+};
+
+// The QPACK dynamic table we allow the peer to use when encoding headers.
+const QPACK_MAX_TABLE_CAPACITY: u32 = 4096;
+const QPACK_BLOCKED_STREAMS: u32 = 16;
+
+// The largest SETTINGS frame we'll buffer, which is far more than any real peer sends.
+const MAX_SETTINGS_SIZE: usize = 16 * 1024;
 
 #[derive(Error, Debug, Clone)]
 pub enum SettingsError {
@@ -11,9 +30,6 @@ pub enum SettingsError {
     #[error("protocol error: {0}")]
     ProtoError(#[from] web_transport_proto::SettingsError),
 
-    #[error("WebTransport is not supported")]
-    WebTransportUnsupported,
-
     #[error("connection error")]
     ConnectionError(#[from] quinn::ConnectionError),
 
@@ -25,27 +41,136 @@ pub enum SettingsError {
 }
 
 pub struct Settings {
-    // A reference to the send/recv stream, so we don't close it until dropped.
-    #[allow(dead_code)]
-    send: quinn::SendStream,
+    // Our control stream, kept open until dropped and used to send GOAWAY.
+    send: AsyncMutex<quinn::SendStream>,
+
+    // The peer's control stream, which is read by the session after SETTINGS.
+    recv: Option<ControlRecv>,
+
+    // Whether we're the server, which determines the frames and streams the peer may send.
+    pub(crate) server: bool,
+
+    // The newest draft supported by both sides, or None if the peer only supports plain HTTP/3.
+    pub(crate) draft: Option<DraftVersion>,
 
-    #[allow(dead_code)]
-    recv: quinn::RecvStream,
+    // The settings we sent and the settings we received.
+    pub(crate) local: web_transport_proto::Settings,
+    pub(crate) remote: web_transport_proto::Settings,
 }
 
 impl Settings {
-    // Establish the H3 connection.
-    pub async fn connect(conn: &quinn::Connection) -> Result<Self, SettingsError> {
-        let recv = Self::accept(conn);
-        let send = Self::open(conn);
+    // Establish the H3 connection, allowing up to max_sessions WebTransport sessions.
+    // Also returns any unidirectional streams the peer opened before its control stream.
+    pub async fn connect(
+        conn: &quinn::Connection,
+        options: &ConnectionOptions,
+    ) -> Result<(Self, EarlyUni), SettingsError> {
+        let recv = Self::accept(conn, &options.stream_headers, Timeout::new(options));
+        let send = Self::open(conn, options);
 
         // Run both tasks concurrently until one errors or they both complete.
-        let (send, recv) = try_join!(send, recv)?;
-        Ok(Self { send, recv })
+        let ((send, local), (recv, buf, remote, early)) = try_join!(send, recv)?;
+
+        // WebTransport is only required once a session is requested, so plain HTTP/3 peers still work.
+        let draft = DraftVersion::negotiate(&options.drafts, &remote);
+        log::debug!("negotiated WebTransport draft: {:?}", draft);
+
+        // Our control stream tells us which side we are, which determines the frames the peer may send.
+        let server = send.id().initiator() == quinn_proto::Side::Server;
+        let recv = ControlRecv::new(recv, buf, server);
+
+        let settings = Self {
+            send: AsyncMutex::new(send),
+            recv: Some(recv),
+            server,
+            draft,
+            local,
+            remote,
+        };
+
+        Ok((settings, early))
+    }
+
+    // Take the peer's control stream so it can be read after SETTINGS.
+    pub fn take_control(&mut self) -> Option<ControlRecv> {
+        self.recv.take()
+    }
+
+    // Send a GOAWAY frame on our control stream, using the ID chosen by the demultiplexer.
+    // The ID is chosen while holding the stream, so a concurrent call can't send a larger ID after a smaller one.
+    pub async fn goaway(&self, demux: &Mutex<Demux>) -> Result<(), quinn::WriteError> {
+        let mut send = self.send.lock().await;
+
+        let id = demux.lock().unwrap().goaway_id();
+        let goaway = ControlFrame::GoAway(id);
+        log::debug!("sending GOAWAY frame: {:?}", goaway);
+
+        let mut buf = Vec::new();
+        goaway.encode(&mut buf);
+
+        send.write_all(&buf).await
     }
 
-    async fn accept(conn: &quinn::Connection) -> Result<quinn::RecvStream, SettingsError> {
-        let mut recv = conn.accept_uni().await?;
+    // Accept unidirectional streams until we find the peer's control stream, then read the SETTINGS frame.
+    // The peer may open its QPACK streams (or anything else) first, so each stream type is read concurrently.
+    // Any other streams are returned so they can be routed once the connection is established.
+    async fn accept(
+        conn: &quinn::Connection,
+        limits: &StreamHeaderLimits,
+        timeout: Option<Timeout>,
+    ) -> Result<
+        (
+            quinn::RecvStream,
+            Vec<u8>,
+            web_transport_proto::Settings,
+            EarlyUni,
+        ),
+        SettingsError,
+    > {
+        // Create a stream that just outputs new streams, so it's easy to call from poll.
+        let mut accept = Box::pin(futures::stream::unfold(conn.clone(), |conn| async {
+            Some((conn.accept_uni().await, conn))
+        }));
+
+        let mut pending = EarlyUni::new();
+        let mut early = Vec::new();
+
+        let mut recv = poll_fn(|cx| loop {
+            if let Poll::Ready(Some(res)) = accept.poll_next_unpin(cx) {
+                let mut recv = res?;
+
+                // Use the same limit as the demux, so the peer can't pin memory before sending SETTINGS either.
+                if pending.len() >= limits.max_pending_uni {
+                    let reason = StreamRejectedReason::TooManyPending;
+                    recv.stop(Demux::reject_code(&reason)).ok();
+                    early.push(Err(HeaderError::Rejected(recv.id(), reason)));
+                } else {
+                    pending.push(Box::pin(Demux::decode_uni(recv, timeout.clone())));
+                }
+
+                continue;
+            }
+
+            match pending.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok((StreamUni::CONTROL, _, recv)))) => {
+                    return Poll::Ready(Ok(recv))
+                }
+                Poll::Ready(Some(Err(HeaderError::Connection(err)))) => {
+                    return Poll::Ready(Err(err))
+                }
+                Poll::Ready(Some(res)) => early.push(res),
+                _ => return Poll::Pending,
+            }
+        })
+        .await?;
+
+        // Streams that were already decoded are routed first, followed by those still waiting for their header.
+        pending.extend(
+            early
+                .into_iter()
+                .map(|res| Box::pin(future::ready(res)) as Pin<Box<PendingUni>>),
+        );
+
         let mut buf = Vec::new();
 
         loop {
@@ -57,34 +182,143 @@ impl Settings {
             // Look at the buffer we've already read.
             let mut limit = io::Cursor::new(&buf);
 
-            let settings = match web_transport_proto::Settings::decode(&mut limit) {
+            let settings = match web_transport_proto::Settings::decode_frame(&mut limit) {
                 Ok(settings) => settings,
-                Err(web_transport_proto::SettingsError::UnexpectedEnd) => continue, // More data needed.
-                Err(e) => return Err(e.into()),
+                Err(web_transport_proto::SettingsError::UnexpectedEnd) => {
+                    if buf.len() > MAX_SETTINGS_SIZE {
+                        // H3_EXCESSIVE_LOAD
+                        conn.close(quinn::VarInt::from_u32(0x107), b"settings too large");
+                        return Err(web_transport_proto::SettingsError::InvalidSize.into());
+                    }
+
+                    continue; // More data needed.
+                }
+                Err(e) => {
+                    // H3_SETTINGS_ERROR
+                    conn.close(quinn::VarInt::from_u32(0x109), b"invalid settings");
+                    return Err(e.into());
+                }
             };
 
             log::debug!("received SETTINGS frame: {:?}", settings);
 
-            if settings.supports_webtransport() == 0 {
-                return Err(SettingsError::WebTransportUnsupported);
-            }
+            // Keep any remaining data for later.
+            let used = limit.position() as usize;
+            buf.drain(..used);
 
-            return Ok(recv);
+            return Ok((recv, buf, settings, pending));
         }
     }
 
-    async fn open(conn: &quinn::Connection) -> Result<quinn::SendStream, SettingsError> {
+    async fn open(
+        conn: &quinn::Connection,
+        options: &ConnectionOptions,
+    ) -> Result<(quinn::SendStream, web_transport_proto::Settings), SettingsError> {
         let mut settings = web_transport_proto::Settings::default();
-        settings.enable_webtransport(1);
+        for draft in &options.drafts {
+            draft.enable(&mut settings, options.max_sessions);
+        }
+
+        // Allow the peer to use the QPACK dynamic table, since some implementations ignore a zero capacity anyway.
+        settings.insert(
+            Setting::QPACK_MAX_TABLE_CAPACITY,
+            VarInt::from_u32(QPACK_MAX_TABLE_CAPACITY),
+        );
+        settings.insert(
+            Setting::QPACK_BLOCKED_STREAMS,
+            VarInt::from_u32(QPACK_BLOCKED_STREAMS),
+        );
+
+        // Tell the peer not to bother sending requests that we'll reject anyway.
+        settings.insert(
+            Setting::MAX_FIELD_SECTION_SIZE,
+            VarInt::from_u64(options.stream_headers.max_field_section_size as u64)
+                .unwrap_or(VarInt::MAX),
+        );
+
+        if let Some(limits) = &options.session_limits {
+            let limits = [
+                (Setting::WEBTRANSPORT_INITIAL_MAX_DATA, limits.max_data),
+                (
+                    Setting::WEBTRANSPORT_INITIAL_MAX_STREAMS_BIDI,
+                    limits.max_streams_bidi,
+                ),
+                (
+                    Setting::WEBTRANSPORT_INITIAL_MAX_STREAMS_UNI,
+                    limits.max_streams_uni,
+                ),
+            ];
+
+            for (id, value) in limits {
+                settings.insert(id, VarInt::from_u64(value).unwrap_or(VarInt::MAX));
+            }
+        }
+
+        if options.grease {
+            // The peer must ignore reserved settings, no matter the value.
+            let mut rng = rand::thread_rng();
+            settings.insert(Setting::grease(rng.gen()), VarInt::from_u32(rng.gen()));
+        }
 
         log::debug!("sending SETTINGS frame: {:?}", settings);
 
         let mut buf = Vec::new();
         settings.encode(&mut buf);
 
+        // SETTINGS must be the first frame, but anything can follow it.
+        if options.grease {
+            Self::grease_frame(&mut buf);
+        }
+
         let mut send = conn.open_uni().await?;
         send.write_all(&buf).await?;
 
-        Ok(send)
+        if options.grease {
+            Self::grease_stream(conn).await;
+        }
+
+        Ok((send, settings))
+    }
+
+    // Append a reserved frame with a random payload, which the peer must skip.
+    fn grease_frame(buf: &mut Vec<u8>) {
+        let mut rng = rand::thread_rng();
+
+        let mut payload = vec![0u8; rng.gen_range(0..16)];
+        rng.fill(&mut payload[..]);
+
+        log::debug!("sending GREASE frame: size={}", payload.len());
+
+        Frame::grease(rng.gen()).encode(buf);
+        VarInt::try_from(payload.len()).unwrap().encode(buf);
+        buf.extend_from_slice(&payload);
+    }
+
+    // Open a reserved unidirectional stream with a random payload, which the peer must ignore.
+    // Failures are only logged, since the connection works fine without it.
+    async fn grease_stream(conn: &quinn::Connection) {
+        let buf = {
+            let mut rng = rand::thread_rng();
+
+            let mut buf = Vec::new();
+            StreamUni::grease(rng.gen()).encode(&mut buf);
+
+            let mut payload = vec![0u8; rng.gen_range(0..16)];
+            rng.fill(&mut payload[..]);
+            buf.extend_from_slice(&payload);
+
+            buf
+        };
+
+        let res = async {
+            let mut send = conn.open_uni().await?;
+            // The stream is finished when dropped, without waiting for the peer to acknowledge it.
+            send.write_all(&buf).await?;
+            Ok::<_, SettingsError>(())
+        };
+
+        if let Err(err) = res.await {
+            log::debug!("failed to send GREASE stream: {}", err);
+        }
     }
 }
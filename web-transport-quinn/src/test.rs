@@ -0,0 +1,67 @@
+// Helpers for tests that need a real QUIC connection over loopback.
+
+use std::sync::Arc;
+
+use web_transport_proto::{qpack, Frame, VarInt};
+
+use crate::ALPN;
+
+// Returns a (server, client) pair of QUIC connections, using a certificate generated for localhost.
+pub async fn pair() -> (quinn::Connection, quinn::Connection) {
+    let (server, client) = endpoints();
+
+    let addr = server.local_addr().unwrap();
+    let connecting = client.connect(addr, "localhost").unwrap();
+    let (server, client) = tokio::join!(server.accept(), connecting);
+
+    (server.unwrap().await.unwrap(), client.unwrap())
+}
+
+// Returns a (server, client) pair of QUIC endpoints on loopback, using a certificate generated for localhost.
+pub fn endpoints() -> (quinn::Endpoint, quinn::Endpoint) {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let chain = vec![rustls::Certificate(cert.serialize_der().unwrap())];
+    let key = rustls::PrivateKey(cert.serialize_private_key_der());
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(&chain[0]).unwrap();
+
+    let mut tls = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(chain, key)
+        .unwrap();
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+
+    let config = quinn::ServerConfig::with_crypto(Arc::new(tls));
+    let server = quinn::Endpoint::server(config, "127.0.0.1:0".parse().unwrap()).unwrap();
+
+    let mut tls = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+
+    let mut client = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+    client.set_default_client_config(quinn::ClientConfig::new(Arc::new(tls)));
+
+    (server, client)
+}
+
+// Encodes a HEADERS frame for a GET request to the provided path.
+pub fn request(path: &str) -> Vec<u8> {
+    let mut headers = qpack::Headers::default();
+    headers.set(":method", "GET");
+    headers.set(":scheme", "https");
+    headers.set(":authority", "localhost");
+    headers.set(":path", path);
+
+    let mut section = Vec::new();
+    headers.encode(&mut section);
+
+    let mut buf = Vec::new();
+    Frame::HEADERS.encode(&mut buf);
+    VarInt::try_from(section.len()).unwrap().encode(&mut buf);
+    buf.extend_from_slice(&section);
+    buf
+}
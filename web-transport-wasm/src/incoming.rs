@@ -0,0 +1,133 @@
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    future::{poll_fn, Future},
+    marker::PhantomData,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Wake, Waker},
+};
+
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::ReadableStream;
+
+use crate::{Reader, WebError};
+
+// A ReadableStream shared by every clone of the session, such as the incoming streams or datagrams.
+//
+// There's a single read in flight at a time, which is driven by whichever task is waiting and queues the result.
+// The read lives here rather than in the caller's future, so dropping a future (ex. in a select) never loses an item;
+// it's returned to the next caller instead.
+pub struct Incoming<T> {
+    state: RefCell<IncomingState>,
+    wakers: Arc<Wakers>,
+    _marker: PhantomData<T>,
+}
+
+struct IncomingState {
+    reader: Reader,
+
+    // The read in flight, if any.
+    pending: Option<JsFuture>,
+
+    // Results that haven't been returned yet, in the order they were read.
+    queue: VecDeque<Result<Option<wasm_bindgen::JsValue>, WebError>>,
+
+    // Set once the stream is done or errored, after which nothing else is read.
+    closed: bool,
+}
+
+impl<T: JsCast> Incoming<T> {
+    pub fn new(stream: &ReadableStream) -> Result<Self, WebError> {
+        let state = IncomingState {
+            reader: Reader::new(stream)?,
+            pending: None,
+            queue: VecDeque::new(),
+            closed: false,
+        };
+
+        Ok(Self {
+            state: RefCell::new(state),
+            wakers: Default::default(),
+            _marker: PhantomData,
+        })
+    }
+
+    // Returns the next item, or None if the stream is done.
+    pub async fn next(&self) -> Result<Option<T>, WebError> {
+        let res = poll_fn(|cx| self.poll_next(cx)).await?;
+        Ok(res.map(JsCast::dyn_into).transpose()?)
+    }
+
+    fn poll_next(
+        &self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<wasm_bindgen::JsValue>, WebError>> {
+        let mut state = self.state.borrow_mut();
+
+        loop {
+            if let Some(res) = state.queue.pop_front() {
+                return Poll::Ready(res);
+            }
+
+            // Keep returning the end of the stream, instead of reading from a closed reader.
+            if state.closed {
+                return Poll::Ready(Ok(None));
+            }
+
+            self.wakers.register(cx.waker());
+
+            let state = &mut *state;
+            let pending = state
+                .pending
+                .get_or_insert_with(|| JsFuture::from(state.reader.read_promise()));
+
+            // Any waiting task may be dropped, so the read wakes all of them instead of just this one.
+            let waker = Waker::from(self.wakers.clone());
+            let res = match Pin::new(pending).poll(&mut Context::from_waker(&waker)) {
+                Poll::Ready(res) => res,
+                Poll::Pending => return Poll::Pending,
+            };
+
+            state.pending = None;
+
+            let res = res.map_err(WebError::from).and_then(Reader::parse);
+            state.closed = !matches!(res, Ok(Some(_)));
+            state.queue.push_back(res);
+
+            // Wake any other tasks, so one of them can start the next read.
+            self.wakers.wake_all();
+        }
+    }
+}
+
+// Every task waiting on the read in flight.
+#[derive(Default)]
+struct Wakers(Mutex<Vec<Waker>>);
+
+impl Wakers {
+    fn register(&self, waker: &Waker) {
+        let mut wakers = self.0.lock().unwrap();
+        if !wakers.iter().any(|w| w.will_wake(waker)) {
+            wakers.push(waker.clone());
+        }
+    }
+
+    fn wake_all(&self) {
+        let wakers = std::mem::take(&mut *self.0.lock().unwrap());
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+impl Wake for Wakers {
+    fn wake(self: Arc<Self>) {
+        self.wake_all();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wake_all();
+    }
+}
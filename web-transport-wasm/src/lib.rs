@@ -1,4 +1,6 @@
 mod error;
+mod incoming;
+mod options;
 mod reader;
 mod recv;
 mod send;
@@ -6,9 +8,11 @@ mod session;
 mod writer;
 
 pub use error::*;
+pub use options::*;
 pub use recv::*;
 pub use send::*;
 pub use session::*;
 
+pub(crate) use incoming::*;
 pub(crate) use reader::*;
 pub(crate) use writer::*;
@@ -0,0 +1,94 @@
+use js_sys::{Array, Reflect, Uint8Array};
+use wasm_bindgen::JsValue;
+use web_sys::{WebTransportCongestionControl, WebTransportHash, WebTransportOptions};
+
+use crate::WebError;
+
+/// The congestion control algorithm preferred by the application, which the browser may ignore.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CongestionControl {
+    #[default]
+    Default,
+    Throughput,
+    LowLatency,
+}
+
+/// Options used to establish a [`Session`](crate::Session), mapped to the browser's `WebTransportOptions`.
+#[derive(Clone, Debug, Default)]
+pub struct SessionOptions {
+    allow_pooling: bool,
+    require_unreliable: bool,
+    congestion_control: CongestionControl,
+    server_certificate_hashes: Vec<Vec<u8>>,
+    protocols: Vec<String>,
+}
+
+impl SessionOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow the session to share a connection with other sessions to the same server.
+    pub fn with_allow_pooling(mut self, allow: bool) -> Self {
+        self.allow_pooling = allow;
+        self
+    }
+
+    /// Fail instead of falling back to HTTP/2, which doesn't support unreliable datagrams.
+    pub fn with_require_unreliable(mut self, require: bool) -> Self {
+        self.require_unreliable = require;
+        self
+    }
+
+    /// Prefer a congestion control algorithm optimized for throughput or latency.
+    pub fn with_congestion_control(mut self, congestion_control: CongestionControl) -> Self {
+        self.congestion_control = congestion_control;
+        self
+    }
+
+    /// Trust self-signed certificates with any of the provided SHA-256 hashes, instead of using the system roots.
+    /// The certificate must be valid for at most 14 days and use an ECDSA P-256 key.
+    pub fn with_server_certificate_hashes(mut self, hashes: Vec<Vec<u8>>) -> Self {
+        self.server_certificate_hashes = hashes;
+        self
+    }
+
+    /// Offer application protocols to the server, in order of preference.
+    pub fn with_protocols(mut self, protocols: Vec<String>) -> Self {
+        self.protocols = protocols;
+        self
+    }
+
+    pub(crate) fn to_js(&self) -> Result<WebTransportOptions, WebError> {
+        let mut options = WebTransportOptions::new();
+        options.allow_pooling(self.allow_pooling);
+        options.require_unreliable(self.require_unreliable);
+
+        options.congestion_control(match self.congestion_control {
+            CongestionControl::Default => WebTransportCongestionControl::Default,
+            CongestionControl::Throughput => WebTransportCongestionControl::Throughput,
+            CongestionControl::LowLatency => WebTransportCongestionControl::LowLatency,
+        });
+
+        if !self.server_certificate_hashes.is_empty() {
+            let hashes = Array::new();
+
+            for value in &self.server_certificate_hashes {
+                let mut hash = WebTransportHash::new();
+                hash.algorithm("sha-256");
+                hash.value(&Uint8Array::from(value.as_slice()));
+                hashes.push(&hash);
+            }
+
+            options.server_certificate_hashes(&hashes);
+        }
+
+        // web-sys doesn't have a binding for protocols yet.
+        if !self.protocols.is_empty() {
+            let protocols: Array = self.protocols.iter().map(JsValue::from).collect();
+            Reflect::set(&options, &"protocols".into(), &protocols)?;
+        }
+
+        Ok(options)
+    }
+}
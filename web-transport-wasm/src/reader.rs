@@ -1,4 +1,4 @@
-use js_sys::Reflect;
+use js_sys::{Promise, Reflect};
 use wasm_bindgen::{JsCast, JsValue};
 use wasm_bindgen_futures::JsFuture;
 use web_sys::{ReadableStream, ReadableStreamDefaultReader, ReadableStreamReadResult};
@@ -16,15 +16,27 @@ impl Reader {
         Ok(Self { inner })
     }
 
-    pub async fn read<T: JsCast>(&mut self) -> Result<Option<T>, WebError> {
-        let result: ReadableStreamReadResult = JsFuture::from(self.inner.read()).await?.into();
+    // Concurrent reads are queued by the browser and resolved in order.
+    pub async fn read<T: JsCast>(&self) -> Result<Option<T>, WebError> {
+        let result = JsFuture::from(self.read_promise()).await?;
+        let res = Self::parse(result)?.map(JsCast::dyn_into).transpose()?;
+        Ok(res)
+    }
+
+    // Start a read, resolving to a ReadableStreamReadResult.
+    pub fn read_promise(&self) -> Promise {
+        self.inner.read()
+    }
+
+    // Returns the value of a ReadableStreamReadResult, or None if the stream is done.
+    pub fn parse(result: JsValue) -> Result<Option<JsValue>, WebError> {
+        let result: ReadableStreamReadResult = result.into();
 
         if Reflect::get(&result, &"done".into())?.is_truthy() {
             return Ok(None);
         }
 
-        let res = Reflect::get(&result, &"value".into())?.dyn_into()?;
-        Ok(Some(res))
+        Ok(Some(Reflect::get(&result, &"value".into())?))
     }
 
     pub fn close(self, reason: &str) {
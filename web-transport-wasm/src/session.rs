@@ -1,3 +1,5 @@
+use std::{rc::Rc, time::Duration};
+
 use bytes::Bytes;
 use js_sys::Uint8Array;
 use wasm_bindgen::JsCast;
@@ -7,32 +9,66 @@ use web_sys::{
     WebTransportSendStream,
 };
 
-use crate::{Reader, RecvStream, SendStream, WebError, Writer};
+use crate::{Incoming, RecvStream, SendStream, SessionOptions, WebError, Writer};
 
 #[derive(Clone)]
 pub struct Session {
     inner: web_sys::WebTransport,
+
+    // Each incoming ReadableStream can only be locked by a single reader, so it's shared by every clone.
+    // Dropping a reader cancels the stream, so they live until the last clone is dropped.
+    incoming_uni: Rc<Incoming<WebTransportReceiveStream>>,
+    incoming_bi: Rc<Incoming<WebTransportBidirectionalStream>>,
+    datagrams: Rc<Incoming<Uint8Array>>,
+
+    // Closing a writer closes the stream, so the datagram writer is also shared and cached.
+    datagram_writer: Rc<Writer>,
 }
 
 impl Session {
     pub async fn new(url: &str) -> Result<Self, WebError> {
         let inner = web_sys::WebTransport::new(url)?;
+        Self::connect(inner).await
+    }
+
+    /// Connect with the provided options, such as `serverCertificateHashes` for self-signed certificates.
+    pub async fn with_options(url: &str, options: &SessionOptions) -> Result<Self, WebError> {
+        let inner = web_sys::WebTransport::new_with_options(url, &options.to_js()?)?;
+        Self::connect(inner).await
+    }
+
+    async fn connect(inner: web_sys::WebTransport) -> Result<Self, WebError> {
         JsFuture::from(inner.ready()).await?;
 
-        Ok(Self { inner })
+        let incoming_uni = Rc::new(Incoming::new(&inner.incoming_unidirectional_streams())?);
+        let incoming_bi = Rc::new(Incoming::new(&inner.incoming_bidirectional_streams())?);
+        let datagrams = Rc::new(Incoming::new(&inner.datagrams().readable())?);
+        let datagram_writer = Rc::new(Writer::new(&inner.datagrams().writable())?);
+
+        Ok(Self {
+            inner,
+            incoming_uni,
+            incoming_bi,
+            datagrams,
+            datagram_writer,
+        })
     }
 
     pub async fn accept_uni(&mut self) -> Result<RecvStream, WebError> {
-        let mut reader = Reader::new(&self.inner.incoming_unidirectional_streams())?;
-        let stream: WebTransportReceiveStream = reader.read().await?.expect("closed without error");
+        let stream = match self.incoming_uni.next().await? {
+            Some(stream) => stream,
+            None => return Err(self.closed().await),
+        };
+
         let recv = RecvStream::new(stream)?;
         Ok(recv)
     }
 
     pub async fn accept_bi(&mut self) -> Result<(SendStream, RecvStream), WebError> {
-        let mut reader = Reader::new(&self.inner.incoming_bidirectional_streams())?;
-        let stream: WebTransportBidirectionalStream =
-            reader.read().await?.expect("closed without error");
+        let stream = match self.incoming_bi.next().await? {
+            Some(stream) => stream,
+            None => return Err(self.closed().await),
+        };
 
         let send = SendStream::new(stream.writable())?;
         let recv = RecvStream::new(stream.readable())?;
@@ -62,15 +98,70 @@ impl Session {
         Ok(send)
     }
 
+    /// Send a datagram, first waiting until the outgoing queue is below [`Self::outgoing_high_water_mark`].
     pub async fn send_datagram(&mut self, payload: Bytes) -> Result<(), WebError> {
-        let mut writer = Writer::new(&self.inner.datagrams().writable())?;
-        writer.write(&Uint8Array::from(payload.as_ref())).await?;
-        Ok(())
+        self.datagram_writer.ready().await?;
+        self.datagram_writer
+            .write(&Uint8Array::from(payload.as_ref()))
+            .await
+    }
+
+    /// The number of datagrams that can be queued before [`Self::send_datagram`] waits, or None if the session is closed.
+    pub fn datagram_desired_size(&self) -> Option<f64> {
+        self.datagram_writer.desired_size()
+    }
+
+    /// The maximum size of a datagram payload, which may change during the session.
+    pub fn max_datagram_size(&self) -> u32 {
+        self.inner.datagrams().max_datagram_size()
+    }
+
+    /// The number of received datagrams to queue before dropping the oldest.
+    pub fn incoming_high_water_mark(&self) -> f64 {
+        self.inner.datagrams().incoming_high_water_mark()
+    }
+
+    pub fn set_incoming_high_water_mark(&self, value: f64) {
+        self.inner.datagrams().set_incoming_high_water_mark(value)
+    }
+
+    /// The number of outgoing datagrams to queue before [`Self::send_datagram`] waits.
+    pub fn outgoing_high_water_mark(&self) -> f64 {
+        self.inner.datagrams().outgoing_high_water_mark()
+    }
+
+    pub fn set_outgoing_high_water_mark(&self, value: f64) {
+        self.inner.datagrams().set_outgoing_high_water_mark(value)
+    }
+
+    /// How long a received datagram is queued before it's dropped, or None for no limit.
+    pub fn incoming_max_age(&self) -> Option<Duration> {
+        max_age(self.inner.datagrams().incoming_max_age())
+    }
+
+    pub fn set_incoming_max_age(&self, age: Option<Duration>) {
+        self.inner
+            .datagrams()
+            .set_incoming_max_age(max_age_millis(age))
+    }
+
+    /// How long an outgoing datagram is queued before it's dropped, or None for no limit.
+    pub fn outgoing_max_age(&self) -> Option<Duration> {
+        max_age(self.inner.datagrams().outgoing_max_age())
+    }
+
+    pub fn set_outgoing_max_age(&self, age: Option<Duration>) {
+        self.inner
+            .datagrams()
+            .set_outgoing_max_age(max_age_millis(age))
     }
 
     pub async fn recv_datagram(&mut self) -> Result<Bytes, WebError> {
-        let mut reader = Reader::new(&self.inner.datagrams().readable())?;
-        let data: Uint8Array = reader.read().await?.unwrap_or_default();
+        let data = match self.datagrams.next().await? {
+            Some(data) => data,
+            None => return Err(self.closed().await),
+        };
+
         Ok(data.to_vec().into())
     }
 
@@ -81,8 +172,23 @@ impl Session {
         self.inner.close_with_close_info(&info);
     }
 
+    /// Wait until the session is closed, returning the close info or the error if it was closed abruptly.
     pub async fn closed(&self) -> WebError {
-        let err = JsFuture::from(self.inner.closed()).await.unwrap();
-        WebError::from(err)
+        match JsFuture::from(self.inner.closed()).await {
+            Ok(info) => WebError::from(info),
+            Err(err) => WebError::from(err),
+        }
+    }
+}
+
+// The browser uses null (or 0) milliseconds for no limit.
+fn max_age(millis: f64) -> Option<Duration> {
+    match millis.is_finite() && millis > 0.0 {
+        true => Some(Duration::from_secs_f64(millis / 1000.0)),
+        false => None,
     }
 }
+
+fn max_age_millis(age: Option<Duration>) -> f64 {
+    age.map(|age| age.as_secs_f64() * 1000.0).unwrap_or(0.0)
+}
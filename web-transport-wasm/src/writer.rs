@@ -15,11 +15,22 @@ impl Writer {
         Ok(Self { inner })
     }
 
-    pub async fn write(&mut self, v: &JsValue) -> Result<(), WebError> {
+    pub async fn write(&self, v: &JsValue) -> Result<(), WebError> {
         JsFuture::from(self.inner.write_with_chunk(v)).await?;
         Ok(())
     }
 
+    // Wait until the stream's queue is below the high-water mark.
+    pub async fn ready(&self) -> Result<(), WebError> {
+        JsFuture::from(self.inner.ready()).await?;
+        Ok(())
+    }
+
+    // The room left in the queue before the high-water mark, or None if the stream errored.
+    pub fn desired_size(&self) -> Option<f64> {
+        self.inner.desired_size().ok().flatten()
+    }
+
     pub fn close(self, reason: &str) {
         let str = JsValue::from_str(reason);
         let _ = self.inner.abort_with_reason(&str); // ignore the promise
@@ -1,9 +1,22 @@
 use bytes::{Buf, BufMut, Bytes};
 
+pub use web_transport_wasm::{CongestionControl, SessionOptions};
+
 #[derive(Clone)]
 pub struct Session(web_transport_wasm::Session);
 
 impl Session {
+    /// Connect to the provided URL with the default options.
+    pub async fn new(url: &str) -> Result<Self, SessionError> {
+        Self::with_options(url, &SessionOptions::default()).await
+    }
+
+    /// Connect to the provided URL, passing the options to the browser's WebTransport constructor.
+    pub async fn with_options(url: &str, options: &SessionOptions) -> Result<Self, SessionError> {
+        let session = web_transport_wasm::Session::with_options(url, options).await?;
+        Ok(Session(session))
+    }
+
     pub async fn accept_uni(&mut self) -> Result<RecvStream, SessionError> {
         self.0
             .accept_uni()